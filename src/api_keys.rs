@@ -0,0 +1,545 @@
+use std::sync::Arc;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond, Body};
+use crate::db_pool::{DbPool};
+use crate::route_handlers::{database_error, now, sqlite_required, ApiResponse};
+
+/// `last_used_at` is only written when it is older than this, so busy keys do not
+/// cost a write on every request.
+const LAST_USED_RESOLUTION: i64 = 60_000;
+
+const DAY: i64 = 86_400_000;
+
+/// Longest lifetime a key can be created with, 100 years.
+pub const MAX_EXPIRES_IN_DAYS: u64 = 36_500;
+
+/// Longest time the previous value of a rotated key stays valid, 30 days in seconds.
+pub const MAX_GRACE_PERIOD: u64 = 2_592_000;
+
+/// What a key may access. `SECRET_KEY` has every scope.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// `/get_sessions` and the session stats
+    ReadSessions,
+    /// `/get_events`, event gaps, search and the event stats
+    ReadEvents,
+    /// `/export/*` and `/users/{user_id}/export`
+    Export,
+    /// `/admin/*` and `/metrics`, including key management
+    Admin,
+    /// `DELETE /users/{user_id}`
+    Delete,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadSessions => "read_sessions",
+            Scope::ReadEvents => "read_events",
+            Scope::Export => "export",
+            Scope::Admin => "admin",
+            Scope::Delete => "delete",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Scope> {
+        match value {
+            "read_sessions" => Some(Scope::ReadSessions),
+            "read_events" => Some(Scope::ReadEvents),
+            "export" => Some(Scope::Export),
+            "admin" => Some(Scope::Admin),
+            "delete" => Some(Scope::Delete),
+            _ => None
+        }
+    }
+}
+
+/// Parses comma-separated scopes, `None` if one is unknown.
+pub fn parse_scopes(value: &str) -> Option<Vec<Scope>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .map(Scope::parse)
+        .collect()
+}
+
+fn join_scopes(scopes: &[Scope]) -> String {
+    scopes.iter().map(Scope::as_str).collect::<Vec<&str>>().join(",")
+}
+
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    /// Start of the key, enough to recognize it
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub rotated_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+impl ApiKey {
+    const COLUMNS: &'static str = "id, name, prefix, scopes, created_at, expires_at, last_used_at, rotated_at, revoked_at";
+
+    fn from_row(row: &Row) -> rusqlite::Result<ApiKey> {
+        let scopes: String = row.get(3)?;
+
+        Ok(ApiKey {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            prefix: row.get(2)?,
+            // Unknown scopes of a newer release grant nothing
+            scopes: scopes.split(',').filter_map(Scope::parse).collect(),
+            created_at: row.get(4)?,
+            expires_at: row.get(5)?,
+            last_used_at: row.get(6)?,
+            rotated_at: row.get(7)?,
+            revoked_at: row.get(8)?,
+        })
+    }
+}
+
+/// A key as returned once on creation and rotation. Only its hash is stored.
+#[derive(Serialize)]
+pub struct IssuedKey {
+    pub id: i64,
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<i64>,
+}
+
+fn generate_key() -> String {
+    format!("rla_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+fn key_prefix(key: &str) -> String {
+    key.chars().take(12).collect()
+}
+
+fn hash_key(key: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(key))
+}
+
+/// Rejects lifetimes whose expiry would not fit a timestamp.
+pub fn check_expires_in_days(days: Option<u64>) -> Result<(), String> {
+    match days {
+        Some(days) if days > MAX_EXPIRES_IN_DAYS => Err(format!("expires_in_days must be at most {}", MAX_EXPIRES_IN_DAYS)),
+        _ => Ok(())
+    }
+}
+
+pub fn check_grace_period(grace_period: u64) -> Result<(), String> {
+    match grace_period > MAX_GRACE_PERIOD {
+        true => Err(format!("grace_period must be at most {} seconds", MAX_GRACE_PERIOD)),
+        false => Ok(())
+    }
+}
+
+/// `at` plus `amount` times `unit`, saturating instead of wrapping into the past.
+fn later(at: i64, amount: u64, unit: i64) -> i64 {
+    i64::try_from(amount).ok()
+        .and_then(|amount| amount.checked_mul(unit))
+        .and_then(|offset| at.checked_add(offset))
+        .unwrap_or(i64::MAX)
+}
+
+pub fn list_keys(conn: &Connection) -> rusqlite::Result<Vec<ApiKey>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM main.api_keys ORDER BY id", ApiKey::COLUMNS))?;
+    let rows = stmt.query_map([], ApiKey::from_row)?;
+    rows.collect()
+}
+
+/// The key `key` belongs to, if it is neither revoked nor expired. Keys rotated with a
+/// grace period are still found by their previous value until the grace period ends.
+pub fn find_key(conn: &Connection, key: &[u8], at: i64) -> rusqlite::Result<Option<ApiKey>> {
+    let hash = hash_key(key);
+
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM main.api_keys
+            WHERE (key_hash = ?1 OR (previous_key_hash = ?1 AND previous_expires_at > ?2))
+                AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > ?2)",
+            ApiKey::COLUMNS
+        ),
+        params![hash, at],
+        ApiKey::from_row
+    )
+    .optional()
+}
+
+pub fn create_key(conn: &Connection, name: &str, scopes: &[Scope], expires_in_days: Option<u64>) -> rusqlite::Result<IssuedKey> {
+    let key = generate_key();
+    let created_at = now();
+    let expires_at = expires_in_days.map(|days| later(created_at, days, DAY));
+
+    conn.execute(
+        "INSERT INTO main.api_keys (name, prefix, key_hash, scopes, created_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![name, key_prefix(&key), hash_key(key.as_bytes()), join_scopes(scopes), created_at, expires_at],
+    )?;
+
+    Ok(IssuedKey {
+        id: conn.last_insert_rowid(),
+        name: name.to_string(),
+        key,
+        scopes: scopes.to_vec(),
+        expires_at,
+    })
+}
+
+/// Replaces the value of a key, keeping its name, scopes and expiry. The previous
+/// value keeps working for `grace_period` seconds, so clients can be updated first.
+/// `None` if the key does not exist or was revoked.
+pub fn rotate_key(conn: &mut Connection, id: i64, grace_period: u64) -> rusqlite::Result<Option<IssuedKey>> {
+    let tx = conn.transaction()?;
+
+    let Some(existing) = tx.query_row(
+        &format!("SELECT {} FROM main.api_keys WHERE id = ?1 AND revoked_at IS NULL", ApiKey::COLUMNS),
+        params![id],
+        ApiKey::from_row
+    ).optional()? else {
+        return Ok(None);
+    };
+
+    let key = generate_key();
+    let rotated_at = now();
+    let previous_expires_at = (grace_period > 0).then(|| later(rotated_at, grace_period, 1000));
+
+    tx.execute(
+        "UPDATE main.api_keys
+        SET previous_key_hash = CASE WHEN ?4 IS NULL THEN NULL ELSE key_hash END,
+            previous_expires_at = ?4,
+            key_hash = ?2,
+            prefix = ?3,
+            rotated_at = ?5
+        WHERE id = ?1",
+        params![id, hash_key(key.as_bytes()), key_prefix(&key), previous_expires_at, rotated_at],
+    )?;
+
+    tx.commit()?;
+
+    Ok(Some(IssuedKey {
+        id,
+        name: existing.name,
+        key,
+        scopes: existing.scopes,
+        expires_at: existing.expires_at,
+    }))
+}
+
+/// Revokes a key immediately, including a previous value still in its grace period.
+/// False if the key does not exist or was already revoked.
+pub fn revoke_key(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.execute(
+        "UPDATE main.api_keys SET revoked_at = ?2, previous_key_hash = NULL, previous_expires_at = NULL
+        WHERE id = ?1 AND revoked_at IS NULL",
+        params![id, now()],
+    )
+    .map(|updated| updated > 0)
+}
+
+/// Records that a key was used at `at`, at most once per `LAST_USED_RESOLUTION`.
+pub async fn record_use(db: &Arc<DbPool>, key: &ApiKey, at: i64) {
+    if key.last_used_at.is_some_and(|last_used_at| at - last_used_at < LAST_USED_RESOLUTION) {
+        return;
+    }
+
+    let id = key.id;
+    let result = db.write(move |conn| {
+        conn.execute("UPDATE main.api_keys SET last_used_at = ?2 WHERE id = ?1", params![id, at])
+    }).await;
+
+    if let Err(e) = result {
+        log::warn!("Cannot record use of API key {}: {}", id, e);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct RotateKeyQuery {
+    /// Seconds the previous value stays valid
+    #[serde(default)]
+    pub grace_period: u64,
+}
+
+fn unknown_key(id: i64) -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse {
+        success: false,
+        message: format!("Unknown or revoked API key {}", id)
+    })
+}
+
+/// Lists the API keys, without their values.
+pub async fn get_keys(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    match db.read(list_keys).await {
+        Ok(keys) => Ok(respond(&req, &keys, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Failed to list API keys", e))
+    }
+}
+
+/// Creates a key. Its value is only part of this response.
+pub async fn post_key(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+    payload: Body<CreateKeyRequest>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let request = payload.into_inner();
+
+    if request.name.trim().is_empty() || request.scopes.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: "Expected a name and at least one scope".to_string()
+        }));
+    }
+
+    if let Err(message) = check_expires_in_days(request.expires_in_days) {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse { success: false, message }));
+    }
+
+    match db.write(move |conn| create_key(conn, request.name.trim(), &request.scopes, request.expires_in_days)).await {
+        Ok(issued) => Ok(HttpResponse::Created().json(issued)),
+        Err(e) => Ok(database_error("API key not created", e))
+    }
+}
+
+/// Issues a new value for a key, e.g. `POST /admin/keys/3/rotate?grace_period=3600`.
+pub async fn post_key_rotation(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+    path: web::Path<i64>,
+    query: web::Query<RotateKeyQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let id = path.into_inner();
+    let grace_period = query.grace_period;
+
+    if let Err(message) = check_grace_period(grace_period) {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse { success: false, message }));
+    }
+
+    match db.write(move |conn| rotate_key(conn, id, grace_period)).await {
+        Ok(Some(issued)) => Ok(HttpResponse::Ok().json(issued)),
+        Ok(None) => Ok(unknown_key(id)),
+        Err(e) => Ok(database_error("API key not rotated", e))
+    }
+}
+
+/// Revokes a key.
+pub async fn delete_key(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let id = path.into_inner();
+
+    match db.write(move |conn| revoke_key(conn, id)).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: format!("API key {} revoked", id)
+        })),
+        Ok(false) => Ok(unknown_key(id)),
+        Err(e) => Ok(database_error("API key not revoked", e))
+    }
+}
+
+fn print_issued(issued: &IssuedKey) {
+    println!("Key {} ({}): {}", issued.id, issued.name, issued.key);
+    println!("Store it now, it cannot be shown again");
+}
+
+fn usage() -> std::io::Error {
+    std::io::Error::other(
+        "Usage: keys list | keys create <name> <scopes> [expires_in_days] | keys rotate <id> [grace_period] | keys revoke <id>"
+    )
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: Option<&String>) -> Result<T, std::io::Error> {
+    arg.and_then(|arg| arg.parse().ok()).ok_or_else(usage)
+}
+
+fn parse_optional_arg<T: std::str::FromStr>(arg: Option<&String>) -> Result<Option<T>, std::io::Error> {
+    arg.map(|arg| arg.parse().map_err(|_| usage())).transpose()
+}
+
+/// Entry point of the `keys` command line.
+///
+/// - `keys list`: list keys with their scopes and last use
+/// - `keys create <name> <scopes> [expires_in_days]`: create a key, e.g. `keys create dashboard read_sessions,read_events 90`
+/// - `keys rotate <id> [grace_period]`: issue a new value, the old one stays valid for `grace_period` seconds
+/// - `keys revoke <id>`: revoke a key
+pub fn run_cli(conn: &mut Connection, args: &[String]) -> std::io::Result<()> {
+    match args.first().map(String::as_str) {
+        None | Some("list") => {
+            let keys = list_keys(conn).map_err(std::io::Error::other)?;
+            if keys.is_empty() {
+                println!("No API keys");
+            }
+
+            for key in keys {
+                let state = match (key.revoked_at, key.expires_at) {
+                    (Some(_), _) => "revoked",
+                    (None, Some(expires_at)) if expires_at <= now() => "expired",
+                    _ => "active"
+                };
+                let last_used = key.last_used_at
+                    .and_then(chrono::DateTime::from_timestamp_millis)
+                    .map(|time| time.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_string());
+
+                println!(
+                    "  {} {} [{}] {}... scopes: {}, last used: {}",
+                    key.id, key.name, state, key.prefix, join_scopes(&key.scopes), last_used
+                );
+            }
+        },
+
+        Some("create") => {
+            let name = args.get(1).filter(|name| !name.trim().is_empty()).ok_or_else(usage)?;
+            let scopes = args.get(2)
+                .and_then(|scopes| parse_scopes(scopes))
+                .filter(|scopes| !scopes.is_empty())
+                .ok_or_else(|| std::io::Error::other("Expected comma-separated scopes: read_sessions, read_events, export, admin, delete"))?;
+            let expires_in_days = parse_optional_arg(args.get(3))?;
+            check_expires_in_days(expires_in_days).map_err(std::io::Error::other)?;
+
+            let issued = create_key(conn, name.trim(), &scopes, expires_in_days).map_err(std::io::Error::other)?;
+            print_issued(&issued);
+        },
+
+        Some("rotate") => {
+            let id = parse_arg(args.get(1))?;
+            let grace_period = parse_optional_arg(args.get(2))?.unwrap_or(0);
+            check_grace_period(grace_period).map_err(std::io::Error::other)?;
+
+            match rotate_key(conn, id, grace_period).map_err(std::io::Error::other)? {
+                Some(issued) => print_issued(&issued),
+                None => return Err(std::io::Error::other(format!("Unknown or revoked API key {}", id)))
+            }
+        },
+
+        Some("revoke") => {
+            let id = parse_arg(args.get(1))?;
+
+            if !revoke_key(conn, id).map_err(std::io::Error::other)? {
+                return Err(std::io::Error::other(format!("Unknown or revoked API key {}", id)));
+            }
+            println!("API key {} revoked", id);
+        },
+
+        Some(_) => return Err(usage())
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("migrations/0007_api_keys.sql")).unwrap();
+        conn
+    }
+
+    #[test]
+    fn finds_keys_with_their_scopes() {
+        let conn = database();
+        let issued = create_key(&conn, "dashboard", &[Scope::ReadSessions, Scope::ReadEvents], None).unwrap();
+
+        let key = find_key(&conn, issued.key.as_bytes(), now()).unwrap().unwrap();
+        assert_eq!(key.id, issued.id);
+        assert_eq!(key.scopes, [Scope::ReadSessions, Scope::ReadEvents]);
+        assert!(issued.key.starts_with(&key.prefix));
+
+        assert!(find_key(&conn, b"rla_unknown", now()).unwrap().is_none());
+        assert_eq!(parse_scopes("read_events, admin"), Some(vec![Scope::ReadEvents, Scope::Admin]));
+        assert_eq!(parse_scopes("read_events,root"), None);
+    }
+
+    #[test]
+    fn ignores_expired_and_revoked_keys() {
+        let conn = database();
+        let issued = create_key(&conn, "ci", &[Scope::Export], Some(1)).unwrap();
+        let expires_at = issued.expires_at.unwrap();
+
+        assert!(find_key(&conn, issued.key.as_bytes(), expires_at - 1).unwrap().is_some());
+        assert!(find_key(&conn, issued.key.as_bytes(), expires_at).unwrap().is_none());
+
+        assert!(revoke_key(&conn, issued.id).unwrap());
+        assert!(!revoke_key(&conn, issued.id).unwrap());
+        assert!(find_key(&conn, issued.key.as_bytes(), now()).unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_the_previous_value_during_the_grace_period() {
+        let mut conn = database();
+        let issued = create_key(&conn, "game", &[Scope::ReadEvents], None).unwrap();
+
+        let rotated = rotate_key(&mut conn, issued.id, 60).unwrap().unwrap();
+        let at = now();
+
+        assert_ne!(rotated.key, issued.key);
+        assert!(find_key(&conn, rotated.key.as_bytes(), at).unwrap().is_some());
+        assert!(find_key(&conn, issued.key.as_bytes(), at).unwrap().is_some());
+        assert!(find_key(&conn, issued.key.as_bytes(), at + 61_000).unwrap().is_none());
+
+        // Without a grace period the previous value stops working at once
+        let again = rotate_key(&mut conn, issued.id, 0).unwrap().unwrap();
+        assert!(find_key(&conn, rotated.key.as_bytes(), now()).unwrap().is_none());
+        assert!(find_key(&conn, again.key.as_bytes(), now()).unwrap().is_some());
+
+        revoke_key(&conn, issued.id).unwrap();
+        assert!(rotate_key(&mut conn, issued.id, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_lifetimes_out_of_range() {
+        assert!(check_expires_in_days(Some(MAX_EXPIRES_IN_DAYS)).is_ok());
+        assert!(check_expires_in_days(Some(u64::MAX)).is_err());
+        assert!(check_grace_period(MAX_GRACE_PERIOD).is_ok());
+        assert!(check_grace_period(u64::MAX).is_err());
+
+        assert_eq!(later(now(), u64::MAX, DAY), i64::MAX);
+        assert_eq!(later(0, 2, DAY), 2 * DAY);
+    }
+}
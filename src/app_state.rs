@@ -11,14 +11,34 @@ use deadpool_redis::{
 use std::sync::Arc;
 use std::collections::HashMap;
 
-use crate::rate_limit::{RateLimitInfo};
+use crate::rate_limit::{AuthFailureInfo, RateLimitInfo};
 use crate::config::{Config};
+use crate::consent::{ConsentTracker};
+use crate::db_pool::{DbPool};
+use crate::ip_privacy::{IpAnonymizer};
+use crate::backup::{BackupStatus};
+use crate::parquet_archive::{ParquetArchiveStatus};
+use crate::request_signing::{NonceCache};
+use crate::retention::{RetentionReport};
+use crate::storage::{Storage};
 
 #[derive(Clone)]
 pub struct AppState {
     pub rate_limiter: Arc<Mutex<HashMap<String, RateLimitInfo>>>,
+    pub auth_failures: Arc<Mutex<HashMap<String, AuthFailureInfo>>>,
+    /// Open WebSocket channels per IP address
+    pub ws_connections: Arc<Mutex<HashMap<String, usize>>>,
+    pub nonces: NonceCache,
+    pub ip_anonymizer: Arc<IpAnonymizer>,
+    pub consent: Arc<ConsentTracker>,
     pub config: Arc<Config>,
     pub redis_pool: Option<Arc<RedisPool>>,
+    pub storage: Arc<dyn Storage>,
+    /// `None` with the in-memory storage backend
+    pub db: Option<Arc<DbPool>>,
+    pub retention_report: Arc<Mutex<Option<RetentionReport>>>,
+    pub backup_status: Arc<Mutex<BackupStatus>>,
+    pub parquet_archive_status: Arc<Mutex<ParquetArchiveStatus>>,
 }
 
 #[derive(Debug)]
@@ -54,14 +74,14 @@ fn get_redis_connection_addr(hostname: &str, port: u16, use_tls: bool) -> RedisC
         false => RedisConnectionAddr::Tcp(hostname.to_string(), port),
         true => RedisConnectionAddr::TcpTls {
             host: hostname.to_string(),
-            port: port,
+            port,
             insecure: false,
         }
     }
 }
 
 impl AppState {
-    pub fn init(config: Config) -> AppState {
+    pub fn init(config: Config, storage: Arc<dyn Storage>, db: Option<Arc<DbPool>>) -> AppState {
         let redis_pool = config.redis_connection_hostname.as_ref().map(|hostname| {
             let cfg = &config;
 
@@ -73,7 +93,7 @@ impl AppState {
                 connection.redis.password = cfg.redis_connection_password.clone();
                 connection.redis.protocol = parse_redis_protocol(cfg.redis_connection_protocol.clone());
 
-                connection.addr = get_redis_connection_addr(&hostname, cfg.redis_connection_port, cfg.redis_connection_use_tls);
+                connection.addr = get_redis_connection_addr(hostname, cfg.redis_connection_port, cfg.redis_connection_use_tls);
             }
 
             let pool = redis_cfg.create_pool(Some(Runtime::Tokio1)).expect("Unable to create redis pool");
@@ -83,11 +103,44 @@ impl AppState {
 
         AppState {
             rate_limiter: Arc::new(Mutex::new(HashMap::new())),
+            auth_failures: Arc::new(Mutex::new(HashMap::new())),
+            ws_connections: Arc::new(Mutex::new(HashMap::new())),
+            nonces: Arc::new(Mutex::new(HashMap::new())),
+            ip_anonymizer: Arc::new(IpAnonymizer::new(config.ip_privacy)),
+            consent: Arc::new(ConsentTracker::default()),
             config: Arc::new(config),
-            redis_pool: redis_pool
+            redis_pool,
+            storage,
+            db,
+            retention_report: Arc::new(Mutex::new(None)),
+            backup_status: Arc::new(Mutex::new(BackupStatus::default())),
+            parquet_archive_status: Arc::new(Mutex::new(ParquetArchiveStatus::default())),
         }
     }
 
+    /// A project on the in-memory backend configured by `vars`, for handler tests.
+    #[cfg(test)]
+    pub fn in_memory(vars: &[(&str, &str)]) -> actix_web::web::Data<AppState> {
+        let config = Config::from_pairs(vars);
+        let storage: Arc<dyn Storage> = Arc::new(crate::memory_storage::MemoryStorage::default());
+
+        actix_web::web::Data::new(AppState::init(config, storage, None))
+    }
+
+    /// A project on a SQLite database in `dir` configured by `vars`, for tests of the
+    /// features working on the database directly.
+    #[cfg(test)]
+    pub fn on_sqlite(dir: &TestDir, vars: &[(&str, &str)]) -> actix_web::web::Data<AppState> {
+        let db_path = dir.file("analytics.db");
+        let mut pairs = vec![("DB_PATH", db_path.as_str())];
+        pairs.extend_from_slice(vars);
+
+        let config = Config::from_pairs(&pairs);
+        let (storage, db) = crate::storage::open_storage(&config).expect("test database opens");
+
+        actix_web::web::Data::new(AppState::init(config, storage, db))
+    }
+
     pub async fn test_connection(&self) -> Result<(), ConnectionTestError> {
         if let Some(redis_pool) = self.redis_pool.as_ref() {
             redis_pool.get().await?;
@@ -95,3 +148,32 @@ impl AppState {
         Ok(())
     }
 }
+
+/// A temporary directory that is removed again when dropped.
+#[cfg(test)]
+pub struct TestDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+        let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("rla-{}-{}-{}", name, std::process::id(), count));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("temporary directory is created");
+
+        TestDir(path)
+    }
+
+    pub fn file(&self, name: &str) -> String {
+        self.0.join(name).display().to_string()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,199 @@
+use std::marker::PhantomData;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{error, web, Error, FromRequest, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+
+use crate::api_keys::{find_key, record_use, Scope};
+use crate::app_state::{AppState};
+use crate::rate_limit::{auth_lockout, record_auth_failure};
+use crate::route_handlers::{compare_key, database_error, get_request_id, now, ApiResponse};
+use crate::tls::{ClientCertificate};
+
+/// The scope an `Authorized` extractor requires, as a type.
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub mod scope {
+    use super::{RequiredScope, Scope};
+
+    pub struct ReadSessions;
+    pub struct ReadEvents;
+    pub struct Export;
+    pub struct Admin;
+    pub struct Delete;
+
+    impl RequiredScope for ReadSessions { const SCOPE: Scope = Scope::ReadSessions; }
+    impl RequiredScope for ReadEvents { const SCOPE: Scope = Scope::ReadEvents; }
+    impl RequiredScope for Export { const SCOPE: Scope = Scope::Export; }
+    impl RequiredScope for Admin { const SCOPE: Scope = Scope::Admin; }
+    impl RequiredScope for Delete { const SCOPE: Scope = Scope::Delete; }
+}
+
+/// Extractor rejecting requests without `SECRET_KEY` or an API key with scope `S`,
+/// e.g. `_auth: Authorized<scope::ReadEvents>`.
+///
+/// The key is read from `X-RLA-KEY` or `Authorization: Bearer`. Failed attempts are
+/// logged and counted per IP address; addresses exceeding `AUTH_FAILURE_LIMIT` within
+/// `AUTH_FAILURE_WINDOW` are answered with 429 until the window has passed. With
+/// `TLS_CLIENT_CA_PATH`, the admin scope also requires a verified client certificate.
+pub struct Authorized<S: RequiredScope> {
+    scope: PhantomData<S>,
+    principal: String,
+}
+
+impl<S: RequiredScope> Authorized<S> {
+    /// Who made the request, `SECRET_KEY` or the id and name of the API key.
+    pub fn principal(&self) -> &str {
+        &self.principal
+    }
+}
+
+/// The key of a request, from `X-RLA-KEY` or else `Authorization: Bearer <key>`.
+pub fn credential(headers: &HeaderMap) -> Option<&[u8]> {
+    if let Some(key) = headers.get("X-RLA-KEY") {
+        return Some(key.as_bytes());
+    }
+
+    let authorization = headers.get(header::AUTHORIZATION)?.as_bytes();
+    let (scheme, token) = authorization.split_at(authorization.iter().position(|byte| *byte == b' ')?);
+
+    scheme.eq_ignore_ascii_case(b"bearer").then(|| token.trim_ascii())
+}
+
+fn reject(response: HttpResponse) -> Error {
+    error::InternalError::from_response("Authentication failed", response).into()
+}
+
+fn unauthorized() -> Error {
+    reject(HttpResponse::Unauthorized().json(ApiResponse {
+        success: false,
+        message: "Insufficient permissions".to_string()
+    }))
+}
+
+async fn authenticate(req: &HttpRequest, scope: Scope) -> Result<String, Error> {
+    let Some(data) = req.app_data::<web::Data<AppState>>() else {
+        return Err(unauthorized());
+    };
+
+    let ip = get_request_id(req, data).unwrap_or("unknown".to_string());
+
+    if let Some(retry_after) = auth_lockout(data, &ip) {
+        return Err(reject(
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .json(ApiResponse {
+                    success: false,
+                    message: "Too many failed authentication attempts".to_string()
+                })
+        ));
+    }
+
+    // With `TLS_CLIENT_CA_PATH`, admin endpoints also need a verified client certificate
+    if scope == Scope::Admin && data.config.tls_client_ca_path.is_some() && req.conn_data::<ClientCertificate>().is_none() {
+        log::warn!("Rejected admin request from {} for {}: no client certificate", ip, req.path());
+        return Err(reject(HttpResponse::Unauthorized().json(ApiResponse {
+            success: false,
+            message: "Client certificate required".to_string()
+        })));
+    }
+
+    let credential = credential(req.headers());
+
+    let failure = match credential {
+        None => "no key",
+        Some(key) if compare_key(Some(key), data.config.secret_key.as_deref()) => return Ok("SECRET_KEY".to_string()),
+        Some(key) => match data.db.as_ref() {
+            None => "invalid key",
+            Some(db) => {
+                let key = key.to_vec();
+                let at = now();
+
+                match db.read(move |conn| find_key(conn, &key, at)).await {
+                    Ok(Some(key)) if key.scopes.contains(&scope) => {
+                        record_use(db, &key, at).await;
+                        return Ok(format!("API key {} ({})", key.id, key.name));
+                    },
+                    Ok(Some(key)) => {
+                        // A valid key lacking the scope is a configuration error, not a guess
+                        log::warn!("API key {} ({}) lacks scope {} for {}", key.id, key.name, scope.as_str(), req.path());
+                        return Err(unauthorized());
+                    },
+                    Ok(None) => "invalid key",
+                    Err(e) => return Err(reject(database_error("Key not checked", e)))
+                }
+            }
+        }
+    };
+
+    record_auth_failure(data, &ip);
+    log::warn!("Failed authentication from {} for {}: {}", ip, req.path(), failure);
+
+    Err(unauthorized())
+}
+
+impl<S: RequiredScope + 'static> FromRequest for Authorized<S> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            let principal = authenticate(&req, S::SCOPE).await?;
+            Ok(Authorized { scope: PhantomData, principal })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::{header::HeaderValue, StatusCode};
+    use actix_web::{test as web_test, App, HttpResponse};
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(header::HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn reads_the_key_from_either_header() {
+        assert_eq!(credential(&headers(&[("x-rla-key", "abc")])), Some(&b"abc"[..]));
+        assert_eq!(credential(&headers(&[("authorization", "Bearer abc")])), Some(&b"abc"[..]));
+        assert_eq!(credential(&headers(&[("authorization", "bearer   abc  ")])), Some(&b"abc"[..]));
+        assert_eq!(credential(&headers(&[("x-rla-key", "abc"), ("authorization", "Bearer xyz")])), Some(&b"abc"[..]));
+
+        assert_eq!(credential(&headers(&[("authorization", "Basic abc")])), None);
+        assert_eq!(credential(&headers(&[("authorization", "Bearer")])), None);
+        assert_eq!(credential(&headers(&[])), None);
+    }
+
+    async fn sessions(_auth: Authorized<scope::ReadSessions>) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn locks_out_after_repeated_failures() {
+        let data = AppState::in_memory(&[("STORAGE_BACKEND", "memory"), ("SECRET_KEY", "secret"), ("AUTH_FAILURE_LIMIT", "2")]);
+        let app = web_test::init_service(App::new().app_data(data.clone()).route("/get_sessions", web::get().to(sessions))).await;
+
+        let call = |key: &'static str| web_test::TestRequest::get().uri("/get_sessions").insert_header(("X-RLA-KEY", key)).to_request();
+
+        assert_eq!(web_test::call_service(&app, call("secret")).await.status(), StatusCode::OK);
+        assert_eq!(web_test::call_service(&app, call("guess-1")).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(web_test::call_service(&app, call("guess-2")).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Locked out, even with the right key
+        let res = web_test::call_service(&app, call("secret")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+    }
+}
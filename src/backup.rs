@@ -0,0 +1,335 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, Error, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use parking_lot::Mutex;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::config::{Config};
+use crate::db_pool::{DbError, DbPool};
+use crate::route_handlers::{now, sqlite_required, ApiResponse};
+
+/// Attempts to take the read lock of the source before a backup gives up.
+const MAX_BUSY_RETRIES: u32 = 100;
+
+const BUSY_PAUSE: Duration = Duration::from_millis(50);
+
+/// Backup directories are named `{db stem}-{time}`, with `.tmp` appended while written.
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%S%3fZ";
+
+#[derive(Serialize, Clone)]
+pub struct BackupReport {
+    started_at: i64,
+    finished_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    files: usize,
+    bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct BackupStatus {
+    running: bool,
+    last_run: Option<BackupReport>,
+}
+
+pub fn backup_enabled(config: &Config) -> bool {
+    config.backup_dir.is_some()
+}
+
+/// Marks a backup as running. Returns `false` if one is already in progress.
+pub fn try_begin(status: &Mutex<BackupStatus>) -> bool {
+    let mut status = status.lock();
+    if status.running {
+        return false;
+    }
+    status.running = true;
+    true
+}
+
+/// Copies a live database with the online backup API.
+///
+/// The whole database is copied in a single step: in WAL mode this only holds a read
+/// transaction, so ingestion continues, and the copy is a consistent snapshot. Copying
+/// in several steps would restart whenever another connection writes in between.
+fn backup_file(source: &Path, target: &Path) -> Result<u64, DbError> {
+    let source_conn = Connection::open_with_flags(
+        source,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+    )?;
+    let mut target_conn = Connection::open(target)?;
+
+    {
+        let backup = Backup::new(&source_conn, &mut target_conn)?;
+        let mut retries = 0;
+
+        loop {
+            match backup.step(-1)? {
+                StepResult::Done => break,
+                StepResult::More => continue,
+                StepResult::Busy | StepResult::Locked if retries < MAX_BUSY_RETRIES => {
+                    retries += 1;
+                    std::thread::sleep(BUSY_PAUSE);
+                },
+                _ => return Err(DbError::Failed(format!("{} stayed locked", source.display())))
+            }
+        }
+    }
+
+    drop(target_conn);
+    Ok(fs::metadata(target)?.len())
+}
+
+fn db_stem(config: &Config) -> &str {
+    Path::new(&config.db_path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("analytics")
+}
+
+/// Whether `name` is a backup directory of the database `stem`, complete or `partial`.
+/// The time is parsed, so `game` does not match the backups of `game-beta`.
+fn is_backup(name: &str, stem: &str, partial: bool) -> bool {
+    let name = match (partial, name.strip_suffix(".tmp")) {
+        (true, Some(name)) => name,
+        (false, None) => name,
+        _ => return false
+    };
+
+    name.strip_prefix(stem)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .map(|time| NaiveDateTime::parse_from_str(time, TIME_FORMAT).is_ok())
+        .unwrap_or(false)
+}
+
+/// Deletes the oldest backups of this database beyond `BACKUP_KEEP`.
+fn rotate(backup_dir: &Path, config: &Config) -> std::io::Result<()> {
+    let mut backups = fs::read_dir(backup_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| is_backup(name, db_stem(config), false))
+                .unwrap_or(false)
+        })
+        .collect::<Vec<PathBuf>>();
+
+    backups.sort();
+
+    let excess = backups.len().saturating_sub(config.backup_keep.max(1));
+    for path in backups.into_iter().take(excess) {
+        log::info!("Removing old backup {}", path.display());
+        fs::remove_dir_all(path)?;
+    }
+
+    Ok(())
+}
+
+/// Removes backups of this database that were left incomplete, e.g. by a crash.
+pub fn remove_partial_backups(config: &Config) {
+    let Some(backup_dir) = config.backup_dir.as_ref() else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(backup_dir) else {
+        return;
+    };
+
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        let partial = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| is_backup(name, db_stem(config), true))
+            .unwrap_or(false);
+
+        if partial && path.is_dir() {
+            log::warn!("Removing incomplete backup {}", path.display());
+            if let Err(e) = fs::remove_dir_all(&path) {
+                log::error!("Unable to remove incomplete backup {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn copy_files(partial: &Path, sources: Vec<PathBuf>, report: &mut BackupReport) -> Result<(), DbError> {
+    for source in sources {
+        let file_name = source.file_name().map(PathBuf::from).unwrap_or_default();
+        report.bytes += backup_file(&source, &partial.join(file_name))?;
+        report.files += 1;
+    }
+
+    Ok(())
+}
+
+/// Backs up the main database and all event partitions into a new directory.
+fn write_backup(config: &Config, sources: Vec<PathBuf>, report: &mut BackupReport) -> Result<(), DbError> {
+    let backup_dir = PathBuf::from(config.backup_dir.as_ref().ok_or_else(|| DbError::Failed("BACKUP_DIR is not set".to_string()))?);
+    let name = format!("{}-{}", db_stem(config), Utc::now().format(TIME_FORMAT));
+
+    // Written under a temporary name, so incomplete backups are never rotated in
+    let target = backup_dir.join(&name);
+    let partial = backup_dir.join(format!("{}.tmp", name));
+    fs::create_dir_all(&partial)?;
+
+    let result = copy_files(&partial, sources, report).and_then(|_| fs::rename(&partial, &target).map_err(DbError::from));
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(e);
+    }
+
+    report.path = Some(target.display().to_string());
+
+    rotate(&backup_dir, config)?;
+
+    Ok(())
+}
+
+/// Runs a backup that was started with `try_begin` and records its report.
+pub async fn run_backup(db: Arc<DbPool>, config: Arc<Config>, status: Arc<Mutex<BackupStatus>>) {
+    let mut sources = vec![PathBuf::from(&config.db_path)];
+    if let Some(partitions) = db.partitions() {
+        sources.extend(partitions.files());
+    }
+
+    let started_at = now();
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut report = BackupReport { started_at, finished_at: 0, path: None, files: 0, bytes: 0, error: None };
+
+        if let Err(e) = write_backup(&config, sources, &mut report) {
+            report.error = Some(e.to_string());
+        }
+
+        report.finished_at = now();
+        report
+    }).await;
+
+    let report = result.unwrap_or_else(|e| BackupReport {
+        started_at,
+        finished_at: now(),
+        path: None,
+        files: 0,
+        bytes: 0,
+        error: Some(e.to_string()),
+    });
+
+    match report.error.as_ref() {
+        Some(e) => log::error!("Backup failed: {}", e),
+        None => log::info!("Backup of {} files ({} bytes) written to {}", report.files, report.bytes, report.path.as_deref().unwrap_or_default())
+    }
+
+    let mut status = status.lock();
+    status.running = false;
+    status.last_run = Some(report);
+}
+
+fn backups_disabled() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse {
+        success: false,
+        message: "Backups are disabled, set BACKUP_DIR".to_string()
+    })
+}
+
+/// Reports whether a backup is running and the outcome of the last one.
+pub async fn get_backup(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    if data.db.is_none() {
+        return Ok(sqlite_required());
+    }
+
+    if !backup_enabled(&data.config) {
+        return Ok(backups_disabled());
+    }
+
+    let status = data.backup_status.lock().clone();
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Starts a backup in the background. Poll `GET /admin/backup` for the result.
+pub async fn create_backup(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    if !backup_enabled(&data.config) {
+        return Ok(backups_disabled());
+    }
+
+    if !try_begin(&data.backup_status) {
+        return Ok(HttpResponse::Conflict().json(ApiResponse {
+            success: false,
+            message: "A backup is already running".to_string()
+        }));
+    }
+
+    actix_web::rt::spawn(run_backup(db.clone(), data.config.clone(), data.backup_status.clone()));
+
+    Ok(HttpResponse::Accepted().json(ApiResponse {
+        success: true,
+        message: "Backup started".to_string()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app_state::TestDir;
+
+    use super::*;
+
+    #[test]
+    fn matches_only_backups_of_the_database() {
+        assert!(is_backup("game-20261018T101500123Z", "game", false));
+        assert!(is_backup("game-20261018T101500123Z.tmp", "game", true));
+        assert!(!is_backup("game-20261018T101500123Z.tmp", "game", false));
+        assert!(!is_backup("game-20261018T101500123Z", "game", true));
+        assert!(!is_backup("game-beta-20261018T101500123Z", "game", false));
+        assert!(!is_backup("game-beta-20261018T101500123Z.tmp", "game", true));
+        assert!(!is_backup("game-notes", "game", false));
+    }
+
+    #[test]
+    fn rotates_and_cleans_up_only_own_backups() {
+        let dir = TestDir::new("backup");
+        let names = [
+            "game-20261016T000000000Z",
+            "game-20261017T000000000Z",
+            "game-20261018T000000000Z",
+            "game-20261018T120000000Z.tmp",
+            "game-beta-20261001T000000000Z",
+            "game-beta-20261018T120000000Z.tmp",
+        ];
+        for name in names {
+            fs::create_dir(dir.0.join(name)).unwrap();
+        }
+
+        let config = Config::from_pairs(&[
+            ("DB_PATH", &dir.file("game.db")),
+            ("BACKUP_DIR", dir.0.to_str().unwrap()),
+            ("BACKUP_KEEP", "2"),
+        ]);
+
+        rotate(&dir.0, &config).unwrap();
+        remove_partial_backups(&config);
+
+        let mut left = fs::read_dir(&dir.0).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        left.sort();
+
+        assert_eq!(left, [names[1], names[2], names[4], names[5]]);
+    }
+}
@@ -0,0 +1,195 @@
+use std::ops::Deref;
+
+use actix_web::{
+    dev::{Decompress, Payload},
+    error::{self, PayloadError},
+    http::header::{self, Header},
+    web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::{future::LocalBoxFuture, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::app_state::{AppState};
+use crate::compression::{encoded_response};
+use crate::server::{PublicJsonError};
+
+const MIME_MSGPACK: &str = "application/msgpack";
+const MIME_CBOR: &str = "application/cbor";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BodyFormat {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl BodyFormat {
+    fn from_essence(essence: &str) -> Option<BodyFormat> {
+        match essence {
+            "application/json" => Some(BodyFormat::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(BodyFormat::MessagePack),
+            "application/cbor" => Some(BodyFormat::Cbor),
+            _ => None
+        }
+    }
+
+    /// Picks the format of a request body from its `Content-Type`. Anything that is
+    /// neither MessagePack nor CBOR is handed to the JSON extractor.
+    pub fn from_content_type(req: &HttpRequest) -> BodyFormat {
+        match req.mime_type() {
+            Ok(Some(mime)) => BodyFormat::from_essence(mime.essence_str()).unwrap_or(BodyFormat::Json),
+            _ => BodyFormat::Json
+        }
+    }
+
+    /// Picks the response format with the highest quality in the `Accept` header.
+    pub fn from_accept(req: &HttpRequest) -> BodyFormat {
+        let accept = match header::Accept::parse(req) {
+            Ok(accept) => accept,
+            Err(_) => return BodyFormat::Json
+        };
+
+        for mime in accept.ranked() {
+            if mime.essence_str() == "*/*" || mime.essence_str() == "application/*" {
+                return BodyFormat::Json;
+            }
+            if let Some(format) = BodyFormat::from_essence(mime.essence_str()) {
+                return format;
+            }
+        }
+
+        BodyFormat::Json
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            BodyFormat::Json => "application/json",
+            BodyFormat::MessagePack => MIME_MSGPACK,
+            BodyFormat::Cbor => MIME_CBOR,
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, body: &T) -> Result<Vec<u8>, String> {
+        match self {
+            BodyFormat::Json => serde_json::to_vec(body).map_err(|e| e.to_string()),
+            BodyFormat::MessagePack => rmp_serde::to_vec_named(body).map_err(|e| e.to_string()),
+            BodyFormat::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(body, &mut buffer).map_err(|e| e.to_string())?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            BodyFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            BodyFormat::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            BodyFormat::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            BodyFormat::Json => "JSON",
+            BodyFormat::MessagePack => "MessagePack",
+            BodyFormat::Cbor => "CBOR",
+        }
+    }
+}
+
+fn public_error(err: impl std::fmt::Debug + std::fmt::Display + 'static, response: HttpResponse) -> Error {
+    error::InternalError::from_response(err, response).into()
+}
+
+/// Request body extractor accepting JSON, MessagePack and CBOR.
+///
+/// JSON bodies go through `web::Json` so the `web::JsonConfig` set up in `server::main`
+/// keeps applying; binary formats share its size limit and error shape.
+pub struct Body<T>(pub T);
+
+impl<T> Body<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Body<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Body<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = BodyFormat::from_content_type(req);
+
+        if format == BodyFormat::Json {
+            let json = web::Json::<T>::from_request(req, payload);
+            return Box::pin(async move { json.await.map(|body| Body(body.into_inner())) });
+        }
+
+        let (limit, compressed_limit) = req.app_data::<web::Data<AppState>>()
+            .map(|data| (data.config.max_json_payload, data.config.max_compressed_payload))
+            .unwrap_or((0, 0));
+
+        let mut stream = Decompress::from_headers(payload.take(), req.headers());
+
+        Box::pin(async move {
+            let mut buffer = web::BytesMut::new();
+
+            while let Some(chunk) = stream.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(PayloadError::Overflow) => {
+                        let response = HttpResponse::PayloadTooLarge().json(
+                            PublicJsonError { message: format!("Compressed payload too large. Maximum size allowed is {} bytes", compressed_limit) }
+                        );
+                        return Err(public_error(PayloadError::Overflow, response));
+                    },
+                    Err(e) => {
+                        let response = HttpResponse::BadRequest().json(
+                            PublicJsonError { message: format!("Invalid {}: {}", format.name(), e) }
+                        );
+                        return Err(public_error(e, response));
+                    }
+                };
+
+                if buffer.len() + chunk.len() > limit {
+                    let response = HttpResponse::PayloadTooLarge().json(
+                        PublicJsonError { message: format!("Payload too large. Maximum size allowed is {} bytes", limit) }
+                    );
+                    return Err(public_error(PayloadError::Overflow, response));
+                }
+
+                buffer.extend_from_slice(&chunk);
+            }
+
+            format.deserialize(&buffer)
+                .map(Body)
+                .map_err(|e| {
+                    let message = format!("Invalid {}: {}", format.name(), e);
+                    let response = HttpResponse::BadRequest().json(PublicJsonError { message: message.clone() });
+                    public_error(message, response)
+                })
+        })
+    }
+}
+
+/// Serializes `body` in the format negotiated through the `Accept` header.
+pub fn respond<T: Serialize>(req: &HttpRequest, body: &T, min_size: usize) -> HttpResponse {
+    let format = BodyFormat::from_accept(req);
+
+    match format.serialize(body) {
+        Ok(bytes) => encoded_response(bytes, format.content_type(), min_size),
+
+        Err(e) => HttpResponse::InternalServerError().json(
+            PublicJsonError { message: format!("Unable to serialize response: {}", e) }
+        )
+    }
+}
@@ -0,0 +1,114 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::header::{self, ContentEncoding},
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use futures_util::Stream;
+
+use crate::app_state::{AppState};
+use crate::server::{PublicJsonError};
+
+type BoxedPayloadStream = Pin<Box<dyn Stream<Item = Result<web::Bytes, PayloadError>>>>;
+
+/// Wraps a request payload and fails with `PayloadError::Overflow` as soon as
+/// more than `remaining` bytes of the (still compressed) body have been read.
+struct CappedPayload {
+    inner: Payload,
+    remaining: usize,
+}
+
+impl Stream for CappedPayload {
+    type Item = Result<web::Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if chunk.len() > self.remaining {
+                    self.remaining = 0;
+                    Poll::Ready(Some(Err(PayloadError::Overflow)))
+                } else {
+                    self.remaining -= chunk.len();
+                    Poll::Ready(Some(Ok(chunk)))
+                }
+            },
+            other => other,
+        }
+    }
+}
+
+fn request_encoding(req: &ServiceRequest) -> Result<ContentEncoding, ()> {
+    match req.headers().get(header::CONTENT_ENCODING) {
+        None => Ok(ContentEncoding::Identity),
+        Some(value) => match value.to_str().map(|s| s.trim().to_ascii_lowercase()).as_deref() {
+            Ok("gzip") => Ok(ContentEncoding::Gzip),
+            Ok("br") => Ok(ContentEncoding::Brotli),
+            Ok("zstd") => Ok(ContentEncoding::Zstd),
+            Ok("identity") | Ok("") => Ok(ContentEncoding::Identity),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Middleware for ingestion routes accepting `Content-Encoding: gzip`, `br` and `zstd`.
+///
+/// The compressed body is capped at `max_compressed_payload`, while the extractors
+/// apply `max_json_payload` to the decompressed body.
+pub async fn limit_compressed_payload(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let encoding = match request_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(_) => {
+            let response = HttpResponse::UnsupportedMediaType().json(
+                PublicJsonError { message: "Unsupported Content-Encoding. Use gzip, br or zstd".to_string() }
+            );
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+
+    if encoding != ContentEncoding::Identity {
+        let limit = req.app_data::<web::Data<AppState>>()
+            .map(|data| data.config.max_compressed_payload)
+            .unwrap_or(0);
+
+        let length = req.headers().get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.parse::<usize>().ok());
+
+        if length.is_some_and(|length| length > limit) {
+            let response = HttpResponse::PayloadTooLarge().json(
+                PublicJsonError { message: format!("Compressed payload too large. Maximum size allowed is {} bytes", limit) }
+            );
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+
+        // The content length describes the compressed body; drop it so the extractors
+        // only compare the decompressed size against their limit.
+        req.headers_mut().remove(header::CONTENT_LENGTH);
+
+        let capped: BoxedPayloadStream = Box::pin(CappedPayload { inner: req.take_payload(), remaining: limit });
+        req.set_payload(Payload::from(capped));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Builds a response from already serialized bytes, opting out of response compression
+/// when the body is smaller than `min_size` bytes.
+pub fn encoded_response(bytes: Vec<u8>, content_type: &str, min_size: usize) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.content_type(content_type);
+
+    if bytes.len() < min_size {
+        response.insert_header(ContentEncoding::Identity);
+    }
+
+    response.body(bytes)
+}
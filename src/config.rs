@@ -1,11 +1,88 @@
 use std::env;
+use std::path::Path;
+
+/// Time span covered by one event partition file.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PartitionPeriod {
+    Month,
+    Day,
+}
+
+/// Where sessions and events are stored.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Sqlite,
+    Memory,
+}
+
+/// How the IP address of a request is stored with its session and events.
+#[derive(Clone, Copy, PartialEq)]
+pub enum IpPrivacy {
+    Full,
+    /// IPv4 addresses cut to /24, IPv6 addresses to /48
+    Truncated,
+    /// Salted SHA-256, with a new random salt every day
+    Hashed,
+    None,
+}
+
+/// What a player consents to, and what an event needs consent to be stored.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConsentCategory {
+    Analytics,
+    CrashReporting,
+    Personalized,
+}
+
+impl ConsentCategory {
+    pub const ALL: [ConsentCategory; 3] = [ConsentCategory::Analytics, ConsentCategory::CrashReporting, ConsentCategory::Personalized];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConsentCategory::Analytics => "analytics",
+            ConsentCategory::CrashReporting => "crash_reporting",
+            ConsentCategory::Personalized => "personalized",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<ConsentCategory> {
+        ConsentCategory::ALL.into_iter().find(|category| category.as_str() == value)
+    }
+}
 
 #[derive(Clone)]
 pub struct Config {
+    /// `None` for the default project served without a URL prefix
+    pub project: Option<String>,
+    /// Names of the additional projects, only set on the default project
+    pub projects: Vec<String>,
+    pub storage_backend: StorageBackend,
+    pub db_path: String,
+    pub db_readers: usize,
+    pub db_busy_timeout: u64,
+    pub db_synchronous: String,
+    pub db_pragmas: Option<String>,
+    pub db_max_tasks: usize,
+    pub db_queue_timeout: u64,
+    pub db_task_timeout: u64,
+    pub db_partitioning: Option<PartitionPeriod>,
+    pub db_partitions_attached: usize,
+    pub db_partition_archive_dir: Option<String>,
     pub secret_key: Option<String>,
+    pub ingest_key: Option<String>,
+    /// Build id and key pairs, ingestion requests must be signed if not empty
+    pub ingest_signing_keys: Vec<(String, String)>,
+    pub ingest_signature_window: u64,
+    pub auth_failure_limit: u64,
+    pub auth_failure_window: u64,
     pub max_events_per_second: u64,
     pub host: String,
     pub port: u16,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    pub tls_reload_interval: u64,
+    pub http_redirect_port: Option<u16>,
     pub max_ratelimit_entries: usize,
     pub ratelimiter_cleanup_interval: u64,
     pub ratelimit_cache_entry_lifetime: u64,
@@ -13,8 +90,40 @@ pub struct Config {
     pub ingest_event_cost: u64,
     pub token_bucket_size: u64,
     pub trust_proxy: u64,
+    pub ip_privacy: IpPrivacy,
     pub max_json_payload: usize,
+    pub max_compressed_payload: usize,
+    pub compress_min_response_size: usize,
+    pub max_stream_payload: usize,
+    pub ingest_stream_cost: u64,
+    pub ingest_stream_batch_size: usize,
+    pub ws_connect_cost: u64,
+    pub ws_max_events_per_second: u64,
+    pub ws_idle_timeout: u64,
+    pub ws_max_connections_per_ip: usize,
     pub cors_origins: Option<String>,
+    pub auto_migrate: bool,
+    pub retention_events_days: Option<u64>,
+    pub retention_sessions_days: Option<u64>,
+    pub retention_event_overrides: Vec<(String, u64)>,
+    pub retention_interval: u64,
+    pub retention_batch_size: usize,
+    pub backup_dir: Option<String>,
+    pub backup_interval: u64,
+    pub backup_keep: usize,
+    pub parquet_archive_dir: Option<String>,
+    pub parquet_archive_after_days: u64,
+    pub parquet_archive_by_event_name: bool,
+    pub parquet_archive_interval: u64,
+    pub parquet_archive_batch_size: usize,
+    pub export_page_size: usize,
+    pub rollup_interval: u64,
+    pub rollup_batch_size: usize,
+    pub search_event_names: Vec<String>,
+    /// Event names needing consent to a category other than `analytics`
+    pub consent_categories: Vec<(String, ConsentCategory)>,
+    /// Consent assumed for categories a session was created without
+    pub consent_default: bool,
 
     pub redis_connection_hostname: Option<String>,
     pub redis_connection_db: i64,
@@ -25,6 +134,145 @@ pub struct Config {
     pub redis_connection_protocol: Option<String>,
 }
 
+fn parse_synchronous(input: Option<String>) -> String {
+    match input.as_deref().map(str::to_uppercase).as_deref() {
+        None | Some("") => "NORMAL".to_string(),
+        Some(mode @ ("OFF" | "NORMAL" | "FULL" | "EXTRA")) => mode.to_string(),
+        _ => panic!("Invalid value provided for DB_SYNCHRONOUS")
+    }
+}
+
+fn parse_days(var: &dyn Fn(&str) -> Result<String, env::VarError>, name: &str) -> Option<u64> {
+    var(name)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse().unwrap_or_else(|_| panic!("Invalid value provided for {}", name)))
+}
+
+/// Parses `event_name=days` pairs, e.g. `purchase=730,frame_time=7`.
+fn parse_retention_overrides(input: Option<String>) -> Vec<(String, u64)> {
+    input
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, days) = entry
+                .split_once('=')
+                .expect("Invalid value provided for RETENTION_EVENT_OVERRIDES");
+            let days = days
+                .trim()
+                .parse()
+                .expect("Invalid value provided for RETENTION_EVENT_OVERRIDES");
+            (name.trim().to_string(), days)
+        })
+        .collect()
+}
+
+/// Parses `build=key` pairs, e.g. `1.4.0=k3y,1.5.0=0th3r`. Keys may contain `=`.
+fn parse_signing_keys(input: Option<String>) -> Vec<(String, String)> {
+    parse_list(input)
+        .into_iter()
+        .map(|entry| {
+            let (build, key) = entry
+                .split_once('=')
+                .filter(|(build, key)| !build.trim().is_empty() && !key.is_empty())
+                .expect("Invalid value provided for INGEST_SIGNING_KEYS");
+            (build.trim().to_string(), key.to_string())
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list, e.g. `crash,item_found`.
+fn parse_list(input: Option<String>) -> Vec<String> {
+    input
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses `event_name=category` pairs, e.g. `crash=crash_reporting,offer_shown=personalized`.
+fn parse_consent_categories(input: Option<String>) -> Vec<(String, ConsentCategory)> {
+    parse_list(input)
+        .into_iter()
+        .map(|entry| {
+            let (name, category) = entry
+                .split_once('=')
+                .and_then(|(name, category)| Some((name.trim(), ConsentCategory::parse(category.trim())?)))
+                .filter(|(name, _)| !name.is_empty())
+                .expect("Invalid value provided for CONSENT_CATEGORIES");
+            (name.to_string(), category)
+        })
+        .collect()
+}
+
+fn parse_consent_default(input: Option<String>) -> bool {
+    match input.as_deref().map(str::to_lowercase).as_deref() {
+        None | Some("") | Some("granted") => true,
+        Some("denied") => false,
+        _ => panic!("Invalid value provided for CONSENT_DEFAULT")
+    }
+}
+
+/// Parses the project names, e.g. `dungeon,tower-defense`.
+fn parse_projects(input: Option<String>) -> Vec<String> {
+    let projects = parse_list(input);
+
+    for project in projects.iter() {
+        if !project.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            panic!("Invalid value provided for PROJECTS");
+        }
+    }
+
+    projects
+}
+
+/// Adds the project name to a file name, e.g. `analytics.db` becomes `analytics-dungeon.db`.
+fn project_file(path: &str, project: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("analytics");
+
+    let name = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => format!("{}-{}.{}", stem, project, extension),
+        None => format!("{}-{}", stem, project)
+    };
+
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Settings every project configures itself instead of inheriting them.
+const PROJECT_ONLY: [&str; 4] = ["SECRET_KEY", "INGEST_KEY", "INGEST_SIGNING_KEYS", "ALLOWED_ORIGINS"];
+
+fn parse_partitioning(input: Option<String>) -> Option<PartitionPeriod> {
+    match input.as_deref().map(str::to_lowercase).as_deref() {
+        None | Some("") | Some("none") => None,
+        Some("month") => Some(PartitionPeriod::Month),
+        Some("day") => Some(PartitionPeriod::Day),
+        _ => panic!("Invalid value provided for DB_PARTITIONING")
+    }
+}
+
+fn parse_storage_backend(input: Option<String>) -> StorageBackend {
+    match input.as_deref().map(str::to_lowercase).as_deref() {
+        None | Some("") | Some("sqlite") => StorageBackend::Sqlite,
+        Some("memory") => StorageBackend::Memory,
+        _ => panic!("Invalid value provided for STORAGE_BACKEND")
+    }
+}
+
+pub fn parse_ip_privacy(input: Option<&str>) -> Option<IpPrivacy> {
+    match input.map(str::to_lowercase).as_deref() {
+        None | Some("") | Some("full") => Some(IpPrivacy::Full),
+        Some("truncate") => Some(IpPrivacy::Truncated),
+        Some("hash") => Some(IpPrivacy::Hashed),
+        Some("none") => Some(IpPrivacy::None),
+        _ => None
+    }
+}
+
 fn parse_bool(input: Option<String>, default_value: bool) -> bool {
     match input.as_deref() {
         Some("") => default_value,
@@ -45,65 +293,265 @@ fn parse_bool(input: Option<String>, default_value: bool) -> bool {
 
 impl Config {
     pub fn from_env() -> Config {
+        let mut config = Config::from_vars(&|name| env::var(name));
+        config.projects = parse_projects(env::var("PROJECTS").ok());
+        config
+    }
+
+    /// Config of an additional project. `PROJECT_<NAME>_<VAR>` overrides `<VAR>`, e.g.
+    /// `PROJECT_DUNGEON_DB_PATH` for the project `dungeon`. Keys and CORS origins are never
+    /// inherited, inherited file locations get the project name added.
+    pub fn for_project(project: &str) -> Config {
+        let prefix = format!("PROJECT_{}_", project.to_uppercase().replace('-', "_"));
+        let own = |name: &str| env::var(format!("{}{}", prefix, name));
+
+        let mut config = Config::from_vars(&|name| match own(name) {
+            Err(_) if !PROJECT_ONLY.contains(&name) => env::var(name),
+            value => value
+        });
+        config.project = Some(project.to_string());
+
+        if own("DB_PATH").is_err() {
+            config.db_path = project_file(&config.db_path, project);
+        }
+
+        let dirs = [
+            ("DB_PARTITION_ARCHIVE_DIR", &mut config.db_partition_archive_dir),
+            ("BACKUP_DIR", &mut config.backup_dir),
+            ("PARQUET_ARCHIVE_DIR", &mut config.parquet_archive_dir),
+        ];
+        for (name, dir) in dirs {
+            if let Some(dir) = dir.as_mut().filter(|_| own(name).is_err()) {
+                *dir = Path::new(dir).join(project).to_string_lossy().into_owned();
+            }
+        }
+
+        config
+    }
+
+    /// Configs of the additional projects listed in `PROJECTS`.
+    pub fn project_configs(&self) -> Vec<Config> {
+        self.projects.iter().map(|project| Config::for_project(project)).collect()
+    }
+
+    /// Config read from `vars` alone, unaffected by the environment of the test run.
+    #[cfg(test)]
+    pub fn from_pairs(vars: &[(&str, &str)]) -> Config {
+        Config::from_vars(&|name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+                .ok_or(env::VarError::NotPresent)
+        })
+    }
+
+    /// Reads the config from `var`, which looks up an environment variable by name.
+    fn from_vars(var: &dyn Fn(&str) -> Result<String, env::VarError>) -> Config {
         Config {
-            secret_key: env::var("SECRET_KEY").ok(),
-            max_events_per_second: env::var("MAX_EVENTS_PER_SECOND")
+            project: None,
+            projects: Vec::new(),
+            storage_backend: parse_storage_backend(var("STORAGE_BACKEND").ok()),
+            db_path: var("DB_PATH")
+                .unwrap_or_else(|_| "analytics.db".to_string()),
+            db_readers: var("DB_READERS")
+                .unwrap_or_else(|_| "4".to_string())
+                .parse()
+                .expect("Invalid value provided for DB_READERS"),
+            db_busy_timeout: var("DB_BUSY_TIMEOUT")
+                .unwrap_or_else(|_| "5000".to_string())
+                .parse()
+                .expect("Invalid value provided for DB_BUSY_TIMEOUT"),
+            db_synchronous: parse_synchronous(var("DB_SYNCHRONOUS").ok()),
+            db_pragmas: var("DB_PRAGMAS").ok(),
+            db_max_tasks: var("DB_MAX_TASKS")
+                .unwrap_or_else(|_| "16".to_string())
+                .parse()
+                .expect("Invalid value provided for DB_MAX_TASKS"),
+            db_queue_timeout: var("DB_QUEUE_TIMEOUT")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse()
+                .expect("Invalid value provided for DB_QUEUE_TIMEOUT"),
+            db_task_timeout: var("DB_TASK_TIMEOUT")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse()
+                .expect("Invalid value provided for DB_TASK_TIMEOUT"),
+            db_partitioning: parse_partitioning(var("DB_PARTITIONING").ok()),
+            db_partitions_attached: var("DB_PARTITIONS_ATTACHED")
+                .unwrap_or_else(|_| "8".to_string())
+                .parse()
+                .expect("Invalid value provided for DB_PARTITIONS_ATTACHED"),
+            db_partition_archive_dir: var("DB_PARTITION_ARCHIVE_DIR").ok(),
+            secret_key: var("SECRET_KEY").ok(),
+            ingest_key: var("INGEST_KEY").ok().filter(|key| !key.is_empty()),
+            ingest_signing_keys: parse_signing_keys(var("INGEST_SIGNING_KEYS").ok()),
+            ingest_signature_window: var("INGEST_SIGNATURE_WINDOW")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .expect("Invalid value provided for INGEST_SIGNATURE_WINDOW"),
+            auth_failure_limit: var("AUTH_FAILURE_LIMIT")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .expect("Invalid value provided for AUTH_FAILURE_LIMIT"),
+            auth_failure_window: var("AUTH_FAILURE_WINDOW")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .expect("Invalid value provided for AUTH_FAILURE_WINDOW"),
+            max_events_per_second: var("MAX_EVENTS_PER_SECOND")
                 .unwrap_or_else(|_| "5".to_string())
                 .parse()
                 .expect("Invalid value provided for MAX_EVENTS_PER_SECOND"),
-            host: env::var("HOST")
+            host: var("HOST")
                 .unwrap_or_else(|_| "127.0.0.1".to_string()),
-            port: env::var("PORT")
+            port: var("PORT")
                 .unwrap_or_else(|_| "8080".to_string())
                 .parse()
                 .expect("Invalid value provided for PORT"),
-            max_ratelimit_entries: env::var("MAX_RATELIMIT_ENTRIES")
+            tls_cert_path: var("TLS_CERT_PATH").ok().filter(|path| !path.is_empty()),
+            tls_key_path: var("TLS_KEY_PATH").ok().filter(|path| !path.is_empty()),
+            tls_client_ca_path: var("TLS_CLIENT_CA_PATH").ok().filter(|path| !path.is_empty()),
+            tls_reload_interval: var("TLS_RELOAD_INTERVAL")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("Invalid value provided for TLS_RELOAD_INTERVAL"),
+            http_redirect_port: var("HTTP_REDIRECT_PORT")
+                .ok()
+                .filter(|port| !port.is_empty())
+                .map(|port| port.parse().expect("Invalid value provided for HTTP_REDIRECT_PORT")),
+            max_ratelimit_entries: var("MAX_RATELIMIT_ENTRIES")
                 .unwrap_or_else(|_| "1000".to_string())
                 .parse()
                 .expect("Invalid value provided for MAX_RATELIMIT_ENTRIES"),
-            ratelimiter_cleanup_interval: env::var("RATE_LIMITER_CLEANUP_INTERVAL")
+            ratelimiter_cleanup_interval: var("RATE_LIMITER_CLEANUP_INTERVAL")
                 .unwrap_or_else(|_| "60".to_string())
                 .parse()
                 .expect("Invalid value provided for RATE_LIMITER_CLEANUP_INTERVAL"),
-            ratelimit_cache_entry_lifetime: env::var("RATELIMIT_CACHE_ENTRY_LIFETIME")
+            ratelimit_cache_entry_lifetime: var("RATELIMIT_CACHE_ENTRY_LIFETIME")
                 .unwrap_or_else(|_| "300".to_string())
                 .parse()
                 .expect("Invalid value provided for RATELIMIT_CACHE_ENTRY_LIFETIME"),
-            create_session_cost: env::var("CREATE_SESSION_COST")
+            create_session_cost: var("CREATE_SESSION_COST")
                 .unwrap_or_else(|_| "5".to_string())
                 .parse()
                 .expect("Invalid value provided for CREATE_SESSION_COST"),
-            ingest_event_cost: env::var("INGEST_EVENT_COST")
+            ingest_event_cost: var("INGEST_EVENT_COST")
                 .unwrap_or_else(|_| "1".to_string())
                 .parse()
                 .expect("Invalid value provided for INGEST_EVENT_COST"),
-            token_bucket_size: env::var("TOKEN_BUCKET_SIZE")
+            token_bucket_size: var("TOKEN_BUCKET_SIZE")
                 .unwrap_or_else(|_| "10".to_string())
                 .parse()
                 .expect("Invalid value provided for TOKEN_BUCKET_SIZE"),
-            max_json_payload: env::var("MAX_JSON_PAYLOAD")
+            max_json_payload: var("MAX_JSON_PAYLOAD")
                 .unwrap_or_else(|_| "4096".to_string())
                 .parse()
                 .expect("Invalid value provided for MAX_JSON_PAYLOAD"),
-            cors_origins: env::var("ALLOWED_ORIGINS").ok(),
-            trust_proxy: env::var("TRUST_PROXY")
+            max_compressed_payload: var("MAX_COMPRESSED_PAYLOAD")
+                .unwrap_or_else(|_| "4096".to_string())
+                .parse()
+                .expect("Invalid value provided for MAX_COMPRESSED_PAYLOAD"),
+            compress_min_response_size: var("COMPRESS_MIN_RESPONSE_SIZE")
+                .unwrap_or_else(|_| "1024".to_string())
+                .parse()
+                .expect("Invalid value provided for COMPRESS_MIN_RESPONSE_SIZE"),
+            max_stream_payload: var("MAX_STREAM_PAYLOAD")
+                .unwrap_or_else(|_| "16777216".to_string())
+                .parse()
+                .expect("Invalid value provided for MAX_STREAM_PAYLOAD"),
+            ingest_stream_cost: var("INGEST_STREAM_COST")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .expect("Invalid value provided for INGEST_STREAM_COST"),
+            ingest_stream_batch_size: var("INGEST_STREAM_BATCH_SIZE")
+                .unwrap_or_else(|_| "500".to_string())
+                .parse()
+                .expect("Invalid value provided for INGEST_STREAM_BATCH_SIZE"),
+            ws_connect_cost: var("WS_CONNECT_COST")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .expect("Invalid value provided for WS_CONNECT_COST"),
+            ws_max_events_per_second: var("WS_MAX_EVENTS_PER_SECOND")
+                .unwrap_or_else(|_| "20".to_string())
+                .parse()
+                .expect("Invalid value provided for WS_MAX_EVENTS_PER_SECOND"),
+            ws_idle_timeout: var("WS_IDLE_TIMEOUT")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("Invalid value provided for WS_IDLE_TIMEOUT"),
+            ws_max_connections_per_ip: var("WS_MAX_CONNECTIONS_PER_IP")
+                .unwrap_or_else(|_| "4".to_string())
+                .parse()
+                .expect("Invalid value provided for WS_MAX_CONNECTIONS_PER_IP"),
+            cors_origins: var("ALLOWED_ORIGINS").ok(),
+            auto_migrate: parse_bool(var("AUTO_MIGRATE").ok(), true),
+            retention_events_days: parse_days(var, "RETENTION_EVENTS_DAYS"),
+            retention_sessions_days: parse_days(var, "RETENTION_SESSIONS_DAYS"),
+            retention_event_overrides: parse_retention_overrides(var("RETENTION_EVENT_OVERRIDES").ok()),
+            retention_interval: var("RETENTION_INTERVAL")
+                .unwrap_or_else(|_| "3600".to_string())
+                .parse()
+                .expect("Invalid value provided for RETENTION_INTERVAL"),
+            retention_batch_size: var("RETENTION_BATCH_SIZE")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse()
+                .expect("Invalid value provided for RETENTION_BATCH_SIZE"),
+            backup_dir: var("BACKUP_DIR").ok().filter(|dir| !dir.is_empty()),
+            backup_interval: var("BACKUP_INTERVAL")
+                .unwrap_or_else(|_| "86400".to_string())
+                .parse()
+                .expect("Invalid value provided for BACKUP_INTERVAL"),
+            backup_keep: var("BACKUP_KEEP")
+                .unwrap_or_else(|_| "7".to_string())
+                .parse()
+                .expect("Invalid value provided for BACKUP_KEEP"),
+            parquet_archive_dir: var("PARQUET_ARCHIVE_DIR").ok().filter(|dir| !dir.is_empty()),
+            parquet_archive_after_days: var("PARQUET_ARCHIVE_AFTER_DAYS")
+                .unwrap_or_else(|_| "90".to_string())
+                .parse()
+                .expect("Invalid value provided for PARQUET_ARCHIVE_AFTER_DAYS"),
+            parquet_archive_by_event_name: parse_bool(var("PARQUET_ARCHIVE_BY_EVENT_NAME").ok(), false),
+            parquet_archive_interval: var("PARQUET_ARCHIVE_INTERVAL")
+                .unwrap_or_else(|_| "86400".to_string())
+                .parse()
+                .expect("Invalid value provided for PARQUET_ARCHIVE_INTERVAL"),
+            parquet_archive_batch_size: var("PARQUET_ARCHIVE_BATCH_SIZE")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse()
+                .expect("Invalid value provided for PARQUET_ARCHIVE_BATCH_SIZE"),
+            export_page_size: var("EXPORT_PAGE_SIZE")
+                .unwrap_or_else(|_| "5000".to_string())
+                .parse()
+                .expect("Invalid value provided for EXPORT_PAGE_SIZE"),
+            rollup_interval: var("ROLLUP_INTERVAL")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("Invalid value provided for ROLLUP_INTERVAL"),
+            rollup_batch_size: var("ROLLUP_BATCH_SIZE")
+                .unwrap_or_else(|_| "10000".to_string())
+                .parse()
+                .expect("Invalid value provided for ROLLUP_BATCH_SIZE"),
+            search_event_names: parse_list(var("SEARCH_EVENT_NAMES").ok()),
+            consent_categories: parse_consent_categories(var("CONSENT_CATEGORIES").ok()),
+            consent_default: parse_consent_default(var("CONSENT_DEFAULT").ok()),
+            trust_proxy: var("TRUST_PROXY")
                 .unwrap_or_else(|_| "0".to_string())
                 .parse()
                 .expect("Trust proxy must either be zero or non-zero"),
+            ip_privacy: parse_ip_privacy(var("IP_PRIVACY").ok().as_deref())
+                .expect("Invalid value provided for IP_PRIVACY"),
 
-            redis_connection_hostname: env::var("REDIS_HOSTNAME").ok(),
-            redis_connection_username: env::var("REDIS_USERNAME").ok(),
-            redis_connection_password: env::var("REDIS_PASSWORD").ok(),
-            redis_connection_protocol: env::var("REDIS_PROTOCOL").ok(),
-            redis_connection_port: env::var("REDIS_PORT")
+            redis_connection_hostname: var("REDIS_HOSTNAME").ok(),
+            redis_connection_username: var("REDIS_USERNAME").ok(),
+            redis_connection_password: var("REDIS_PASSWORD").ok(),
+            redis_connection_protocol: var("REDIS_PROTOCOL").ok(),
+            redis_connection_port: var("REDIS_PORT")
                 .unwrap_or_else(|_| "6379".to_string())
                 .parse()
                 .expect("Invalid number specified for REDIS_PORT"),            
-            redis_connection_db: env::var("REDIS_DATABASE")
+            redis_connection_db: var("REDIS_DATABASE")
                 .unwrap_or_else(|_| "0".to_string())
                 .parse()
                 .expect("Invalid number specified for REDIS_DATABASE"),
-            redis_connection_use_tls: parse_bool(env::var("REDIS_USE_TLS").ok(), false),            
+            redis_connection_use_tls: parse_bool(var("REDIS_USE_TLS").ok(), false),            
         }      
     }
 }
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::{AppState};
+use crate::body_format::{Body};
+use crate::config::{Config, ConsentCategory};
+use crate::db_pool::{DbError};
+use crate::rate_limit::{check_rate_limit};
+use crate::route_handlers::{check_ingest_key, database_error, get_request_id, ingest_key_rejected, ApiResponse};
+
+/// Cached consent of sessions not ingested into for this long is looked up again.
+const CACHE_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// The consent state of a player, recorded with each session.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Consent {
+    pub analytics: bool,
+    pub crash_reporting: bool,
+    pub personalized: bool,
+}
+
+impl Consent {
+    pub fn all(granted: bool) -> Consent {
+        Consent { analytics: granted, crash_reporting: granted, personalized: granted }
+    }
+
+    pub fn allows(&self, category: ConsentCategory) -> bool {
+        match category {
+            ConsentCategory::Analytics => self.analytics,
+            ConsentCategory::CrashReporting => self.crash_reporting,
+            ConsentCategory::Personalized => self.personalized,
+        }
+    }
+}
+
+/// Consent as sent by clients. Categories left out keep their state, or get
+/// `CONSENT_DEFAULT` when the session is created.
+#[derive(Deserialize, Default, Debug)]
+pub struct ConsentRequest {
+    analytics: Option<bool>,
+    crash_reporting: Option<bool>,
+    personalized: Option<bool>,
+}
+
+impl ConsentRequest {
+    pub fn apply(&self, consent: Consent) -> Consent {
+        Consent {
+            analytics: self.analytics.unwrap_or(consent.analytics),
+            crash_reporting: self.crash_reporting.unwrap_or(consent.crash_reporting),
+            personalized: self.personalized.unwrap_or(consent.personalized),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateConsentRequest {
+    session_id: String,
+    #[serde(flatten)]
+    consent: ConsentRequest,
+}
+
+#[derive(Serialize)]
+struct UpdateConsentResponse {
+    session_id: String,
+    consent: Consent,
+}
+
+/// The consent of recently active sessions, so ingestion does not look it up for
+/// every event, and the number of events dropped per category.
+#[derive(Default)]
+pub struct ConsentTracker {
+    sessions: Mutex<HashMap<String, (Consent, Instant)>>,
+    dropped: [AtomicU64; 3],
+}
+
+impl ConsentTracker {
+    pub fn remember(&self, session_id: &str, consent: Consent) {
+        self.sessions.lock().insert(session_id.to_string(), (consent, Instant::now()));
+    }
+
+    fn cached(&self, session_id: &str) -> Option<Consent> {
+        let mut sessions = self.sessions.lock();
+        let (consent, last_access) = sessions.get_mut(session_id)?;
+        *last_access = Instant::now();
+        Some(*consent)
+    }
+
+    fn count_dropped(&self, category: ConsentCategory) {
+        self.dropped[category as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, category: ConsentCategory) -> u64 {
+        self.dropped[category as usize].load(Ordering::Relaxed)
+    }
+
+    /// Forgets the consent of sessions that have not been ingested into for a while.
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        self.sessions.lock().retain(|_, (_, last_access)| now.duration_since(*last_access) <= CACHE_LIFETIME);
+    }
+}
+
+/// The category an event needs consent to, `analytics` unless `CONSENT_CATEGORIES` lists it.
+pub fn category(config: &Config, event_name: &str) -> ConsentCategory {
+    config.consent_categories
+        .iter()
+        .find(|(name, _)| name == event_name)
+        .map(|(_, category)| *category)
+        .unwrap_or(ConsentCategory::Analytics)
+}
+
+/// The category the player has not consented to if the event has to be dropped, which
+/// is counted. Events of unknown sessions pass, the storage rejects them.
+pub async fn missing_consent(data: &AppState, session_id: &str, event_name: &str) -> Result<Option<ConsentCategory>, DbError> {
+    let consent = match data.consent.cached(session_id) {
+        Some(consent) => consent,
+        None => match data.storage.get_consent(session_id.to_string()).await? {
+            Some(consent) => {
+                data.consent.remember(session_id, consent);
+                consent
+            },
+            None => return Ok(None)
+        }
+    };
+
+    let category = category(&data.config, event_name);
+
+    if consent.allows(category) {
+        return Ok(None);
+    }
+
+    data.consent.count_dropped(category);
+    Ok(Some(category))
+}
+
+pub fn dropped_message(category: ConsentCategory) -> String {
+    format!("Event dropped, no consent to {}", category.as_str())
+}
+
+/// Changes the consent of a session, e.g. when the player opens the privacy settings
+/// mid-run. Only the categories sent are changed; later events are checked against
+/// the new state, events already stored are kept.
+pub async fn update_consent(req: HttpRequest, data: web::Data<AppState>, payload: Body<UpdateConsentRequest>) -> impl Responder {
+    // Check the ingestion key of the project
+    if !check_ingest_key(&req, &data.config) {
+        return ingest_key_rejected();
+    }
+
+    // Rate limiting per IP address
+    let ip = get_request_id(&req, &data).unwrap_or("unknown".to_string());
+
+    if !check_rate_limit(&data, &ip, data.config.create_session_cost) {
+        return HttpResponse::TooManyRequests().json(ApiResponse {
+            success: false,
+            message: "Rate limit exceeded".to_string()
+        });
+    }
+
+    let request = payload.into_inner();
+
+    let current = match data.storage.get_consent(request.session_id.clone()).await {
+        Ok(Some(consent)) => consent,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(ApiResponse {
+                success: false,
+                message: "Unknown session".to_string()
+            });
+        },
+        Err(e) => return database_error("Consent not updated", e)
+    };
+
+    let consent = request.consent.apply(current);
+
+    match data.storage.set_consent(request.session_id.clone(), consent).await {
+        Ok(_) => {
+            data.consent.remember(&request.session_id, consent);
+
+            HttpResponse::Ok().json(UpdateConsentResponse {
+                session_id: request.session_id,
+                consent,
+            })
+        },
+        Err(e) => database_error("Consent not updated", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test as web_test, App};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::storage::{NewSession};
+
+    const VARS: [(&str, &str); 3] = [
+        ("STORAGE_BACKEND", "memory"),
+        ("MAX_EVENTS_PER_SECOND", "1000"),
+        ("CONSENT_CATEGORIES", "crash=crash_reporting,offer_shown=personalized"),
+    ];
+
+    async fn session(data: &AppState, session_id: &str, consent: Consent) {
+        data.storage.create_session(NewSession {
+            session_id: session_id.to_string(),
+            user_id: "player".to_string(),
+            ip_address: String::new(),
+            device_model: None,
+            operating_system: None,
+            screen_width: None,
+            screen_height: None,
+            user_agent: None,
+            consent,
+        }).await.unwrap();
+    }
+
+    #[test]
+    fn applies_only_the_categories_sent() {
+        let request = ConsentRequest { analytics: None, crash_reporting: Some(false), personalized: Some(true) };
+        let consent = Consent { analytics: true, crash_reporting: true, personalized: false };
+
+        assert_eq!(request.apply(consent), Consent { analytics: true, crash_reporting: false, personalized: true });
+        assert_eq!(ConsentRequest::default().apply(consent), consent);
+    }
+
+    #[actix_web::test]
+    async fn drops_events_of_categories_without_consent() {
+        let data = AppState::in_memory(&VARS);
+        session(&data, "s1", Consent { analytics: true, crash_reporting: false, personalized: true }).await;
+
+        assert_eq!(missing_consent(&data, "s1", "level_start").await.unwrap(), None);
+        assert_eq!(missing_consent(&data, "s1", "offer_shown").await.unwrap(), None);
+        assert_eq!(missing_consent(&data, "s1", "crash").await.unwrap(), Some(ConsentCategory::CrashReporting));
+        assert_eq!(missing_consent(&data, "unknown", "crash").await.unwrap(), None);
+
+        assert_eq!(data.consent.dropped(ConsentCategory::CrashReporting), 1);
+        assert_eq!(data.consent.dropped(ConsentCategory::Analytics), 0);
+    }
+
+    #[actix_web::test]
+    async fn updates_apply_to_later_events() {
+        let data = AppState::in_memory(&VARS);
+        session(&data, "s1", Consent::all(true)).await;
+
+        let app = web_test::init_service(App::new().app_data(data.clone()).route("/update_consent", web::post().to(update_consent))).await;
+
+        // Cache the consent before the update
+        assert_eq!(missing_consent(&data, "s1", "offer_shown").await.unwrap(), None);
+
+        let req = web_test::TestRequest::post().uri("/update_consent").set_json(json!({ "session_id": "s1", "personalized": false })).to_request();
+        let res: Value = web_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["consent"], json!({ "analytics": true, "crash_reporting": true, "personalized": false }));
+
+        assert_eq!(missing_consent(&data, "s1", "offer_shown").await.unwrap(), Some(ConsentCategory::Personalized));
+        assert_eq!(missing_consent(&data, "s1", "level_start").await.unwrap(), None);
+
+        let req = web_test::TestRequest::post().uri("/update_consent").set_json(json!({ "session_id": "unknown", "analytics": false })).to_request();
+        assert_eq!(web_test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+}
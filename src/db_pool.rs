@@ -1,71 +1,477 @@
-use rusqlite::{Connection};
-use std::sync::Once;
-use std::env;
-
-static INIT: Once = Once::new();
-static DB_PATH: &str = "analytics.db";
-
-fn initialize_database() {
-    INIT.call_once(|| {
-        let path = env::var("DB_PATH").unwrap_or_else(|_| DB_PATH.to_string());
-        let conn = Connection::open(path).expect("Failed to open database");
-
-        // Create tables if they do not exist
-        conn.execute_batch(
-            "
-            CREATE TABLE IF NOT EXISTS sessions (
-                session_id TEXT PRIMARY KEY NOT NULL,
-                user_id TEXT,
-                start_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
-                ip_address TEXT NOT NULL,
-                device_model TEXT,
-                operating_system TEXT,
-                screen_width INT,
-                screen_height INT,
-                user_agent TEXT
-            );
-            CREATE TABLE IF NOT EXISTS events (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                session_id TEXT NOT NULL,
-                event_name TEXT NOT NULL,
-                timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
-                ip_address TEXT NOT NULL,
-                params TEXT,
-                FOREIGN KEY(session_id) REFERENCES sessions(session_id)
-            );
-
-            CREATE INDEX IF NOT EXISTS user_idx ON sessions (user_id);
-            CREATE INDEX IF NOT EXISTS session_idx ON events (session_id);
-            CREATE INDEX IF NOT EXISTS session_event_name_idx ON events (session_id, event_name);
-            ",
-        )
-        .expect("Failed to create tables");
-    });
-}
-
-thread_local! {
-    static DB_CONNECTION: Connection = {
-        initialize_database();
-        let path = env::var("DB_PATH").unwrap_or_else(|_| DB_PATH.to_string());
-        let conn = Connection::open(path).expect("Failed to open database");
-
-        // Enable WAL mode and other PRAGMAs
-        conn.execute_batch(
-            "
-            PRAGMA journal_mode = WAL;
-            PRAGMA synchronous = NORMAL;
-            PRAGMA foreign_keys = ON;
-            ",
-        )
-        .expect("Failed to set PRAGMAs");
-
-        conn
-    };
-}
-
-pub fn with_connection<F, R>(f: F) -> R
-where
-    F: FnOnce(&Connection) -> R,
-{
-    DB_CONNECTION.with(|conn| f(conn))
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+use rusqlite::{params, Connection, InterruptHandle, OpenFlags};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::config::{Config};
+use crate::migrations::{self, MigrationError};
+use crate::partitions::{Partitions};
+use crate::promoted_params::{sync_promoted_indexes};
+use crate::search::{sync_search_triggers};
+
+/// Wait time statistics of one side of the pool.
+#[derive(Default)]
+pub struct WaitStats {
+    acquisitions: AtomicU64,
+    wait_micros_total: AtomicU64,
+    wait_micros_max: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct WaitStatsSnapshot {
+    pub acquisitions: u64,
+    pub wait_micros_total: u64,
+    pub wait_micros_max: u64,
+}
+
+impl WaitStats {
+    fn record(&self, waited: Duration) {
+        let micros = waited.as_micros() as u64;
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros_total.fetch_add(micros, Ordering::Relaxed);
+        self.wait_micros_max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> WaitStatsSnapshot {
+        WaitStatsSnapshot {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            wait_micros_total: self.wait_micros_total.load(Ordering::Relaxed),
+            wait_micros_max: self.wait_micros_max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Sqlite(rusqlite::Error),
+    /// No task slot became free within `DB_QUEUE_TIMEOUT`.
+    Saturated,
+    /// The task did not finish within `DB_TASK_TIMEOUT` and was interrupted.
+    Timeout,
+    Failed(String),
+    Migration(MigrationError),
+    Io(io::Error),
+}
+
+impl DbError {
+    /// Whether the error is caused by load rather than by the request itself.
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, DbError::Saturated | DbError::Timeout)
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlite(e) => write!(f, "{}", e),
+            DbError::Saturated => write!(f, "Database is saturated, retry later"),
+            DbError::Timeout => write!(f, "Database request timed out"),
+            DbError::Failed(e) => write!(f, "Database task failed: {}", e),
+            DbError::Migration(e) => write!(f, "{}", e),
+            DbError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(error: rusqlite::Error) -> DbError {
+        DbError::Sqlite(error)
+    }
+}
+
+impl From<MigrationError> for DbError {
+    fn from(error: MigrationError) -> DbError {
+        DbError::Migration(error)
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(error: io::Error) -> DbError {
+        DbError::Io(error)
+    }
+}
+
+impl From<DbError> for io::Error {
+    fn from(err: DbError) -> io::Error {
+        match err {
+            DbError::Io(e) => e,
+            other => io::Error::other(other.to_string())
+        }
+    }
+}
+
+/// Outcome counters of the blocking database tasks.
+#[derive(Default)]
+pub struct TaskStats {
+    pub saturated: AtomicU64,
+    pub timed_out: AtomicU64,
+}
+
+/// Connection that a running task uses, so it can be interrupted on timeout.
+type InterruptSlot = Arc<Mutex<Option<InterruptHandle>>>;
+
+/// A connection and the partition generation it is attached to.
+struct PooledConnection {
+    conn: Connection,
+    generation: u64,
+}
+
+/// SQLite connections of the server: one serialized writer and a fixed set of
+/// read-only connections, so reads no longer contend with the write lock.
+pub struct DbPool {
+    writer: Mutex<PooledConnection>,
+    readers: Mutex<Vec<PooledConnection>>,
+    reader_available: Condvar,
+    reader_count: usize,
+    tasks: Arc<Semaphore>,
+    max_tasks: usize,
+    queue_timeout: Duration,
+    task_timeout: Duration,
+    partitions: Option<Partitions>,
+    /// Event names whose params are indexed for full-text search
+    search_event_names: Vec<String>,
+    pub writer_stats: WaitStats,
+    pub reader_stats: WaitStats,
+    pub task_stats: TaskStats,
+}
+
+/// Returns a reader to the pool even if the closure using it panics.
+struct ReaderGuard<'a> {
+    pool: &'a DbPool,
+    conn: Option<PooledConnection>,
+}
+
+impl Drop for ReaderGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.readers.lock().push(conn);
+            // Wake everyone, archiving a partition waits for all readers to be returned
+            self.pool.reader_available.notify_all();
+        }
+    }
+}
+
+fn apply_pragmas(conn: &Connection, config: &Config) -> rusqlite::Result<()> {
+    conn.busy_timeout(Duration::from_millis(config.db_busy_timeout))?;
+
+    conn.execute_batch(&format!(
+        "
+        PRAGMA journal_mode = WAL;
+        PRAGMA synchronous = {};
+        PRAGMA foreign_keys = ON;
+        ",
+        config.db_synchronous
+    ))?;
+
+    // Additional PRAGMAs, e.g. `DB_PRAGMAS="cache_size=-64000;mmap_size=268435456"`
+    if let Some(pragmas) = config.db_pragmas.as_ref() {
+        for pragma in pragmas.split(';').map(str::trim).filter(|pragma| !pragma.is_empty()) {
+            conn.execute_batch(&format!("PRAGMA {};", pragma))?;
+        }
+    }
+
+    Ok(())
+}
+
+impl DbPool {
+    /// Opens the writer, applies pending migrations and opens the readers.
+    pub fn open(config: &Config) -> Result<DbPool, DbError> {
+        let mut writer = Connection::open(&config.db_path)?;
+
+        // Only takes effect on new databases; existing ones need a one-time VACUUM
+        writer.execute_batch("PRAGMA auto_vacuum = INCREMENTAL;")?;
+        apply_pragmas(&writer, config)?;
+
+        // Apply pending schema migrations, refuse to start on an incompatible database
+        migrations::migrate_on_startup(&mut writer, config.auto_migrate)?;
+
+        let partitions = Partitions::new(config)?;
+        let mut writer = PooledConnection { conn: writer, generation: 0 };
+
+        if let Some(partitions) = partitions.as_ref() {
+            partitions.ensure_current()?;
+            writer.generation = partitions.sync(&writer.conn, true)?;
+            sync_promoted_indexes(&writer.conn)?;
+        }
+        sync_search_triggers(&writer.conn, &config.search_event_names)?;
+
+        let reader_count = config.db_readers.max(1);
+        let mut readers = Vec::with_capacity(reader_count);
+
+        for _ in 0..reader_count {
+            let reader = Connection::open_with_flags(
+                &config.db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+            )?;
+            apply_pragmas(&reader, config)?;
+            reader.execute_batch("PRAGMA query_only = ON;")?;
+
+            let mut reader = PooledConnection { conn: reader, generation: 0 };
+            if let Some(partitions) = partitions.as_ref() {
+                reader.generation = partitions.sync(&reader.conn, false)?;
+            }
+            readers.push(reader);
+        }
+
+        let max_tasks = config.db_max_tasks.max(1);
+
+        Ok(DbPool {
+            writer: Mutex::new(writer),
+            readers: Mutex::new(readers),
+            reader_available: Condvar::new(),
+            reader_count,
+            tasks: Arc::new(Semaphore::new(max_tasks)),
+            max_tasks,
+            queue_timeout: Duration::from_millis(config.db_queue_timeout),
+            task_timeout: Duration::from_millis(config.db_task_timeout),
+            partitions,
+            search_event_names: config.search_event_names.clone(),
+            writer_stats: WaitStats::default(),
+            reader_stats: WaitStats::default(),
+            task_stats: TaskStats::default(),
+        })
+    }
+
+    /// Runs `f` on the writer connection off the async executor. Writers are serialized.
+    pub async fn write<F, R>(self: &Arc<Self>, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run(move |pool, slot| pool.write_blocking(slot, |conn| f(conn).map_err(DbError::from))).await
+    }
+
+    /// Runs `f` on one of the read-only connections off the async executor.
+    pub async fn read<F, R>(self: &Arc<Self>, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run(move |pool, slot| pool.read_blocking(slot, |conn| f(conn).map_err(DbError::from))).await
+    }
+
+    /// Like `write`, but without `DB_TASK_TIMEOUT`, for maintenance such as building indexes.
+    pub async fn write_unbounded<F, R>(self: &Arc<Self>, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run_with_timeout(move |pool, slot| pool.write_blocking(slot, |conn| f(conn).map_err(DbError::from)), None).await
+    }
+
+    /// Runs `f` on the blocking thread pool, bounded by `DB_MAX_TASKS` concurrent tasks.
+    ///
+    /// Fails fast with `Saturated` if no slot frees up within `DB_QUEUE_TIMEOUT` and
+    /// interrupts statements still running after `DB_TASK_TIMEOUT`. A timed out task
+    /// keeps its slot until SQLite has given up the connection.
+    async fn run<F, R>(self: &Arc<Self>, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&DbPool, &InterruptSlot) -> Result<R, DbError> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run_with_timeout(f, Some(self.task_timeout)).await
+    }
+
+    async fn run_with_timeout<F, R>(self: &Arc<Self>, f: F, task_timeout: Option<Duration>) -> Result<R, DbError>
+    where
+        F: FnOnce(&DbPool, &InterruptSlot) -> Result<R, DbError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let permit = match timeout(self.queue_timeout, Arc::clone(&self.tasks).acquire_owned()).await {
+            Ok(Ok(permit)) => permit,
+            _ => {
+                self.task_stats.saturated.fetch_add(1, Ordering::Relaxed);
+                return Err(DbError::Saturated);
+            }
+        };
+
+        let pool = Arc::clone(self);
+        let slot = InterruptSlot::default();
+        let task_slot = Arc::clone(&slot);
+
+        let task = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f(&pool, &task_slot)
+        });
+
+        let Some(task_timeout) = task_timeout else {
+            return task.await.unwrap_or_else(|e| Err(DbError::Failed(e.to_string())));
+        };
+
+        match timeout(task_timeout, task).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(DbError::Failed(e.to_string())),
+            Err(_) => {
+                if let Some(handle) = slot.lock().take() {
+                    handle.interrupt();
+                }
+                self.task_stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                Err(DbError::Timeout)
+            }
+        }
+    }
+
+    /// Re-attaches partitions if they changed since the connection was last used.
+    fn sync_partitions(&self, pooled: &mut PooledConnection, writer: bool) -> rusqlite::Result<()> {
+        if let Some(partitions) = self.partitions.as_ref() {
+            if writer {
+                partitions.ensure_current()?;
+            }
+            if pooled.generation != partitions.generation() {
+                pooled.generation = partitions.sync(&pooled.conn, writer)?;
+
+                // New partitions get the indexes of promoted params and the search triggers as well
+                if writer {
+                    sync_promoted_indexes(&pooled.conn)?;
+                    sync_search_triggers(&pooled.conn, &self.search_event_names)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocking<F, R>(&self, slot: &InterruptSlot, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&mut Connection) -> Result<R, DbError>,
+    {
+        let started = Instant::now();
+        let mut pooled = self.writer.lock();
+        self.writer_stats.record(started.elapsed());
+
+        self.sync_partitions(&mut pooled, true)?;
+
+        *slot.lock() = Some(pooled.conn.get_interrupt_handle());
+        let result = f(&mut pooled.conn);
+        slot.lock().take();
+
+        result
+    }
+
+    fn read_blocking<F, R>(&self, slot: &InterruptSlot, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&Connection) -> Result<R, DbError>,
+    {
+        let started = Instant::now();
+
+        let conn = {
+            let mut readers = self.readers.lock();
+            loop {
+                if let Some(conn) = readers.pop() {
+                    break conn;
+                }
+                self.reader_available.wait(&mut readers);
+            }
+        };
+
+        self.reader_stats.record(started.elapsed());
+
+        let mut guard = ReaderGuard { pool: self, conn: Some(conn) };
+        let pooled = guard.conn.as_mut().unwrap();
+
+        self.sync_partitions(pooled, false)?;
+
+        *slot.lock() = Some(pooled.conn.get_interrupt_handle());
+        let result = f(&pooled.conn);
+        slot.lock().take();
+
+        result
+    }
+
+    /// Detaches a partition from every connection and moves it into the archive directory.
+    pub async fn archive_partition(self: &Arc<Self>, key: String) -> Result<PathBuf, DbError> {
+        self.run(move |pool, _| pool.archive_partition_blocking(&key)).await
+    }
+
+    fn archive_partition_blocking(&self, key: &str) -> Result<PathBuf, DbError> {
+        let partitions = self.partitions.as_ref().ok_or_else(|| DbError::Failed("Partitioning is disabled".to_string()))?;
+
+        let mut writer = self.writer.lock();
+        partitions.checkpoint(&writer.conn, key)?;
+
+        let partition = partitions.detach(key)
+            .ok_or_else(|| DbError::Failed(format!("Partition {} cannot be archived", key)))?;
+
+        writer.generation = partitions.sync(&writer.conn, true)?;
+
+        // Idle readers still hold the file open, wait until all are returned
+        let mut readers = self.readers.lock();
+        while readers.len() < self.reader_count {
+            self.reader_available.wait(&mut readers);
+        }
+        for reader in readers.iter_mut() {
+            reader.generation = partitions.sync(&reader.conn, false)?;
+        }
+
+        let archived = partitions.archive(&partition)?;
+
+        // The search index lives in `main`, its entries would outlive the events
+        let (start, end) = partition.id_range();
+        let removed = writer.conn.execute(
+            "DELETE FROM main.event_search WHERE rowid >= ?1 AND rowid < ?2",
+            params![start, end],
+        )?;
+        if removed > 0 {
+            log::info!("Removed {} search index entries of the archived partition", removed);
+        }
+
+        Ok(archived)
+    }
+
+    /// Tables `DELETE` statements on events have to run against, legacy rows in `main` first.
+    pub fn event_tables(&self) -> &'static [&'static str] {
+        match self.partitions {
+            Some(_) => &["main.events", "events"],
+            None => &["events"]
+        }
+    }
+
+    /// Fails if partitions that have not been archived are too old to be attached. Work
+    /// that deletes rows based on what the `events` view holds must not run then.
+    pub fn require_all_partitions_attached(&self, task: &str) -> Result<(), DbError> {
+        let unattached = self.partitions.as_ref().map(Partitions::unattached_keys).unwrap_or_default();
+
+        if unattached.is_empty() {
+            return Ok(());
+        }
+
+        Err(DbError::Failed(format!(
+            "{} skipped, partitions {} are not attached: archive them or raise DB_PARTITIONS_ATTACHED",
+            task,
+            unattached.join(", ")
+        )))
+    }
+
+    pub fn partitions(&self) -> Option<&Partitions> {
+        self.partitions.as_ref()
+    }
+
+    /// Verifies that both the writer and a reader can still execute statements.
+    pub async fn health_check(self: &Arc<Self>) -> Result<(), DbError> {
+        self.write(|conn| conn.query_row("SELECT 1", [], |_| Ok(()))).await?;
+        self.read(|conn| conn.query_row("SELECT 1", [], |_| Ok(()))).await
+    }
+
+    pub fn max_tasks(&self) -> usize {
+        self.max_tasks
+    }
+
+    pub fn running_tasks(&self) -> usize {
+        self.max_tasks - self.tasks.available_permits()
+    }
+
+    pub fn reader_count(&self) -> usize {
+        self.reader_count
+    }
+
+    pub fn idle_readers(&self) -> usize {
+        self.readers.lock().len()
+    }
 }
@@ -0,0 +1,751 @@
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::Arc;
+
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, web::Bytes, Error, HttpRequest, HttpResponse};
+use arrow_array::RecordBatch;
+use arrow_schema::{ArrowError, SchemaRef};
+use parking_lot::Mutex;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use rusqlite::{params_from_iter, types::Value, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::db_pool::{DbError, DbPool};
+use crate::parquet_archive::{
+    event_batch, event_schema, parquet_error, session_batch, session_schema,
+    EventRow, SessionRow, EVENT_COLUMNS, SESSION_COLUMNS,
+};
+use crate::promoted_params::{parse_filters, sql_literal, ParamFilter};
+use crate::route_handlers::{database_error, sqlite_required, ApiResponse};
+
+/// Encoded chunks buffered ahead of a slow client.
+const CHANNEL_CAPACITY: usize = 4;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<String>,
+    /// Inclusive lower bound in milliseconds since the epoch
+    from: Option<i64>,
+    /// Exclusive upper bound in milliseconds since the epoch
+    to: Option<i64>,
+    /// Comma-separated event names
+    event_name: Option<String>,
+    user_id: Option<String>,
+    /// `param.<path>`, `param_min.<path>` and `param_max.<path>` filters
+    #[serde(skip)]
+    params: Vec<ParamFilter>,
+}
+
+impl ExportQuery {
+    fn event_names(&self) -> Vec<String> {
+        self.event_name
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string())
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl ExportFormat {
+    fn parse(format: Option<&str>) -> Option<ExportFormat> {
+        match format.unwrap_or("ndjson") {
+            "csv" => Some(ExportFormat::Csv),
+            "ndjson" | "jsonl" => Some(ExportFormat::Ndjson),
+            "parquet" => Some(ExportFormat::Parquet),
+            _ => None
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// A table that can be exported page by page in the order of a two-column key.
+trait ExportTable: Sized + Send + 'static {
+    const NAME: &'static str;
+    const CSV_HEADER: &'static str;
+
+    /// Builds the page query. Its first two parameters are the cursor, the last one the page size.
+    fn select(query: &ExportQuery, params: &mut Vec<Value>) -> String;
+    fn from_row(row: &Row) -> rusqlite::Result<Self>;
+    fn cursor(&self) -> (i64, i64);
+    fn write_csv(&self, out: &mut Vec<u8>);
+    fn write_json(&self, out: &mut Vec<u8>) -> serde_json::Result<()>;
+    fn schema() -> SchemaRef;
+    fn batch(rows: &[Self]) -> Result<RecordBatch, ArrowError>;
+}
+
+#[derive(Serialize)]
+pub struct EventJson<'a> {
+    id: i64,
+    session_id: &'a str,
+    event_name: &'a str,
+    timestamp: i64,
+    ip_address: &'a str,
+    params: Option<serde_json::Value>,
+    seq: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SessionJson<'a> {
+    session_id: &'a str,
+    user_id: Option<&'a str>,
+    start_date: i64,
+    ip_address: &'a str,
+    device_model: Option<&'a str>,
+    operating_system: Option<&'a str>,
+    screen_width: Option<i64>,
+    screen_height: Option<i64>,
+    user_agent: Option<&'a str>,
+    consent_analytics: Option<bool>,
+    consent_crash_reporting: Option<bool>,
+    consent_personalized: Option<bool>,
+    consent_updated_at: Option<i64>,
+}
+
+impl EventRow {
+    /// The row as exported to JSON, with the params as an object.
+    pub fn json(&self) -> EventJson<'_> {
+        EventJson {
+            id: self.id,
+            session_id: &self.session_id,
+            event_name: &self.event_name,
+            timestamp: self.timestamp,
+            ip_address: &self.ip_address,
+            params: self.params.as_deref().and_then(|params| serde_json::from_str(params).ok()),
+            seq: self.seq,
+        }
+    }
+}
+
+impl SessionRow {
+    pub fn json(&self) -> SessionJson<'_> {
+        SessionJson {
+            session_id: &self.session_id,
+            user_id: self.user_id.as_deref(),
+            start_date: self.start_date,
+            ip_address: &self.ip_address,
+            device_model: self.device_model.as_deref(),
+            operating_system: self.operating_system.as_deref(),
+            screen_width: self.screen_width,
+            screen_height: self.screen_height,
+            user_agent: self.user_agent.as_deref(),
+            consent_analytics: self.consent_analytics,
+            consent_crash_reporting: self.consent_crash_reporting,
+            consent_personalized: self.consent_personalized,
+            consent_updated_at: self.consent_updated_at,
+        }
+    }
+}
+
+/// Appends `?, ?, ...` placeholders for `values` and binds them.
+fn placeholders(values: Vec<String>, params: &mut Vec<Value>) -> String {
+    let list = vec!["?"; values.len()].join(", ");
+    params.extend(values.into_iter().map(Value::Text));
+    list
+}
+
+/// Condition on the name and params of an event, if the query filters on either.
+///
+/// With param filters, every event name gets its own branch with the name inlined as
+/// a literal, which lets SQLite use the partial indexes of promoted params.
+fn event_condition(query: &ExportQuery, params: &mut Vec<Value>) -> Option<String> {
+    let event_names = query.event_names();
+
+    if query.params.is_empty() {
+        return (!event_names.is_empty())
+            .then(|| format!("event_name IN ({})", placeholders(event_names, params)));
+    }
+
+    let mut filter = |prefix: Option<String>| {
+        let conditions: Vec<String> = prefix.into_iter()
+            .chain(query.params.iter().map(|param| {
+                params.push(param.value.clone());
+                param.condition()
+            }))
+            .collect();
+        format!("({})", conditions.join(" AND "))
+    };
+
+    if event_names.is_empty() {
+        return Some(filter(None));
+    }
+
+    let branches: Vec<String> = event_names.iter()
+        .map(|name| filter(Some(format!("event_name = {}", sql_literal(name)))))
+        .collect();
+
+    Some(format!("({})", branches.join(" OR ")))
+}
+
+impl ExportTable for EventRow {
+    const NAME: &'static str = "events";
+    const CSV_HEADER: &'static str = "id,session_id,event_name,timestamp,ip_address,params,seq\n";
+
+    fn select(query: &ExportQuery, params: &mut Vec<Value>) -> String {
+        let mut conditions = vec!["(timestamp, id) > (?, ?)".to_string()];
+
+        if let Some(from) = query.from {
+            conditions.push("timestamp >= ?".to_string());
+            params.push(Value::Integer(from));
+        }
+        if let Some(to) = query.to {
+            conditions.push("timestamp < ?".to_string());
+            params.push(Value::Integer(to));
+        }
+
+        if let Some(condition) = event_condition(query, params) {
+            conditions.push(condition);
+        }
+        if let Some(user_id) = query.user_id.as_ref() {
+            conditions.push("session_id IN (SELECT session_id FROM sessions WHERE user_id = ?)".to_string());
+            params.push(Value::Text(user_id.clone()));
+        }
+
+        format!(
+            "SELECT {} FROM events WHERE {} ORDER BY timestamp, id LIMIT ?",
+            EVENT_COLUMNS,
+            conditions.join(" AND ")
+        )
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        EventRow::from_row(row)
+    }
+
+    fn cursor(&self) -> (i64, i64) {
+        (self.timestamp, self.id)
+    }
+
+    fn write_csv(&self, out: &mut Vec<u8>) {
+        csv_field(out, self.id);
+        csv_field(out, &self.session_id);
+        csv_field(out, &self.event_name);
+        csv_field(out, self.timestamp);
+        csv_field(out, &self.ip_address);
+        csv_optional(out, self.params.as_ref());
+        csv_optional(out, self.seq.as_ref());
+        csv_end(out);
+    }
+
+    fn write_json(&self, out: &mut Vec<u8>) -> serde_json::Result<()> {
+        serde_json::to_writer(out, &self.json())
+    }
+
+    fn schema() -> SchemaRef {
+        event_schema()
+    }
+
+    fn batch(rows: &[Self]) -> Result<RecordBatch, ArrowError> {
+        event_batch(rows)
+    }
+}
+
+impl ExportTable for SessionRow {
+    const NAME: &'static str = "sessions";
+    const CSV_HEADER: &'static str = "session_id,user_id,start_date,ip_address,device_model,operating_system,screen_width,screen_height,user_agent,\
+        consent_analytics,consent_crash_reporting,consent_personalized,consent_updated_at\n";
+
+    fn select(query: &ExportQuery, params: &mut Vec<Value>) -> String {
+        let mut conditions = vec!["(start_date, rowid) > (?, ?)".to_string()];
+
+        if let Some(from) = query.from {
+            conditions.push("start_date >= ?".to_string());
+            params.push(Value::Integer(from));
+        }
+        if let Some(to) = query.to {
+            conditions.push("start_date < ?".to_string());
+            params.push(Value::Integer(to));
+        }
+
+        // Sessions match an event filter if they have at least one matching event
+        if let Some(condition) = event_condition(query, params) {
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM events WHERE events.session_id = sessions.session_id AND {})",
+                condition
+            ));
+        }
+        if let Some(user_id) = query.user_id.as_ref() {
+            conditions.push("user_id = ?".to_string());
+            params.push(Value::Text(user_id.clone()));
+        }
+
+        format!(
+            "SELECT {} FROM sessions WHERE {} ORDER BY start_date, rowid LIMIT ?",
+            SESSION_COLUMNS,
+            conditions.join(" AND ")
+        )
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        SessionRow::from_row(row)
+    }
+
+    fn cursor(&self) -> (i64, i64) {
+        (self.start_date, self.rowid)
+    }
+
+    fn write_csv(&self, out: &mut Vec<u8>) {
+        csv_field(out, &self.session_id);
+        csv_optional(out, self.user_id.as_ref());
+        csv_field(out, self.start_date);
+        csv_field(out, &self.ip_address);
+        csv_optional(out, self.device_model.as_ref());
+        csv_optional(out, self.operating_system.as_ref());
+        csv_optional(out, self.screen_width.as_ref());
+        csv_optional(out, self.screen_height.as_ref());
+        csv_optional(out, self.user_agent.as_ref());
+        csv_optional(out, self.consent_analytics.as_ref());
+        csv_optional(out, self.consent_crash_reporting.as_ref());
+        csv_optional(out, self.consent_personalized.as_ref());
+        csv_optional(out, self.consent_updated_at.as_ref());
+        csv_end(out);
+    }
+
+    fn write_json(&self, out: &mut Vec<u8>) -> serde_json::Result<()> {
+        serde_json::to_writer(out, &self.json())
+    }
+
+    fn schema() -> SchemaRef {
+        session_schema()
+    }
+
+    fn batch(rows: &[Self]) -> Result<RecordBatch, ArrowError> {
+        session_batch(rows)
+    }
+}
+
+/// Writes a field followed by a separator, quoted as described in RFC 4180 if needed.
+fn csv_field(out: &mut Vec<u8>, value: impl Display) {
+    let value = value.to_string();
+    if value.contains([',', '"', '\n', '\r']) {
+        out.push(b'"');
+        out.extend_from_slice(value.replace('"', "\"\"").as_bytes());
+        out.push(b'"');
+    } else {
+        out.extend_from_slice(value.as_bytes());
+    }
+    out.push(b',');
+}
+
+/// Writes an empty field for `None`.
+fn csv_optional(out: &mut Vec<u8>, value: Option<impl Display>) {
+    match value {
+        Some(value) => csv_field(out, value),
+        None => out.push(b',')
+    }
+}
+
+/// Replaces the separator after the last field with a line break.
+fn csv_end(out: &mut Vec<u8>) {
+    out.pop();
+    out.push(b'\n');
+}
+
+/// A `Write` target the Parquet writer flushes row groups into, drained after every page.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    Csv,
+    Ndjson,
+    Parquet(Box<ArrowWriter<SharedBuffer>>, SharedBuffer),
+}
+
+impl Encoder {
+    fn new<T: ExportTable>(format: ExportFormat) -> Result<Encoder, DbError> {
+        match format {
+            ExportFormat::Csv => Ok(Encoder::Csv),
+            ExportFormat::Ndjson => Ok(Encoder::Ndjson),
+            ExportFormat::Parquet => {
+                let buffer = SharedBuffer::default();
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .build();
+                let writer = ArrowWriter::try_new(buffer.clone(), T::schema(), Some(properties))
+                    .map_err(parquet_error)?;
+
+                Ok(Encoder::Parquet(Box::new(writer), buffer))
+            }
+        }
+    }
+
+    fn header<T: ExportTable>(&self) -> Vec<u8> {
+        match self {
+            Encoder::Csv => T::CSV_HEADER.as_bytes().to_vec(),
+            Encoder::Ndjson => Vec::new(),
+            Encoder::Parquet(_, buffer) => buffer.take(),
+        }
+    }
+
+    /// Encodes one page. Each Parquet page becomes its own row group.
+    fn encode<T: ExportTable>(&mut self, rows: &[T]) -> Result<Vec<u8>, DbError> {
+        match self {
+            Encoder::Csv => {
+                let mut out = Vec::new();
+                for row in rows {
+                    row.write_csv(&mut out);
+                }
+                Ok(out)
+            },
+            Encoder::Ndjson => {
+                let mut out = Vec::new();
+                for row in rows {
+                    row.write_json(&mut out).map_err(|e| DbError::Failed(e.to_string()))?;
+                    out.push(b'\n');
+                }
+                Ok(out)
+            },
+            Encoder::Parquet(writer, buffer) => {
+                let batch = T::batch(rows).map_err(|e| DbError::Failed(e.to_string()))?;
+                writer.write(&batch).map_err(parquet_error)?;
+                writer.flush().map_err(parquet_error)?;
+                Ok(buffer.take())
+            }
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, DbError> {
+        match self {
+            Encoder::Csv | Encoder::Ndjson => Ok(Vec::new()),
+            Encoder::Parquet(writer, buffer) => {
+                writer.close().map_err(parquet_error)?;
+                Ok(buffer.take())
+            }
+        }
+    }
+}
+
+async fn read_page<T: ExportTable>(
+    db: &Arc<DbPool>,
+    sql: Arc<String>,
+    filter: Arc<Vec<Value>>,
+    cursor: (i64, i64),
+    page_size: usize,
+) -> Result<Vec<T>, DbError> {
+    db.read(move |conn| {
+        let mut params = vec![Value::Integer(cursor.0), Value::Integer(cursor.1)];
+        params.extend(filter.iter().cloned());
+        params.push(Value::Integer(page_size as i64));
+
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let rows = stmt.query_map(params_from_iter(params), T::from_row)?;
+        rows.collect::<rusqlite::Result<Vec<T>>>()
+    }).await
+}
+
+async fn encode_page<T: ExportTable>(mut encoder: Encoder, rows: Vec<T>) -> Result<(Encoder, Vec<u8>), DbError> {
+    tokio::task::spawn_blocking(move || {
+        let bytes = encoder.encode(&rows)?;
+        Ok((encoder, bytes))
+    }).await.map_err(|e| DbError::Failed(e.to_string()))?
+}
+
+type Chunk = Result<Bytes, Error>;
+
+/// Reads, encodes and sends the remaining pages. Stops early once the client disconnects.
+async fn produce<T: ExportTable>(
+    db: Arc<DbPool>,
+    sql: Arc<String>,
+    filter: Arc<Vec<Value>>,
+    page_size: usize,
+    mut encoder: Encoder,
+    mut rows: Vec<T>,
+    tx: &mpsc::Sender<Chunk>,
+) -> Result<(), DbError> {
+    let header = encoder.header::<T>();
+    if !header.is_empty() && tx.send(Ok(Bytes::from(header))).await.is_err() {
+        return Ok(());
+    }
+
+    while let Some(last) = rows.last() {
+        let cursor = last.cursor();
+        let complete = rows.len() < page_size;
+
+        let (next_encoder, bytes) = encode_page(encoder, rows).await?;
+        encoder = next_encoder;
+
+        if !bytes.is_empty() && tx.send(Ok(Bytes::from(bytes))).await.is_err() {
+            return Ok(());
+        }
+
+        if complete {
+            break;
+        }
+
+        rows = read_page(&db, sql.clone(), filter.clone(), cursor, page_size).await?;
+    }
+
+    let trailer = tokio::task::spawn_blocking(move || encoder.finish())
+        .await
+        .map_err(|e| DbError::Failed(e.to_string()))??;
+
+    if !trailer.is_empty() {
+        let _ = tx.send(Ok(Bytes::from(trailer))).await;
+    }
+
+    Ok(())
+}
+
+/// Streams all rows matching the query in chunks, so exports never buffer the whole result.
+async fn export<T: ExportTable>(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, Error> {
+    let mut query = query.into_inner();
+
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let Some(format) = ExportFormat::parse(query.format.as_deref()) else {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: "Unsupported format, use csv, ndjson or parquet".to_string()
+        }));
+    };
+
+    query.params = match parse_filters(req.query_string()) {
+        Ok(params) => params,
+        Err(message) => return Ok(HttpResponse::BadRequest().json(ApiResponse { success: false, message }))
+    };
+
+    let mut filter = Vec::new();
+    let sql = Arc::new(T::select(&query, &mut filter));
+    let filter = Arc::new(filter);
+    let page_size = data.config.export_page_size.max(1);
+
+    // The first page is read up front, so an overloaded database still gets a proper status code
+    let rows = match read_page::<T>(db, sql.clone(), filter.clone(), (i64::MIN, i64::MIN), page_size).await {
+        Ok(rows) => rows,
+        Err(e) => return Ok(database_error("Export failed", e))
+    };
+
+    let encoder = match Encoder::new::<T>(format) {
+        Ok(encoder) => encoder,
+        Err(e) => return Ok(database_error("Export failed", e))
+    };
+
+    let (tx, rx) = mpsc::channel::<Chunk>(CHANNEL_CAPACITY);
+    let db = db.clone();
+
+    actix_web::rt::spawn(async move {
+        if let Err(e) = produce(db, sql, filter, page_size, encoder, rows, &tx).await {
+            // Aborts the response, so clients never mistake a truncated export for a complete one
+            log::error!("Export of {} failed: {}", T::NAME, e);
+            let _ = tx.send(Err(actix_web::error::ErrorInternalServerError(e.to_string()))).await;
+        }
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("{}.{}", T::NAME, format.extension()))],
+        })
+        .streaming(stream))
+}
+
+/// Exports events, e.g. `GET /export/events?format=csv&from=1727740800000&event_name=run_start,run_end`.
+/// Params can be filtered with `param.class=mage`, `param_min.floor=3` and `param_max.floor=9`.
+pub async fn export_events(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Export>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, Error> {
+    export::<EventRow>(req, data, query).await
+}
+
+/// Exports sessions, filtered by start date, user and the events they contain.
+pub async fn export_sessions(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Export>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, Error> {
+    export::<SessionRow>(req, data, query).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test as web_test, App};
+    use rusqlite::params;
+
+    use crate::app_state::{TestDir};
+
+    use super::*;
+
+    fn query(event_name: Option<&str>, filters: &str) -> ExportQuery {
+        ExportQuery {
+            format: None,
+            from: None,
+            to: None,
+            event_name: event_name.map(str::to_string),
+            user_id: None,
+            params: parse_filters(filters).unwrap(),
+        }
+    }
+
+    /// Events of `s1` with three sharing a timestamp, so pages of two split them, and one
+    /// event of `s2`.
+    async fn project(dir: &TestDir) -> web::Data<AppState> {
+        let data = AppState::on_sqlite(dir, &[("SECRET_KEY", "secret"), ("EXPORT_PAGE_SIZE", "2")]);
+
+        data.db.as_ref().unwrap().write(|conn| {
+            conn.execute_batch(
+                "INSERT INTO sessions (session_id, user_id, start_date, ip_address) VALUES ('s1', 'u1', 1000, ''), ('s2', 'u2', 2000, '');"
+            )?;
+            for (session_id, event_name, timestamp, params) in [
+                ("s1", "run_start", 1000, r#"{"class": "mage", "floor": 1}"#),
+                ("s1", "run_start", 2000, r#"{"class": "mage", "floor": 3}"#),
+                ("s1", "run_end", 2000, r#"{"class": "mage", "floor": 3}"#),
+                ("s1", "run_start", 2000, r#"{"class": "rogue", "floor": 5}"#),
+                ("s1", "run_start", 3000, r#"{"class": "mage", "floor": 7}"#),
+                ("s2", "run_start", 3000, r#"{"note": "said \"hi\", left"}"#),
+            ] {
+                conn.execute(
+                    "INSERT INTO events (session_id, event_name, timestamp, ip_address, params) VALUES (?1, ?2, ?3, '', ?4)",
+                    params![session_id, event_name, timestamp, params],
+                )?;
+            }
+            Ok(())
+        }).await.unwrap();
+
+        data
+    }
+
+    async fn export_csv(data: &web::Data<AppState>, uri: &str) -> (StatusCode, Vec<String>) {
+        let app = web_test::init_service(App::new().app_data(data.clone())
+            .route("/export/events", web::get().to(export_events))
+            .route("/export/sessions", web::get().to(export_sessions))).await;
+
+        let request = web_test::TestRequest::get().uri(uri).insert_header(("X-RLA-KEY", "secret")).to_request();
+        let response = web_test::call_service(&app, request).await;
+        let status = response.status();
+        let body = web_test::read_body(response).await;
+
+        (status, String::from_utf8(body.to_vec()).unwrap().lines().skip(1).map(str::to_string).collect())
+    }
+
+    fn ids(lines: &[String]) -> Vec<String> {
+        lines.iter().map(|line| line.split(',').next().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        let mut out = Vec::new();
+        csv_field(&mut out, "plain");
+        csv_field(&mut out, "a,b");
+        csv_field(&mut out, "say \"hi\"");
+        csv_field(&mut out, "two\nlines");
+        csv_optional(&mut out, None::<i64>);
+        csv_optional(&mut out, Some(7));
+        csv_end(&mut out);
+
+        assert_eq!(String::from_utf8(out).unwrap(), "plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",,7\n");
+    }
+
+    #[test]
+    fn inlines_event_names_next_to_param_filters() {
+        let mut params = Vec::new();
+        assert_eq!(event_condition(&query(None, ""), &mut params), None);
+
+        let condition = event_condition(&query(Some("run_start, run_end"), ""), &mut params);
+        assert_eq!(condition.as_deref(), Some("event_name IN (?, ?)"));
+        assert_eq!(params, [Value::Text("run_start".to_string()), Value::Text("run_end".to_string())]);
+
+        let mut params = Vec::new();
+        let condition = event_condition(&query(Some("run_start,it's"), "param.class=mage&param_max.floor=3"), &mut params);
+        assert_eq!(condition.as_deref(), Some(
+            "((event_name = 'run_start' AND json_extract(params, '$.class') = ? AND json_extract(params, '$.floor') <= ?) \
+            OR (event_name = 'it''s' AND json_extract(params, '$.class') = ? AND json_extract(params, '$.floor') <= ?))"
+        ));
+        assert_eq!(params, [
+            Value::Text("mage".to_string()), Value::Integer(3),
+            Value::Text("mage".to_string()), Value::Integer(3),
+        ]);
+    }
+
+    #[actix_web::test]
+    async fn pages_through_rows_sharing_a_timestamp() {
+        let dir = TestDir::new("export");
+        let data = project(&dir).await;
+
+        let (status, lines) = export_csv(&data, "/export/events?format=csv").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&lines), ["1", "2", "3", "4", "5", "6"]);
+        assert_eq!(lines[5], r#"6,s2,run_start,3000,,"{""note"": ""said \""hi\"", left""}","#);
+
+        let (_, lines) = export_csv(&data, "/export/sessions?format=csv&user_id=u1").await;
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("s1,u1,1000,"));
+    }
+
+    #[actix_web::test]
+    async fn filters_by_event_name_and_params() {
+        let dir = TestDir::new("export-params");
+        let data = project(&dir).await;
+
+        let (_, lines) = export_csv(&data, "/export/events?format=csv&event_name=run_start&param.class=mage&param_min.floor=2").await;
+        assert_eq!(ids(&lines), ["2", "5"]);
+
+        let (_, lines) = export_csv(&data, "/export/events?format=csv&param_max.floor=3&from=2000&to=3000").await;
+        assert_eq!(ids(&lines), ["2", "3"]);
+
+        let (status, _) = export_csv(&data, "/export/events?format=csv&param.floor%27)--=1").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
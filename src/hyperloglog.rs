@@ -0,0 +1,142 @@
+/// Register index bits, 2048 registers for a standard error of about 2.3%.
+const PRECISION: u32 = 11;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch estimating the number of distinct values added to it.
+///
+/// Sketches are stored in the database, so values are hashed with a fixed function
+/// instead of the standard library hasher, whose output may change between releases.
+#[derive(Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog { registers: vec![0; REGISTERS] }
+    }
+}
+
+/// FNV-1a followed by the SplitMix64 finalizer, which spreads FNV's weak high bits.
+fn hash(value: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+impl HyperLogLog {
+    /// Restores a sketch from `to_bytes`, `None` if the bytes are not a sketch.
+    pub fn from_bytes(bytes: &[u8]) -> Option<HyperLogLog> {
+        (bytes.len() == REGISTERS).then(|| HyperLogLog { registers: bytes.to_vec() })
+    }
+
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    pub fn add(&mut self, value: &str) {
+        let hash = hash(value.as_bytes());
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The sentinel bit caps the rank for hashes whose remaining bits are all zero
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|register| 2f64.powi(-(*register as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Linear counting is more accurate while many registers are still empty
+        let zeros = self.registers.iter().filter(|register| **register == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+
+        estimate.round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch(values: std::ops::Range<u32>) -> HyperLogLog {
+        let mut sketch = HyperLogLog::default();
+        for value in values {
+            sketch.add(&format!("user-{}", value));
+        }
+        sketch
+    }
+
+    /// Relative error of the estimate, allowing three standard errors.
+    fn assert_close(sketch: &HyperLogLog, expected: u64) {
+        let error = (sketch.estimate() as f64 - expected as f64).abs() / expected as f64;
+        assert!(error < 0.07, "estimated {} for {}", sketch.estimate(), expected);
+    }
+
+    #[test]
+    fn estimates_known_cardinalities() {
+        for cardinality in [1_000, 10_000, 100_000, 1_000_000] {
+            assert_close(&sketch(0..cardinality), cardinality as u64);
+        }
+    }
+
+    #[test]
+    fn repeated_values_count_once() {
+        let mut repeated = sketch(0..500);
+        repeated.merge(&sketch(0..500));
+        for value in 0..500 {
+            repeated.add(&format!("user-{}", value));
+        }
+
+        assert_eq!(repeated.estimate(), sketch(0..500).estimate());
+    }
+
+    #[test]
+    fn merge_estimates_the_union() {
+        let mut merged = sketch(0..6_000);
+        merged.merge(&sketch(4_000..10_000));
+
+        assert_close(&merged, 10_000);
+        assert_eq!(merged.to_bytes(), sketch(0..10_000).to_bytes());
+    }
+
+    #[test]
+    fn restores_from_bytes() {
+        let original = sketch(0..2_000);
+        let restored = HyperLogLog::from_bytes(original.to_bytes()).unwrap();
+
+        assert_eq!(restored.to_bytes(), original.to_bytes());
+        assert_eq!(restored.estimate(), original.estimate());
+        assert!(HyperLogLog::from_bytes(&original.to_bytes()[1..]).is_none());
+        assert!(HyperLogLog::from_bytes(&[]).is_none());
+    }
+
+    #[test]
+    fn counts_small_cardinalities_linearly() {
+        assert_eq!(HyperLogLog::default().estimate(), 0);
+        assert_eq!(sketch(0..1).estimate(), 1);
+        assert_eq!(sketch(0..20).estimate(), 20);
+
+        // Above 2.5 values per register the estimate switches to the raw HyperLogLog
+        // formula, both sides stay accurate
+        let switch = (2.5 * REGISTERS as f64) as u32;
+        assert_close(&sketch(0..switch - 200), (switch - 200) as u64);
+        assert_close(&sketch(0..switch + 200), (switch + 200) as u64);
+    }
+}
@@ -0,0 +1,224 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use chrono::{NaiveDate, Utc};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::{parse_ip_privacy, Config, IpPrivacy};
+use crate::partitions::{Partitions};
+
+/// Stored instead of values that are no IP address, e.g. from a malformed proxy header.
+const UNKNOWN: &str = "unknown";
+
+/// Rows rewritten per transaction by `anonymize-ips`.
+const ANONYMIZE_BATCH_SIZE: i64 = 10_000;
+
+fn random_salt() -> [u8; 32] {
+    let mut salt = [0; 32];
+    salt[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+    salt[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+    salt
+}
+
+/// Cuts IPv4 addresses to their /24 and IPv6 addresses to their /48 network.
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => truncate(IpAddr::V4(ip)),
+            None => {
+                let [a, b, c, ..] = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+            }
+        }
+    }
+}
+
+/// Turns the IP address of a request into the value stored with its session or event,
+/// according to `IP_PRIVACY`. Rate limiting keeps using the full address in memory.
+pub struct IpAnonymizer {
+    mode: IpPrivacy,
+    /// Only ever kept in memory and replaced when the day changes, so hashes of
+    /// previous days can neither be reversed nor linked to today's
+    salt: Mutex<(NaiveDate, [u8; 32])>,
+}
+
+impl IpAnonymizer {
+    pub fn new(mode: IpPrivacy) -> IpAnonymizer {
+        IpAnonymizer {
+            mode,
+            salt: Mutex::new((Utc::now().date_naive(), random_salt())),
+        }
+    }
+
+    fn hash(&self, ip: IpAddr) -> String {
+        let today = Utc::now().date_naive();
+        let mut salt = self.salt.lock();
+
+        if salt.0 != today {
+            *salt = (today, random_salt());
+        }
+
+        let digest = Sha256::new()
+            .chain_update(salt.1)
+            .chain_update(ip.to_string())
+            .finalize();
+
+        format!("{:x}", digest)[..32].to_string()
+    }
+
+    pub fn anonymize(&self, ip: &str) -> String {
+        match (self.mode, ip.trim().parse::<IpAddr>()) {
+            (IpPrivacy::Full, _) => ip.to_string(),
+            (IpPrivacy::None, _) => String::new(),
+            (IpPrivacy::Truncated, Ok(ip)) => truncate(ip).to_string(),
+            (IpPrivacy::Hashed, Ok(ip)) => self.hash(ip),
+            (_, Err(_)) => UNKNOWN.to_string()
+        }
+    }
+}
+
+/// Rewrites the `ip_address` column of `table` in batches. Values that are no IP address
+/// have already been anonymized and are left alone, so the tool can run repeatedly.
+fn anonymize_table(conn: &mut Connection, table: &str, anonymizer: &IpAnonymizer) -> rusqlite::Result<usize> {
+    let mut last_rowid = i64::MIN;
+    let mut changed = 0;
+
+    loop {
+        let rows = {
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT rowid, ip_address FROM {} WHERE rowid > ?1 ORDER BY rowid LIMIT ?2",
+                table
+            ))?;
+            let rows = stmt.query_map(params![last_rowid, ANONYMIZE_BATCH_SIZE], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+
+        let Some((rowid, _)) = rows.last() else {
+            return Ok(changed);
+        };
+        last_rowid = *rowid;
+
+        let tx = conn.transaction()?;
+        {
+            let mut update = tx.prepare_cached(&format!("UPDATE {} SET ip_address = ?2 WHERE rowid = ?1", table))?;
+
+            for (rowid, ip) in rows {
+                if ip.trim().parse::<IpAddr>().is_err() {
+                    continue;
+                }
+
+                let anonymized = anonymizer.anonymize(&ip);
+                if anonymized != ip {
+                    update.execute(params![rowid, anonymized])?;
+                    changed += 1;
+                }
+            }
+        }
+        tx.commit()?;
+    }
+}
+
+/// `anonymize-ips [full|truncate|hash|none]` applies a privacy mode, by default
+/// `IP_PRIVACY`, to the sessions and events already stored, including every partition
+/// that has not been archived.
+pub fn run_cli(conn: &mut Connection, config: &Config, args: &[String]) -> std::io::Result<()> {
+    let mode = match args.first() {
+        Some(mode) => parse_ip_privacy(Some(mode))
+            .ok_or_else(|| std::io::Error::other("Usage: anonymize-ips [--project <name>] [full|truncate|hash|none]"))?,
+        None => config.ip_privacy
+    };
+
+    if mode == IpPrivacy::Full {
+        println!("IP_PRIVACY is full, nothing to anonymize");
+        return Ok(());
+    }
+
+    let anonymizer = IpAnonymizer::new(mode);
+
+    for table in ["main.sessions", "main.events"] {
+        let changed = anonymize_table(conn, table, &anonymizer).map_err(std::io::Error::other)?;
+        println!("  {}: {} rows anonymized", table, changed);
+    }
+
+    if let Some(partitions) = Partitions::new(config)? {
+        for (path, table) in partitions.tables() {
+            let mut partition = Connection::open(&path).map_err(std::io::Error::other)?;
+            partition.busy_timeout(std::time::Duration::from_millis(config.db_busy_timeout)).map_err(std::io::Error::other)?;
+
+            let changed = anonymize_table(&mut partition, &table, &anonymizer).map_err(std::io::Error::other)?;
+            println!("  {}: {} rows anonymized", path.display(), changed);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn truncated(ip: &str) -> String {
+        truncate(ip.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn truncates_to_the_network() {
+        assert_eq!(truncated("203.0.113.77"), "203.0.113.0");
+        assert_eq!(truncated("2001:db8:abcd:1234:5678::1"), "2001:db8:abcd::");
+        assert_eq!(truncated("::ffff:203.0.113.77"), "203.0.113.0");
+    }
+
+    #[test]
+    fn rotates_the_hash_salt_daily() {
+        let anonymizer = IpAnonymizer::new(IpPrivacy::Hashed);
+        let today = anonymizer.anonymize("203.0.113.77");
+
+        assert_eq!(anonymizer.anonymize("203.0.113.77"), today);
+        assert_ne!(anonymizer.anonymize("203.0.113.78"), today);
+        assert_eq!(today.len(), 32);
+
+        // A salt left over from yesterday is replaced before hashing
+        let yesterday = Utc::now().date_naive().pred_opt().unwrap();
+        anonymizer.salt.lock().0 = yesterday;
+
+        assert_ne!(anonymizer.anonymize("203.0.113.77"), today);
+        assert_eq!(anonymizer.salt.lock().0, Utc::now().date_naive());
+    }
+
+    fn ip_addresses(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT ip_address FROM sessions ORDER BY rowid").unwrap()
+            .query_map([], |row| row.get::<_, String>(0)).unwrap()
+            .collect::<rusqlite::Result<Vec<String>>>().unwrap()
+    }
+
+    #[test]
+    fn anonymizes_tables_once() {
+        for mode in [IpPrivacy::Truncated, IpPrivacy::Hashed, IpPrivacy::None] {
+            let mut conn = Connection::open_in_memory().unwrap();
+            conn.execute_batch(
+                "CREATE TABLE sessions (session_id TEXT, ip_address TEXT NOT NULL);
+                INSERT INTO sessions VALUES ('a', '203.0.113.77'), ('b', '2001:db8::1'), ('c', 'unknown');"
+            ).unwrap();
+
+            let anonymizer = IpAnonymizer::new(mode);
+            assert_eq!(anonymize_table(&mut conn, "sessions", &anonymizer).unwrap(), 2);
+
+            let first = ip_addresses(&conn);
+
+            assert_eq!(anonymize_table(&mut conn, "sessions", &anonymizer).unwrap(), 0);
+
+            let second = ip_addresses(&conn);
+
+            assert_eq!(first, second);
+            assert_eq!(first[2], "unknown");
+        }
+    }
+}
@@ -1,8 +1,124 @@
+// The test harness replaces `main`, leaving the server modules unreachable.
+#![cfg_attr(test, allow(dead_code))]
+
 mod db_pool;
 mod app_state;
+mod api_keys;
+mod auth;
+mod backup;
+mod body_format;
+mod compression;
 mod config;
+mod consent;
+mod export;
+mod hyperloglog;
+mod ip_privacy;
+mod metrics;
+mod memory_storage;
+mod migrations;
+mod parquet_archive;
+mod partitions;
+mod projects;
+mod promoted_params;
 mod rate_limit;
+mod request_signing;
+mod retention;
+mod rollups;
 mod route_handlers;
+mod search;
+mod sequence_report;
 mod server;
+mod sqlite_storage;
+mod storage;
+mod stream_ingest;
+mod tls;
+mod user_data;
+mod ws_ingest;
+
+use std::env;
+
+use dotenv::dotenv;
+use rusqlite::Connection;
+
+use config::{Config};
+
+/// Config of the project named by a leading `--project <name>`, or of the default
+/// project, and the remaining arguments.
+fn project_config(args: &[String]) -> std::io::Result<(Config, &[String])> {
+    let config = Config::from_env();
+
+    match args.first().map(String::as_str) {
+        Some("--project") => {
+            let name = args.get(1)
+                .ok_or_else(|| std::io::Error::other("Expected a project name after --project"))?;
+            let config = config.project_configs()
+                .into_iter()
+                .find(|config| config.project.as_deref() == Some(name.as_str()))
+                .ok_or_else(|| std::io::Error::other(format!("Unknown project {}", name)))?;
+            Ok((config, &args[2..]))
+        },
+        _ => Ok((config, args))
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("migrate") => {
+            dotenv().ok();
+
+            // Migrates the database of the default project and of every additional one
+            let config = Config::from_env();
+            let mut configs = config.project_configs();
+            configs.insert(0, config);
+
+            for config in configs {
+                if let Some(project) = config.project.as_deref() {
+                    println!("Project {} ({})", project, config.db_path);
+                }
+
+                let mut conn = Connection::open(&config.db_path)
+                    .map_err(std::io::Error::other)?;
+
+                migrations::run_cli(&mut conn, args.get(2).map(String::as_str))?;
+            }
+            Ok(())
+        },
+        Some("keys") => {
+            dotenv().ok();
+
+            let (config, args) = project_config(&args[2..])?;
+            let mut conn = Connection::open(&config.db_path)
+                .map_err(std::io::Error::other)?;
+
+            migrations::migrate_on_startup(&mut conn, config.auto_migrate)?;
+            api_keys::run_cli(&mut conn, args)
+        },
+        Some("anonymize-ips") => {
+            dotenv().ok();
+
+            let (config, args) = project_config(&args[2..])?;
+            let mut conn = Connection::open(&config.db_path)
+                .map_err(std::io::Error::other)?;
+            conn.busy_timeout(std::time::Duration::from_millis(config.db_busy_timeout))
+                .map_err(std::io::Error::other)?;
+
+            migrations::migrate_on_startup(&mut conn, config.auto_migrate)?;
+            ip_privacy::run_cli(&mut conn, &config, args)
+        },
+        Some("reindex-search") => {
+            dotenv().ok();
+
+            let (config, args) = project_config(&args[2..])?;
+            let mut conn = Connection::open(&config.db_path)
+                .map_err(std::io::Error::other)?;
+            conn.busy_timeout(std::time::Duration::from_millis(config.db_busy_timeout))
+                .map_err(std::io::Error::other)?;
 
-use server::{main};
+            migrations::migrate_on_startup(&mut conn, config.auto_migrate)?;
+            search::run_cli(&mut conn, &config, args)
+        },
+        _ => server::main()
+    }
+}
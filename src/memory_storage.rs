@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use crate::consent::{Consent};
+use crate::db_pool::{DbError};
+use crate::route_handlers::{now, IngestEventRequest};
+use crate::storage::{Event, NewSession, SequenceSummary, SessionInfo, Storage};
+
+struct MemorySession {
+    session: NewSession,
+    start_date: i64,
+    /// Events in the order they arrived
+    events: Vec<Event>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    sessions: Vec<MemorySession>,
+    index: HashMap<String, usize>,
+    last_event_id: i64,
+}
+
+impl MemoryData {
+    fn insert_event(&mut self, event: IngestEventRequest) -> Result<(), String> {
+        let Some(position) = self.index.get(&event.session_id).copied() else {
+            return Err(format!("Unknown session {}", event.session_id));
+        };
+
+        self.last_event_id += 1;
+        self.sessions[position].events.push(Event {
+            id: self.last_event_id,
+            seq: event.seq,
+            event_name: event.event_name,
+            time: now(),
+            data: event.data,
+        });
+
+        Ok(())
+    }
+}
+
+/// Keeps all sessions and events in memory, for tests and ephemeral development servers.
+///
+/// Only what the query endpoints return is kept, client IP addresses are dropped.
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Mutex<MemoryData>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn create_session(&self, session: NewSession) -> Result<(), DbError> {
+        let mut data = self.data.lock();
+
+        if data.index.contains_key(&session.session_id) {
+            return Err(DbError::Failed(format!("Session {} already exists", session.session_id)));
+        }
+
+        let position = data.sessions.len();
+        data.index.insert(session.session_id.clone(), position);
+        data.sessions.push(MemorySession { session, start_date: now(), events: Vec::new() });
+
+        Ok(())
+    }
+
+    async fn session_exists(&self, session_id: String) -> Result<bool, DbError> {
+        Ok(self.data.lock().index.contains_key(&session_id))
+    }
+
+    async fn get_consent(&self, session_id: String) -> Result<Option<Consent>, DbError> {
+        let data = self.data.lock();
+
+        Ok(data.index.get(&session_id).map(|position| data.sessions[*position].session.consent))
+    }
+
+    async fn set_consent(&self, session_id: String, consent: Consent) -> Result<bool, DbError> {
+        let mut data = self.data.lock();
+
+        let Some(position) = data.index.get(&session_id).copied() else {
+            return Ok(false);
+        };
+        data.sessions[position].session.consent = consent;
+
+        Ok(true)
+    }
+
+    async fn insert_event(&self, event: IngestEventRequest, _ip: String) -> Result<(), DbError> {
+        self.data.lock().insert_event(event).map_err(DbError::Failed)
+    }
+
+    async fn insert_events(&self, events: Vec<IngestEventRequest>, _ip: String) -> Result<Vec<Result<(), String>>, DbError> {
+        let mut data = self.data.lock();
+
+        Ok(events.into_iter().map(|event| data.insert_event(event)).collect())
+    }
+
+    async fn get_sessions(&self) -> Result<Vec<SessionInfo>, DbError> {
+        Ok(self.data.lock().sessions.iter().map(|session| SessionInfo {
+            session_id: session.session.session_id.clone(),
+            start_date: session.start_date,
+        }).collect())
+    }
+
+    async fn get_events(&self, session_id: String) -> Result<Vec<Event>, DbError> {
+        let data = self.data.lock();
+
+        let mut events = match data.index.get(&session_id) {
+            Some(position) => data.sessions[*position].events.to_vec(),
+            None => Vec::new()
+        };
+
+        // Like SQLite, events without `seq` come first
+        events.sort_by_key(|event: &Event| (event.seq, event.id));
+        Ok(events)
+    }
+
+    async fn get_event_seqs(&self, session_id: String) -> Result<Vec<i64>, DbError> {
+        let data = self.data.lock();
+
+        Ok(match data.index.get(&session_id) {
+            Some(position) => data.sessions[*position].events.iter().filter_map(|event| event.seq).collect(),
+            None => Vec::new()
+        })
+    }
+
+    async fn get_sequence_summaries(&self) -> Result<Vec<SequenceSummary>, DbError> {
+        let data = self.data.lock();
+        let mut summaries = BTreeMap::new();
+
+        for session in data.sessions.iter() {
+            let mut seqs = session.events.iter().filter_map(|event| event.seq).peekable();
+            let Some(first) = seqs.peek().copied() else {
+                continue;
+            };
+
+            let mut summary = SequenceSummary {
+                session_id: session.session.session_id.clone(),
+                events: 0,
+                first_seq: first,
+                last_seq: first,
+                distinct: 0,
+                out_of_order: 0,
+            };
+            let mut distinct = HashSet::new();
+
+            for seq in seqs {
+                if seq < summary.last_seq {
+                    summary.out_of_order += 1;
+                }
+                summary.events += 1;
+                summary.first_seq = summary.first_seq.min(seq);
+                summary.last_seq = summary.last_seq.max(seq);
+                distinct.insert(seq);
+            }
+
+            summary.distinct = distinct.len() as i64;
+            summaries.insert(summary.session_id.clone(), summary);
+        }
+
+        Ok(summaries.into_values().collect())
+    }
+
+    async fn health_check(&self) -> Result<(), DbError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use actix_web::{web, Error, HttpResponse};
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::config::{ConsentCategory};
+use crate::db_pool::{DbPool, WaitStatsSnapshot};
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn write_wait_stats(out: &mut String, writer: &WaitStatsSnapshot, reader: &WaitStatsSnapshot) {
+    write_metric(
+        out,
+        "rla_db_acquisitions_total",
+        "counter",
+        "Number of times a database connection was acquired.",
+        &[("role=\"writer\"", writer.acquisitions), ("role=\"reader\"", reader.acquisitions)],
+    );
+    write_metric(
+        out,
+        "rla_db_wait_microseconds_total",
+        "counter",
+        "Total time spent waiting for a database connection.",
+        &[("role=\"writer\"", writer.wait_micros_total), ("role=\"reader\"", reader.wait_micros_total)],
+    );
+    write_metric(
+        out,
+        "rla_db_wait_microseconds_max",
+        "gauge",
+        "Longest time spent waiting for a database connection.",
+        &[("role=\"writer\"", writer.wait_micros_max), ("role=\"reader\"", reader.wait_micros_max)],
+    );
+}
+
+/// Connection pool metrics, only available with the SQLite storage backend.
+fn write_db_metrics(out: &mut String, db: &DbPool) {
+    write_wait_stats(out, &db.writer_stats.snapshot(), &db.reader_stats.snapshot());
+    write_metric(
+        out,
+        "rla_db_readers",
+        "gauge",
+        "Number of read-only database connections.",
+        &[("", db.reader_count() as u64)],
+    );
+    write_metric(
+        out,
+        "rla_db_readers_idle",
+        "gauge",
+        "Number of read-only database connections not in use.",
+        &[("", db.idle_readers() as u64)],
+    );
+    if let Some(partitions) = db.partitions() {
+        write_metric(
+            out,
+            "rla_db_partitions_attached",
+            "gauge",
+            "Number of event partitions attached to every connection.",
+            &[("", partitions.attached_count() as u64)],
+        );
+    }
+    write_metric(
+        out,
+        "rla_db_tasks_running",
+        "gauge",
+        "Number of database tasks running on the blocking thread pool.",
+        &[("", db.running_tasks() as u64)],
+    );
+    write_metric(
+        out,
+        "rla_db_tasks_max",
+        "gauge",
+        "Maximum number of concurrent database tasks.",
+        &[("", db.max_tasks() as u64)],
+    );
+    write_metric(
+        out,
+        "rla_db_tasks_rejected_total",
+        "counter",
+        "Number of database tasks answered with 503.",
+        &[
+            ("reason=\"saturated\"", db.task_stats.saturated.load(Ordering::Relaxed)),
+            ("reason=\"timeout\"", db.task_stats.timed_out.load(Ordering::Relaxed)),
+        ],
+    );
+}
+
+/// Ingestion metrics, available with every storage backend.
+fn write_consent_metrics(out: &mut String, data: &AppState) {
+    let samples = ConsentCategory::ALL
+        .map(|category| (format!("category=\"{}\"", category.as_str()), data.consent.dropped(category)));
+
+    write_metric(
+        out,
+        "rla_events_dropped_without_consent_total",
+        "counter",
+        "Number of events dropped because the player did not consent to their category.",
+        &samples.iter().map(|(labels, value)| (labels.as_str(), *value)).collect::<Vec<_>>(),
+    );
+}
+
+/// Exposes server metrics in the Prometheus text format.
+pub async fn get_metrics(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    let mut out = String::new();
+    write_consent_metrics(&mut out, &data);
+
+    if let Some(db) = data.db.as_ref() {
+        write_db_metrics(&mut out, db);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out))
+}
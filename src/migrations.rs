@@ -0,0 +1,258 @@
+use std::fmt;
+
+use chrono::DateTime;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::route_handlers::{now};
+
+/// A numbered schema change embedded into the binary.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// All migrations in the order they are applied. Never edit or reorder an
+/// entry that has been released; add a new one instead.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("migrations/0001_initial.sql") },
+    Migration { version: 2, name: "event_seq", sql: include_str!("migrations/0002_event_seq.sql") },
+    Migration { version: 3, name: "retention_indexes", sql: include_str!("migrations/0003_retention_indexes.sql") },
+    Migration { version: 4, name: "promoted_params", sql: include_str!("migrations/0004_promoted_params.sql") },
+    Migration { version: 5, name: "rollups", sql: include_str!("migrations/0005_rollups.sql") },
+    Migration { version: 6, name: "event_search", sql: include_str!("migrations/0006_event_search.sql") },
+    Migration { version: 7, name: "api_keys", sql: include_str!("migrations/0007_api_keys.sql") },
+    Migration { version: 8, name: "erasures", sql: include_str!("migrations/0008_erasures.sql") },
+    Migration { version: 9, name: "session_consent", sql: include_str!("migrations/0009_session_consent.sql") },
+    Migration { version: 10, name: "erasure_retained_in", sql: include_str!("migrations/0010_erasure_retained_in.sql") },
+    Migration { version: 11, name: "parquet_archive_ranges", sql: include_str!("migrations/0011_parquet_archive_ranges.sql") },
+    Migration { version: 12, name: "rollup_session_start_date", sql: include_str!("migrations/0012_rollup_session_start_date.sql") },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(rusqlite::Error),
+    DatabaseNewerThanBinary { database: i64, binary: i64 },
+    PendingMigrations { current: i64, latest: i64 },
+    UnknownCommand(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Sqlite(e) => write!(f, "Migration failed: {}", e),
+            MigrationError::DatabaseNewerThanBinary { database, binary } => write!(
+                f,
+                "Database schema version {} is newer than the latest version {} known to this binary",
+                database, binary
+            ),
+            MigrationError::PendingMigrations { current, latest } => write!(
+                f,
+                "Database schema version {} is behind version {} and AUTO_MIGRATE is disabled",
+                current, latest
+            ),
+            MigrationError::UnknownCommand(command) => write!(
+                f,
+                "Unknown migrate command '{}'. Use status, dry-run or apply",
+                command
+            ),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(error: rusqlite::Error) -> MigrationError {
+        MigrationError::Sqlite(error)
+    }
+}
+
+impl From<MigrationError> for std::io::Error {
+    fn from(err: MigrationError) -> std::io::Error {
+        std::io::Error::other(err.to_string())
+    }
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![table],
+        |row| row.get::<_, i64>(0)
+    )
+    .map(|count| count > 0)
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get::<_, i64>(0)
+    )
+    .map(|count| count > 0)
+}
+
+/// Infers the version of databases created before migrations were tracked.
+fn detect_untracked_version(conn: &Connection) -> rusqlite::Result<i64> {
+    if !table_exists(conn, "events")? {
+        return Ok(0);
+    }
+
+    if column_exists(conn, "events", "seq")? {
+        Ok(2)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Returns the schema version of the database without modifying it.
+pub fn current_version(conn: &Connection) -> rusqlite::Result<i64> {
+    if !table_exists(conn, "schema_migrations")? {
+        return detect_untracked_version(conn);
+    }
+
+    conn.query_row("SELECT MAX(version) FROM schema_migrations", [], |row| row.get::<_, Option<i64>>(0))
+        .map(|version| version.unwrap_or(0))
+}
+
+/// Creates `schema_migrations`, recording the inferred version of untracked databases.
+fn ensure_tracking_table(conn: &Connection) -> rusqlite::Result<()> {
+    if table_exists(conn, "schema_migrations")? {
+        return Ok(());
+    }
+
+    let version = detect_untracked_version(conn)?;
+
+    conn.execute_batch(
+        "
+        CREATE TABLE schema_migrations (
+            version INTEGER PRIMARY KEY NOT NULL,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );
+        "
+    )?;
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version <= version) {
+        conn.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.name, now()],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Lists the migrations that have not been applied yet.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, MigrationError> {
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        return Err(MigrationError::DatabaseNewerThanBinary { database: current, binary: latest });
+    }
+
+    Ok(MIGRATIONS.iter().filter(|migration| migration.version > current).collect())
+}
+
+/// Applies all pending migrations in a single transaction.
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>, MigrationError> {
+    let tx = conn.transaction()?;
+
+    ensure_tracking_table(&tx)?;
+    let pending = pending(&tx)?;
+
+    for migration in pending.iter() {
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.name, now()],
+        )?;
+    }
+
+    tx.commit()?;
+
+    Ok(pending)
+}
+
+/// Brings the database up to date on startup, or refuses to start if that is not possible.
+pub fn migrate_on_startup(conn: &mut Connection, auto_migrate: bool) -> Result<(), MigrationError> {
+    if auto_migrate {
+        for migration in migrate(conn)? {
+            log::info!("Applied migration {:04} {}", migration.version, migration.name);
+        }
+        return Ok(());
+    }
+
+    let pending = pending(conn)?;
+    match pending.last() {
+        Some(latest) => Err(MigrationError::PendingMigrations { current: current_version(conn)?, latest: latest.version }),
+        None => Ok(())
+    }
+}
+
+fn applied_at(conn: &Connection, version: i64) -> rusqlite::Result<Option<i64>> {
+    if !table_exists(conn, "schema_migrations")? {
+        // Untracked databases have no timestamps
+        return Ok((version <= detect_untracked_version(conn)?).then_some(0));
+    }
+
+    conn.query_row(
+        "SELECT applied_at FROM schema_migrations WHERE version = ?1",
+        params![version],
+        |row| row.get(0)
+    )
+    .optional()
+}
+
+/// Entry point of the `migrate` command line.
+///
+/// - `migrate status`: list applied and pending migrations
+/// - `migrate dry-run`: print the SQL of pending migrations without applying it
+/// - `migrate` / `migrate apply`: apply pending migrations and exit
+pub fn run_cli(conn: &mut Connection, command: Option<&str>) -> Result<(), MigrationError> {
+    match command {
+        Some("status") => {
+            let current = current_version(conn)?;
+            println!("Database schema version: {} (binary: {})", current, latest_version());
+
+            for migration in MIGRATIONS {
+                match applied_at(conn, migration.version)?.map(DateTime::from_timestamp_millis) {
+                    Some(Some(time)) if time.timestamp() > 0 => println!("  [applied {}] {:04} {}", time.to_rfc3339(), migration.version, migration.name),
+                    Some(_) => println!("  [applied, untracked] {:04} {}", migration.version, migration.name),
+                    None => println!("  [pending] {:04} {}", migration.version, migration.name),
+                }
+            }
+
+            if current > latest_version() {
+                return Err(MigrationError::DatabaseNewerThanBinary { database: current, binary: latest_version() });
+            }
+        },
+
+        Some("dry-run") => {
+            let pending = pending(conn)?;
+            if pending.is_empty() {
+                println!("Database is up to date");
+            }
+            for migration in pending {
+                println!("-- {:04} {}\n{}", migration.version, migration.name, migration.sql);
+            }
+        },
+
+        None | Some("apply") => {
+            let applied = migrate(conn)?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+            for migration in applied {
+                println!("Applied {:04} {}", migration.version, migration.name);
+            }
+        },
+
+        Some(other) => return Err(MigrationError::UnknownCommand(other.to_string()))
+    }
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    session_id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT,
+    start_date TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ip_address TEXT NOT NULL,
+    device_model TEXT,
+    operating_system TEXT,
+    screen_width INT,
+    screen_height INT,
+    user_agent TEXT
+);
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id TEXT NOT NULL,
+    event_name TEXT NOT NULL,
+    timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ip_address TEXT NOT NULL,
+    params TEXT,
+    FOREIGN KEY(session_id) REFERENCES sessions(session_id)
+);
+
+CREATE INDEX IF NOT EXISTS user_idx ON sessions (user_id);
+CREATE INDEX IF NOT EXISTS session_idx ON events (session_id);
+CREATE INDEX IF NOT EXISTS session_event_name_idx ON events (session_id, event_name);
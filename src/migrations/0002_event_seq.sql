@@ -0,0 +1,3 @@
+ALTER TABLE events ADD COLUMN seq INTEGER;
+
+CREATE INDEX IF NOT EXISTS session_seq_idx ON events (session_id, seq);
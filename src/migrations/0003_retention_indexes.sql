@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS event_timestamp_idx ON events (timestamp);
+CREATE INDEX IF NOT EXISTS session_start_date_idx ON sessions (start_date);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS promoted_params (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE (event_name, path)
+);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS rollup_events (
+    granularity TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    event_name TEXT NOT NULL,
+    events INTEGER NOT NULL,
+    PRIMARY KEY (granularity, bucket, event_name)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS rollup_sessions (
+    granularity TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    sessions INTEGER NOT NULL,
+    users BLOB NOT NULL,
+    PRIMARY KEY (granularity, bucket)
+);
+CREATE TABLE IF NOT EXISTS rollup_watermarks (
+    source TEXT PRIMARY KEY NOT NULL,
+    last_id INTEGER NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE VIRTUAL TABLE IF NOT EXISTS event_search USING fts5(
+    content,
+    session_id UNINDEXED,
+    event_name UNINDEXED,
+    timestamp UNINDEXED,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
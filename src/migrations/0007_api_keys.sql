@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS api_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    prefix TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    last_used_at INTEGER,
+    rotated_at INTEGER,
+    revoked_at INTEGER,
+    previous_key_hash TEXT,
+    previous_expires_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS api_keys_previous_key_hash ON api_keys (previous_key_hash);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS erasures (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id_hash TEXT NOT NULL,
+    requested_by TEXT NOT NULL,
+    reason TEXT,
+    requested_at INTEGER NOT NULL,
+    completed_at INTEGER,
+    sessions_deleted INTEGER NOT NULL DEFAULT 0,
+    events_deleted INTEGER NOT NULL DEFAULT 0,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS erasures_user_id_hash ON erasures (user_id_hash);
@@ -0,0 +1,4 @@
+ALTER TABLE sessions ADD COLUMN consent_analytics INTEGER;
+ALTER TABLE sessions ADD COLUMN consent_crash_reporting INTEGER;
+ALTER TABLE sessions ADD COLUMN consent_personalized INTEGER;
+ALTER TABLE sessions ADD COLUMN consent_updated_at INTEGER;
@@ -0,0 +1 @@
+ALTER TABLE erasures ADD COLUMN retained_in TEXT;
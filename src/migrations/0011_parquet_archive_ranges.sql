@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS parquet_archive_ranges (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    table_name TEXT NOT NULL,
+    day_start INTEGER NOT NULL,
+    day_end INTEGER NOT NULL,
+    last_key INTEGER NOT NULL,
+    last_id INTEGER NOT NULL,
+    rows INTEGER NOT NULL,
+    files TEXT NOT NULL,
+    archived_at INTEGER NOT NULL,
+    deleted INTEGER,
+    deleted_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS parquet_archive_ranges_pending ON parquet_archive_ranges (deleted_at);
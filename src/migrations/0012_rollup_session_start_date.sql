@@ -0,0 +1,4 @@
+INSERT OR IGNORE INTO rollup_watermarks (source, last_id)
+SELECT 'session_start_date', MAX(start_date) FROM sessions
+WHERE rowid <= (SELECT last_id FROM rollup_watermarks WHERE source = 'sessions')
+HAVING MAX(start_date) IS NOT NULL;
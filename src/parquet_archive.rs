@@ -0,0 +1,1006 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use actix_web::{web, Error, HttpResponse};
+use arrow_array::{ArrayRef, BooleanArray, Int64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use rusqlite::{types::Value, Row};
+use serde::Serialize;
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::config::{Config};
+use crate::db_pool::{DbError, DbPool};
+use crate::retention::{delete_events_in_batches, delete_in_batches, incremental_vacuum};
+use crate::route_handlers::{now, sqlite_required, ApiResponse};
+
+const DAY_MILLIS: i64 = 86_400_000;
+
+/// Rows per Parquet row group, bounds the memory a file writer buffers.
+const MAX_ROW_GROUP_SIZE: usize = 100_000;
+
+#[derive(Serialize, Clone)]
+pub struct ArchivedDay {
+    table: &'static str,
+    date: String,
+    rows: u64,
+    deleted: u64,
+    files: Vec<String>,
+}
+
+/// Outcome of a single archival run. Days listed here were written, verified and deleted.
+#[derive(Serialize, Clone, Default)]
+pub struct ParquetArchiveReport {
+    started_at: i64,
+    finished_at: i64,
+    cutoff: i64,
+    days: Vec<ArchivedDay>,
+    events_archived: u64,
+    sessions_archived: u64,
+    bytes: u64,
+    pages_vacuumed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct ParquetArchiveStatus {
+    running: bool,
+    last_run: Option<ParquetArchiveReport>,
+}
+
+/// Columns read into an `EventRow`, in the order `EventRow::from_row` expects them.
+pub const EVENT_COLUMNS: &str = "id, session_id, event_name, timestamp, ip_address, params, seq";
+
+/// Columns read into a `SessionRow`. The rowid only serves as a stable sort key.
+pub const SESSION_COLUMNS: &str = "rowid, session_id, user_id, start_date, ip_address, device_model, \
+    operating_system, screen_width, screen_height, user_agent, consent_analytics, consent_crash_reporting, \
+    consent_personalized, consent_updated_at";
+
+pub struct EventRow {
+    pub id: i64,
+    pub session_id: String,
+    pub event_name: String,
+    pub timestamp: i64,
+    pub ip_address: String,
+    pub params: Option<String>,
+    pub seq: Option<i64>,
+}
+
+impl EventRow {
+    pub fn from_row(row: &Row) -> rusqlite::Result<EventRow> {
+        Ok(EventRow {
+            id: row.get(0)?,
+            session_id: row.get(1)?,
+            event_name: row.get(2)?,
+            timestamp: row.get(3)?,
+            ip_address: row.get(4)?,
+            params: row.get(5)?,
+            seq: row.get(6)?,
+        })
+    }
+}
+
+pub struct SessionRow {
+    pub rowid: i64,
+    pub session_id: String,
+    pub user_id: Option<String>,
+    pub start_date: i64,
+    pub ip_address: String,
+    pub device_model: Option<String>,
+    pub operating_system: Option<String>,
+    pub screen_width: Option<i64>,
+    pub screen_height: Option<i64>,
+    pub user_agent: Option<String>,
+    /// Consent as stored, `None` for sessions created before it was recorded
+    pub consent_analytics: Option<bool>,
+    pub consent_crash_reporting: Option<bool>,
+    pub consent_personalized: Option<bool>,
+    pub consent_updated_at: Option<i64>,
+}
+
+impl SessionRow {
+    pub fn from_row(row: &Row) -> rusqlite::Result<SessionRow> {
+        Ok(SessionRow {
+            rowid: row.get(0)?,
+            session_id: row.get(1)?,
+            user_id: row.get(2)?,
+            start_date: row.get(3)?,
+            ip_address: row.get(4)?,
+            device_model: row.get(5)?,
+            operating_system: row.get(6)?,
+            screen_width: row.get(7)?,
+            screen_height: row.get(8)?,
+            user_agent: row.get(9)?,
+            consent_analytics: row.get(10)?,
+            consent_crash_reporting: row.get(11)?,
+            consent_personalized: row.get(12)?,
+            consent_updated_at: row.get(13)?,
+        })
+    }
+}
+
+pub fn parquet_archive_enabled(config: &Config) -> bool {
+    config.parquet_archive_dir.is_some()
+}
+
+/// Marks an archival run as running. Returns `false` if one is already in progress.
+pub fn try_begin(status: &Mutex<ParquetArchiveStatus>) -> bool {
+    let mut status = status.lock();
+    if status.running {
+        return false;
+    }
+    status.running = true;
+    true
+}
+
+pub fn parquet_error(e: ParquetError) -> DbError {
+    DbError::Failed(format!("Parquet: {}", e))
+}
+
+fn arrow_error(e: ArrowError) -> DbError {
+    DbError::Failed(format!("Arrow: {}", e))
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+}
+
+pub fn event_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("session_id", DataType::Utf8, false),
+        Field::new("event_name", DataType::Utf8, false),
+        Field::new("timestamp", timestamp_type(), false),
+        Field::new("ip_address", DataType::Utf8, false),
+        Field::new("params", DataType::Utf8, true),
+        Field::new("seq", DataType::Int64, true),
+    ]))
+}
+
+pub fn session_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("session_id", DataType::Utf8, false),
+        Field::new("user_id", DataType::Utf8, true),
+        Field::new("start_date", timestamp_type(), false),
+        Field::new("ip_address", DataType::Utf8, false),
+        Field::new("device_model", DataType::Utf8, true),
+        Field::new("operating_system", DataType::Utf8, true),
+        Field::new("screen_width", DataType::Int64, true),
+        Field::new("screen_height", DataType::Int64, true),
+        Field::new("user_agent", DataType::Utf8, true),
+        Field::new("consent_analytics", DataType::Boolean, true),
+        Field::new("consent_crash_reporting", DataType::Boolean, true),
+        Field::new("consent_personalized", DataType::Boolean, true),
+        Field::new("consent_updated_at", timestamp_type(), true),
+    ]))
+}
+
+pub fn event_batch(rows: &[EventRow]) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(rows.iter().map(|row| row.id))),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.session_id))),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.event_name))),
+        Arc::new(TimestampMillisecondArray::from_iter_values(rows.iter().map(|row| row.timestamp)).with_timezone("UTC")),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.ip_address))),
+        Arc::new(rows.iter().map(|row| row.params.as_deref()).collect::<StringArray>()),
+        Arc::new(rows.iter().map(|row| row.seq).collect::<Int64Array>()),
+    ];
+
+    RecordBatch::try_new(event_schema(), columns)
+}
+
+pub fn session_batch(rows: &[SessionRow]) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.session_id))),
+        Arc::new(rows.iter().map(|row| row.user_id.as_deref()).collect::<StringArray>()),
+        Arc::new(TimestampMillisecondArray::from_iter_values(rows.iter().map(|row| row.start_date)).with_timezone("UTC")),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.ip_address))),
+        Arc::new(rows.iter().map(|row| row.device_model.as_deref()).collect::<StringArray>()),
+        Arc::new(rows.iter().map(|row| row.operating_system.as_deref()).collect::<StringArray>()),
+        Arc::new(rows.iter().map(|row| row.screen_width).collect::<Int64Array>()),
+        Arc::new(rows.iter().map(|row| row.screen_height).collect::<Int64Array>()),
+        Arc::new(rows.iter().map(|row| row.user_agent.as_deref()).collect::<StringArray>()),
+        Arc::new(rows.iter().map(|row| row.consent_analytics).collect::<BooleanArray>()),
+        Arc::new(rows.iter().map(|row| row.consent_crash_reporting).collect::<BooleanArray>()),
+        Arc::new(rows.iter().map(|row| row.consent_personalized).collect::<BooleanArray>()),
+        Arc::new(rows.iter().map(|row| row.consent_updated_at).collect::<TimestampMillisecondArray>().with_timezone("UTC")),
+    ];
+
+    RecordBatch::try_new(session_schema(), columns)
+}
+
+fn date_string(day_start: i64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(day_start).unwrap_or_default().format("%Y-%m-%d").to_string()
+}
+
+fn date_dir(day_start: i64) -> String {
+    format!("date={}", date_string(day_start))
+}
+
+/// Escapes a value for a `key=value` directory name the way Hive partitioning does.
+fn escape_path_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-' | b'.' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+struct PartFile {
+    partial: PathBuf,
+    target: PathBuf,
+    writer: ArrowWriter<File>,
+    rows: u64,
+}
+
+/// Writes the rows of one table into one file per partition directory.
+///
+/// Files are written under a hidden temporary name and only renamed into place once
+/// their row counts are verified, so readers of the archive never see partial files.
+struct PartWriter {
+    root: PathBuf,
+    file_name: String,
+    schema: SchemaRef,
+    files: BTreeMap<String, PartFile>,
+}
+
+impl PartWriter {
+    fn new(root: PathBuf, run_stamp: &str, schema: SchemaRef) -> PartWriter {
+        PartWriter { root, file_name: format!("part-{}.parquet", run_stamp), schema, files: BTreeMap::new() }
+    }
+
+    fn write(&mut self, dir: String, batch: &RecordBatch) -> Result<(), DbError> {
+        if !self.files.contains_key(&dir) {
+            let target_dir = self.root.join(&dir);
+            fs::create_dir_all(&target_dir)?;
+
+            let partial = target_dir.join(format!(".{}.tmp", self.file_name));
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                .set_max_row_group_size(MAX_ROW_GROUP_SIZE)
+                .build();
+            let writer = ArrowWriter::try_new(File::create(&partial)?, self.schema.clone(), Some(properties))
+                .map_err(parquet_error)?;
+
+            self.files.insert(dir.clone(), PartFile { partial, target: target_dir.join(&self.file_name), writer, rows: 0 });
+        }
+
+        let file = self.files.get_mut(&dir).expect("file was just created");
+        file.writer.write(batch).map_err(parquet_error)?;
+        file.rows += batch.num_rows() as u64;
+        Ok(())
+    }
+
+    fn discard(self) {
+        for file in self.files.into_values() {
+            drop(file.writer);
+            let _ = fs::remove_file(&file.partial);
+        }
+    }
+
+    /// Closes all files and checks that each holds exactly the rows written to it and
+    /// that together they hold `expected` rows. They stay under their temporary names.
+    fn close(self, expected: u64) -> Result<ClosedFiles, DbError> {
+        let mut closed = Vec::new();
+        let mut result = Ok(());
+
+        for file in self.files.into_values() {
+            match file.writer.close() {
+                Ok(_) => closed.push((file.partial, file.target, file.rows)),
+                Err(e) => {
+                    let _ = fs::remove_file(&file.partial);
+                    result = Err(parquet_error(e));
+                }
+            }
+        }
+
+        if result.is_ok() {
+            result = verify(&closed, expected);
+        }
+
+        if let Err(e) = result {
+            for (partial, _, _) in closed {
+                let _ = fs::remove_file(partial);
+            }
+            return Err(e);
+        }
+
+        Ok(ClosedFiles { files: closed.into_iter().map(|(partial, target, _)| (partial, target)).collect() })
+    }
+}
+
+/// Verified files waiting to be moved into place.
+struct ClosedFiles {
+    files: Vec<(PathBuf, PathBuf)>,
+}
+
+impl ClosedFiles {
+    fn targets(&self) -> Vec<String> {
+        self.files.iter().map(|(_, target)| target.display().to_string()).collect()
+    }
+
+    fn publish(self) -> Result<u64, DbError> {
+        let mut bytes = 0;
+
+        for (partial, target) in self.files {
+            bytes += fs::metadata(&partial)?.len();
+            fs::rename(&partial, &target)?;
+        }
+
+        Ok(bytes)
+    }
+
+    fn discard(self) {
+        for (partial, _) in self.files {
+            let _ = fs::remove_file(partial);
+        }
+    }
+}
+
+/// Reads the row counts back from the Parquet footers.
+fn verify(files: &[(PathBuf, PathBuf, u64)], expected: u64) -> Result<(), DbError> {
+    let mut total = 0;
+
+    for (partial, _, rows) in files {
+        let reader = SerializedFileReader::new(File::open(partial)?).map_err(parquet_error)?;
+        let stored = reader.metadata().file_metadata().num_rows() as u64;
+
+        if stored != *rows {
+            return Err(DbError::Failed(format!("{} holds {} rows, {} were written", partial.display(), stored, rows)));
+        }
+        total += stored;
+    }
+
+    if total != expected {
+        return Err(DbError::Failed(format!("Archived {} rows, the database holds {}", total, expected)));
+    }
+
+    Ok(())
+}
+
+/// Writes grouped rows on a blocking thread and hands the writer back.
+async fn write_groups<R: Send + 'static>(
+    mut writer: PartWriter,
+    groups: BTreeMap<String, Vec<R>>,
+    to_batch: fn(&[R]) -> Result<RecordBatch, ArrowError>,
+) -> Result<PartWriter, DbError> {
+    tokio::task::spawn_blocking(move || {
+        for (dir, rows) in groups {
+            let result = to_batch(&rows)
+                .map_err(arrow_error)
+                .and_then(|batch| writer.write(dir, &batch));
+
+            if let Err(e) = result {
+                writer.discard();
+                return Err(e);
+            }
+        }
+        Ok(writer)
+    }).await.map_err(|e| DbError::Failed(e.to_string()))?
+}
+
+/// A day range of one table whose files were written. Its rows up to the `last` cursor
+/// are deleted from the database once the files are in place.
+struct ArchivedRange {
+    id: i64,
+    table: &'static str,
+    day_start: i64,
+    day_end: i64,
+    last: (i64, i64),
+    rows: u64,
+    files: Vec<String>,
+}
+
+impl ArchivedRange {
+    fn delete_sql(&self) -> &'static str {
+        match self.table {
+            "events" => "DELETE FROM {events} WHERE id IN (
+                SELECT id FROM {events}
+                WHERE timestamp >= ?1 AND timestamp < ?2 AND (timestamp, id) <= (?3, ?4)
+                LIMIT ?5
+            )",
+            _ => "DELETE FROM sessions WHERE rowid IN (
+                SELECT rowid FROM sessions
+                WHERE start_date >= ?1 AND start_date < ?2 AND (start_date, rowid) <= (?3, ?4)
+                AND NOT EXISTS (SELECT 1 FROM events WHERE events.session_id = sessions.session_id)
+                LIMIT ?5
+            )",
+        }
+    }
+}
+
+/// Closes the files of a day, records the range and only then moves the files into
+/// place, so a run that stops midway is finished by `finish_pending` instead of
+/// exporting the rows that are left a second time.
+async fn commit_range(db: &Arc<DbPool>, writer: PartWriter, mut range: ArchivedRange) -> Result<(ArchivedRange, u64), DbError> {
+    let expected = range.rows;
+    let closed = tokio::task::spawn_blocking(move || writer.close(expected))
+        .await
+        .map_err(|e| DbError::Failed(e.to_string()))??;
+
+    range.files = closed.targets();
+
+    let (table, day_start, day_end, last, rows, files) =
+        (range.table, range.day_start, range.day_end, range.last, range.rows as i64, range.files.join("\n"));
+
+    let recorded = db.write(move |conn| {
+        conn.execute(
+            "INSERT INTO main.parquet_archive_ranges (table_name, day_start, day_end, last_key, last_id, rows, files, archived_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![table, day_start, day_end, last.0, last.1, rows, files, now()],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }).await;
+
+    match recorded {
+        Ok(id) => range.id = id,
+        Err(e) => {
+            let _ = tokio::task::spawn_blocking(move || closed.discard()).await;
+            return Err(e);
+        }
+    }
+
+    let bytes = tokio::task::spawn_blocking(move || closed.publish())
+        .await
+        .map_err(|e| DbError::Failed(e.to_string()))??;
+
+    Ok((range, bytes))
+}
+
+/// Deletes the archived rows of a range and marks it as done.
+async fn delete_range(db: &Arc<DbPool>, config: &Config, range: &ArchivedRange) -> Result<u64, DbError> {
+    let params = vec![
+        Value::Integer(range.day_start),
+        Value::Integer(range.day_end),
+        Value::Integer(range.last.0),
+        Value::Integer(range.last.1),
+    ];
+    let batch_size = config.parquet_archive_batch_size.max(1);
+
+    let deleted = match range.table {
+        "events" => delete_events_in_batches(db, range.delete_sql(), params, batch_size).await?,
+        _ => delete_in_batches(db, range.delete_sql().to_string(), params, batch_size).await?,
+    };
+
+    let id = range.id;
+    db.write(move |conn| {
+        conn.execute(
+            "UPDATE main.parquet_archive_ranges SET deleted = ?2, deleted_at = ?3 WHERE id = ?1",
+            rusqlite::params![id, deleted as i64, now()],
+        )
+    }).await?;
+
+    Ok(deleted)
+}
+
+/// Finishes ranges of an earlier run that stopped before their rows were deleted. If
+/// not all of their files were moved into place, the files are removed and the rows
+/// are exported again.
+async fn finish_pending(db: &Arc<DbPool>, config: &Config, report: &mut ParquetArchiveReport) -> Result<(), DbError> {
+    let pending = db.read(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT id, table_name, day_start, day_end, last_key, last_id, rows, files
+            FROM main.parquet_archive_ranges WHERE deleted_at IS NULL ORDER BY id"
+        )?;
+        let rows = stmt.query_map([], |row| Ok(ArchivedRange {
+            id: row.get(0)?,
+            table: if row.get::<_, String>(1)? == "events" { "events" } else { "sessions" },
+            day_start: row.get(2)?,
+            day_end: row.get(3)?,
+            last: (row.get(4)?, row.get(5)?),
+            rows: row.get::<_, i64>(6)? as u64,
+            files: row.get::<_, String>(7)?.split('\n').filter(|file| !file.is_empty()).map(str::to_string).collect(),
+        }))?;
+        rows.collect::<rusqlite::Result<Vec<ArchivedRange>>>()
+    }).await?;
+
+    for range in pending {
+        if range.files.iter().all(|file| Path::new(file).exists()) {
+            let deleted = delete_range(db, config, &range).await?;
+            log::info!("Finished archiving {} of {}, deleted {} rows", range.table, date_dir(range.day_start), deleted);
+
+            match range.table {
+                "events" => report.events_archived += range.rows,
+                _ => report.sessions_archived += range.rows,
+            }
+            report.days.push(ArchivedDay {
+                table: range.table,
+                date: date_string(range.day_start),
+                rows: range.rows,
+                deleted,
+                files: range.files,
+            });
+            continue;
+        }
+
+        for file in range.files.iter().map(PathBuf::from) {
+            let partial = file.with_file_name(format!(".{}.tmp", file.file_name().unwrap_or_default().to_string_lossy()));
+            let _ = fs::remove_file(&partial);
+            let _ = fs::remove_file(&file);
+        }
+
+        let id = range.id;
+        db.write(move |conn| conn.execute("DELETE FROM main.parquet_archive_ranges WHERE id = ?1", [id])).await?;
+        log::warn!("Discarded the incomplete archive of {} of {}, it is exported again", range.table, date_dir(range.day_start));
+    }
+
+    Ok(())
+}
+
+async fn discard_files(writer: PartWriter) {
+    let _ = tokio::task::spawn_blocking(move || writer.discard()).await;
+}
+
+/// Exports the events of `[day_start, day_end)` in pages of `batch_size` rows.
+async fn export_event_day(
+    db: &Arc<DbPool>,
+    config: &Config,
+    mut writer: PartWriter,
+    day_start: i64,
+    day_end: i64,
+) -> Result<(PartWriter, u64, (i64, i64)), DbError> {
+    let batch_size = config.parquet_archive_batch_size.max(1) as i64;
+    let date = date_dir(day_start);
+    let mut cursor = (i64::MIN, i64::MIN);
+    let mut written = 0;
+
+    loop {
+        let rows = db.read(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                &format!(
+                    "SELECT {} FROM events
+                    WHERE timestamp >= ?1 AND timestamp < ?2 AND (timestamp, id) > (?3, ?4)
+                    ORDER BY timestamp, id LIMIT ?5",
+                    EVENT_COLUMNS
+                )
+            )?;
+            let rows = stmt.query_map((day_start, day_end, cursor.0, cursor.1, batch_size), EventRow::from_row)?;
+            rows.collect::<rusqlite::Result<Vec<EventRow>>>()
+        }).await;
+
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                discard_files(writer).await;
+                return Err(e);
+            }
+        };
+
+        let Some(last) = rows.last() else {
+            return Ok((writer, written, cursor));
+        };
+        cursor = (last.timestamp, last.id);
+        written += rows.len() as u64;
+
+        let mut groups: BTreeMap<String, Vec<EventRow>> = BTreeMap::new();
+        for row in rows {
+            let dir = if config.parquet_archive_by_event_name {
+                format!("{}/event_name={}", date, escape_path_value(&row.event_name))
+            } else {
+                date.clone()
+            };
+            groups.entry(dir).or_default().push(row);
+        }
+
+        writer = write_groups(writer, groups, event_batch).await?;
+    }
+}
+
+/// Archives the events of one UTC day. Returns `None` once no events are left before `cutoff`.
+async fn archive_event_day(
+    db: &Arc<DbPool>,
+    config: &Config,
+    run_stamp: &str,
+    after: i64,
+    cutoff: i64,
+    report: &mut ParquetArchiveReport,
+) -> Result<Option<i64>, DbError> {
+    let first = db.read(move |conn| {
+        conn.query_row(
+            "SELECT MIN(timestamp) FROM events WHERE timestamp >= ?1 AND timestamp < ?2",
+            (after, cutoff),
+            |row| row.get::<_, Option<i64>>(0),
+        )
+    }).await?;
+
+    let Some(first) = first else {
+        return Ok(None);
+    };
+
+    let day_start = first - first.rem_euclid(DAY_MILLIS);
+    let day_end = (day_start + DAY_MILLIS).min(cutoff);
+
+    // Timestamps are assigned on ingestion, so no new rows can appear in a past day
+    let expected = db.read(move |conn| {
+        conn.query_row(
+            "SELECT COUNT(*) FROM events WHERE timestamp >= ?1 AND timestamp < ?2",
+            (day_start, day_end),
+            |row| row.get::<_, i64>(0),
+        )
+    }).await? as u64;
+
+    let root = PathBuf::from(config.parquet_archive_dir.as_deref().unwrap_or_default()).join("events");
+    let writer = PartWriter::new(root, run_stamp, event_schema());
+    let (writer, written, last) = export_event_day(db, config, writer, day_start, day_end).await?;
+
+    if written != expected {
+        discard_files(writer).await;
+        return Err(DbError::Failed(format!("Exported {} events of {}, the database holds {}", written, date_dir(day_start), expected)));
+    }
+
+    let range = ArchivedRange { id: 0, table: "events", day_start, day_end, last, rows: expected, files: Vec::new() };
+    let (range, bytes) = commit_range(db, writer, range).await?;
+    let deleted = delete_range(db, config, &range).await?;
+
+    if deleted != expected {
+        log::warn!("Archived {} events of {} but deleted {}", expected, date_dir(day_start), deleted);
+    }
+
+    report.events_archived += expected;
+    report.bytes += bytes;
+    report.days.push(ArchivedDay { table: "events", date: date_string(day_start), rows: expected, deleted, files: range.files });
+
+    Ok(Some(day_end))
+}
+
+/// Archives the sessions of one UTC day that no longer have events.
+async fn archive_session_day(
+    db: &Arc<DbPool>,
+    config: &Config,
+    run_stamp: &str,
+    after: i64,
+    cutoff: i64,
+    report: &mut ParquetArchiveReport,
+) -> Result<Option<i64>, DbError> {
+    let first = db.read(move |conn| {
+        conn.query_row(
+            "SELECT MIN(start_date) FROM sessions
+            WHERE start_date >= ?1 AND start_date < ?2
+            AND NOT EXISTS (SELECT 1 FROM events WHERE events.session_id = sessions.session_id)",
+            (after, cutoff),
+            |row| row.get::<_, Option<i64>>(0),
+        )
+    }).await?;
+
+    let Some(first) = first else {
+        return Ok(None);
+    };
+
+    let day_start = first - first.rem_euclid(DAY_MILLIS);
+    let day_end = (day_start + DAY_MILLIS).min(cutoff);
+    let date = date_dir(day_start);
+    let batch_size = config.parquet_archive_batch_size.max(1) as i64;
+
+    let root = PathBuf::from(config.parquet_archive_dir.as_deref().unwrap_or_default()).join("sessions");
+    let mut writer = PartWriter::new(root, run_stamp, session_schema());
+    let mut cursor = (i64::MIN, i64::MIN);
+    let mut written = 0;
+
+    loop {
+        let rows = db.read(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                &format!(
+                    "SELECT {} FROM sessions
+                    WHERE start_date >= ?1 AND start_date < ?2 AND (start_date, rowid) > (?3, ?4)
+                    AND NOT EXISTS (SELECT 1 FROM events WHERE events.session_id = sessions.session_id)
+                    ORDER BY start_date, rowid LIMIT ?5",
+                    SESSION_COLUMNS
+                )
+            )?;
+            let rows = stmt.query_map((day_start, day_end, cursor.0, cursor.1, batch_size), SessionRow::from_row)?;
+            rows.collect::<rusqlite::Result<Vec<SessionRow>>>()
+        }).await;
+
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                discard_files(writer).await;
+                return Err(e);
+            }
+        };
+
+        let Some(last) = rows.last() else {
+            break;
+        };
+        cursor = (last.start_date, last.rowid);
+        written += rows.len() as u64;
+
+        writer = write_groups(writer, BTreeMap::from([(date.clone(), rows)]), session_batch).await?;
+    }
+
+    let range = ArchivedRange { id: 0, table: "sessions", day_start, day_end, last: cursor, rows: written, files: Vec::new() };
+    let (range, bytes) = commit_range(db, writer, range).await?;
+    let deleted = delete_range(db, config, &range).await?;
+
+    // A session that received an event since the export stays in the database as well
+    if deleted != written {
+        log::warn!("Archived {} sessions of {} but deleted {}", written, date, deleted);
+    }
+
+    report.sessions_archived += written;
+    report.bytes += bytes;
+    report.days.push(ArchivedDay { table: "sessions", date: date_string(day_start), rows: written, deleted, files: range.files });
+
+    Ok(Some(day_end))
+}
+
+async fn archive(db: &Arc<DbPool>, config: &Config, report: &mut ParquetArchiveReport) -> Result<(), DbError> {
+    // Sessions are archived once the `events` view holds none of their events, which
+    // is only true if it covers every partition
+    db.require_all_partitions_attached("Parquet archival")?;
+
+    finish_pending(db, config, report).await?;
+
+    let run_stamp = Utc::now().format("%Y%m%dT%H%M%S%3fZ").to_string();
+
+    // Each day is only visited once, rows that could not be deleted are not exported again
+    let mut after = i64::MIN;
+    while let Some(day_end) = archive_event_day(db, config, &run_stamp, after, report.cutoff, report).await? {
+        after = day_end;
+    }
+
+    // Sessions go last, as they are only archived once all their events are gone
+    let mut after = i64::MIN;
+    while let Some(day_end) = archive_session_day(db, config, &run_stamp, after, report.cutoff, report).await? {
+        after = day_end;
+    }
+
+    if report.events_archived + report.sessions_archived > 0 {
+        report.pages_vacuumed = incremental_vacuum(db).await?.unwrap_or(0);
+    }
+
+    Ok(())
+}
+
+/// Runs an archival that was started with `try_begin` and records its report.
+pub async fn run_archive(db: Arc<DbPool>, config: Arc<Config>, status: Arc<Mutex<ParquetArchiveStatus>>) {
+    let started_at = now();
+    let mut report = ParquetArchiveReport {
+        started_at,
+        cutoff: started_at - config.parquet_archive_after_days as i64 * DAY_MILLIS,
+        ..Default::default()
+    };
+
+    let result = archive(&db, &config, &mut report).await;
+    report.finished_at = now();
+
+    match result {
+        Ok(_) => log::info!(
+            "Archived {} events and {} sessions to Parquet ({} bytes)",
+            report.events_archived, report.sessions_archived, report.bytes
+        ),
+        Err(e) => {
+            log::error!("Parquet archival failed: {}", e);
+            report.error = Some(e.to_string());
+        }
+    }
+
+    let mut status = status.lock();
+    status.running = false;
+    status.last_run = Some(report);
+}
+
+fn archive_disabled() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse {
+        success: false,
+        message: "Parquet archival is disabled, set PARQUET_ARCHIVE_DIR".to_string()
+    })
+}
+
+/// Reports whether an archival is running and the outcome of the last one.
+pub async fn get_parquet_archive(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    if data.db.is_none() {
+        return Ok(sqlite_required());
+    }
+
+    if !parquet_archive_enabled(&data.config) {
+        return Ok(archive_disabled());
+    }
+
+    let status = data.parquet_archive_status.lock().clone();
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Starts an archival in the background. Poll `GET /admin/parquet` for the result.
+pub async fn create_parquet_archive(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    if !parquet_archive_enabled(&data.config) {
+        return Ok(archive_disabled());
+    }
+
+    if !try_begin(&data.parquet_archive_status) {
+        return Ok(HttpResponse::Conflict().json(ApiResponse {
+            success: false,
+            message: "An archival is already running".to_string()
+        }));
+    }
+
+    actix_web::rt::spawn(run_archive(db.clone(), data.config.clone(), data.parquet_archive_status.clone()));
+
+    Ok(HttpResponse::Accepted().json(ApiResponse {
+        success: true,
+        message: "Archival started".to_string()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use crate::app_state::{TestDir};
+
+    use super::*;
+
+    /// 2020-01-01
+    const DAY_ONE: i64 = 1_577_836_800_000;
+
+    async fn project(dir: &TestDir) -> (Arc<DbPool>, Arc<Config>) {
+        let archive_dir = dir.file("archive");
+        let data = AppState::on_sqlite(dir, &[("PARQUET_ARCHIVE_DIR", &archive_dir), ("PARQUET_ARCHIVE_BATCH_SIZE", "2")]);
+        let db = data.db.clone().unwrap();
+
+        // Three events on the first day and two on the second, each day paged twice
+        db.write(|conn| {
+            for (session_id, start_date, events) in [("s1", DAY_ONE, 3), ("s2", DAY_ONE + DAY_MILLIS, 2)] {
+                conn.execute(
+                    "INSERT INTO sessions (session_id, user_id, start_date, ip_address) VALUES (?1, 'u1', ?2, '')",
+                    params![session_id, start_date],
+                )?;
+                for n in 0..events {
+                    conn.execute(
+                        "INSERT INTO events (session_id, event_name, timestamp, ip_address) VALUES (?1, 'run_start', ?2, '')",
+                        params![session_id, start_date + n * 1000],
+                    )?;
+                }
+            }
+            Ok(())
+        }).await.unwrap();
+
+        (db, data.config.clone())
+    }
+
+    fn report() -> ParquetArchiveReport {
+        ParquetArchiveReport { cutoff: now(), ..Default::default() }
+    }
+
+    async fn count(db: &Arc<DbPool>, sql: &'static str) -> i64 {
+        db.read(move |conn| conn.query_row(sql, [], |row| row.get(0))).await.unwrap()
+    }
+
+    /// Every file under `dir` with the row count of its footer, temporary files included.
+    fn archived_files(dir: &Path) -> Vec<(String, i64)> {
+        let mut files = Vec::new();
+
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                files.extend(archived_files(&path));
+                continue;
+            }
+
+            let rows = SerializedFileReader::new(File::open(&path).unwrap())
+                .map(|reader| reader.metadata().file_metadata().num_rows())
+                .unwrap_or(-1);
+            let date = path.parent().unwrap().file_name().unwrap().to_string_lossy().to_string();
+            files.push((date, rows));
+        }
+
+        files.sort();
+        files
+    }
+
+    /// Exports the events of the first day and commits their range, as a run that
+    /// stopped before deleting them would have.
+    async fn export_first_day(db: &Arc<DbPool>, config: &Config, extra_rows: u64) -> Result<(ArchivedRange, u64), DbError> {
+        let root = PathBuf::from(config.parquet_archive_dir.as_deref().unwrap()).join("events");
+        let writer = PartWriter::new(root, "20200103T000000000Z", event_schema());
+        let (writer, written, last) = export_event_day(db, config, writer, DAY_ONE, DAY_ONE + DAY_MILLIS).await?;
+
+        let range = ArchivedRange {
+            id: 0,
+            table: "events",
+            day_start: DAY_ONE,
+            day_end: DAY_ONE + DAY_MILLIS,
+            last,
+            rows: written + extra_rows,
+            files: Vec::new(),
+        };
+        commit_range(db, writer, range).await
+    }
+
+    #[actix_web::test]
+    async fn archives_and_deletes_every_day() {
+        let dir = TestDir::new("parquet");
+        let (db, config) = project(&dir).await;
+
+        let mut report = report();
+        archive(&db, &config, &mut report).await.unwrap();
+
+        let days: Vec<(&str, &str, u64, u64)> = report.days.iter()
+            .map(|day| (day.table, day.date.as_str(), day.rows, day.deleted))
+            .collect();
+        assert_eq!(days, [
+            ("events", "2020-01-01", 3, 3),
+            ("events", "2020-01-02", 2, 2),
+            ("sessions", "2020-01-01", 1, 1),
+            ("sessions", "2020-01-02", 1, 1),
+        ]);
+
+        assert_eq!(archived_files(&dir.0.join("archive").join("events")), [("date=2020-01-01".to_string(), 3), ("date=2020-01-02".to_string(), 2)]);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM events").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM sessions").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM parquet_archive_ranges WHERE deleted_at IS NULL").await, 0);
+    }
+
+    #[actix_web::test]
+    async fn resumed_runs_delete_instead_of_exporting_again() {
+        let dir = TestDir::new("parquet-resume");
+        let (db, config) = project(&dir).await;
+
+        export_first_day(&db, &config, 0).await.unwrap();
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM events").await, 5);
+
+        let mut report = report();
+        archive(&db, &config, &mut report).await.unwrap();
+
+        // The pending range is finished first, its day is not exported a second time
+        assert_eq!((report.days[0].date.as_str(), report.days[0].rows, report.days[0].deleted), ("2020-01-01", 3, 3));
+        assert_eq!(report.events_archived, 5);
+        assert_eq!(archived_files(&dir.0.join("archive").join("events")), [("date=2020-01-01".to_string(), 3), ("date=2020-01-02".to_string(), 2)]);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM events").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM parquet_archive_ranges WHERE deleted_at IS NULL").await, 0);
+    }
+
+    #[actix_web::test]
+    async fn unpublished_ranges_are_exported_again() {
+        let dir = TestDir::new("parquet-unpublished");
+        let (db, config) = project(&dir).await;
+
+        // A run that stopped after recording the range but before moving its file into place
+        let (range, _) = export_first_day(&db, &config, 0).await.unwrap();
+        let file = PathBuf::from(&range.files[0]);
+        fs::rename(&file, file.with_file_name(format!(".{}.tmp", file.file_name().unwrap().to_string_lossy()))).unwrap();
+
+        let mut report = report();
+        archive(&db, &config, &mut report).await.unwrap();
+
+        assert_eq!(archived_files(&dir.0.join("archive").join("events")), [("date=2020-01-01".to_string(), 3), ("date=2020-01-02".to_string(), 2)]);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM events").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM parquet_archive_ranges").await, 4);
+    }
+
+    #[actix_web::test]
+    async fn keeps_rows_whose_files_fail_verification() {
+        let dir = TestDir::new("parquet-verify");
+        let (db, config) = project(&dir).await;
+
+        // The footers hold one row less than the range claims
+        assert!(export_first_day(&db, &config, 1).await.is_err());
+
+        assert_eq!(archived_files(&dir.0.join("archive").join("events")), []);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM parquet_archive_ranges").await, 0);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM events").await, 5);
+    }
+}
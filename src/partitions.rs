@@ -0,0 +1,607 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web, Error, HttpRequest, HttpResponse,
+};
+use chrono::{NaiveDate, Utc};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond};
+use crate::config::{Config, PartitionPeriod};
+use crate::route_handlers::{database_error, sqlite_required, ApiResponse};
+
+/// SQLite refuses to attach more than 10 databases per connection.
+const SQLITE_MAX_ATTACHED: usize = 10;
+
+/// Number of event ids reserved for each partition.
+const PARTITION_ID_SPAN: i64 = 10_000_000_000;
+
+/// Columns shared by `main.events` and every partition table.
+const EVENT_COLUMNS: &str = "id, session_id, event_name, timestamp, ip_address, params, seq";
+
+/// One file holding the events of a month (`2026-10`) or a day (`2026-10-18`).
+#[derive(Clone)]
+pub struct Partition {
+    key: String,
+    path: PathBuf,
+}
+
+impl Partition {
+    /// Schema name of the partition on every connection.
+    fn alias(&self) -> String {
+        format!("p_{}", self.key.replace('-', "_"))
+    }
+
+    /// Triggers may not qualify table names, so every partition table is named uniquely.
+    fn table(&self) -> String {
+        format!("events_{}", self.key.replace('-', "_"))
+    }
+
+    /// Event ids start at the partition key, e.g. `202610_0000000001`, so they stay unique
+    /// across partitions and increase over time.
+    fn id_base(&self) -> i64 {
+        self.key.replace('-', "").parse::<i64>().unwrap_or(0) * PARTITION_ID_SPAN
+    }
+
+    /// The ids events of this partition can have, start inclusive and end exclusive.
+    pub fn id_range(&self) -> (i64, i64) {
+        (self.id_base(), self.id_base() + PARTITION_ID_SPAN)
+    }
+}
+
+#[derive(Serialize)]
+pub struct PartitionInfo {
+    name: String,
+    file: String,
+    size: u64,
+    attached: bool,
+    current: bool,
+}
+
+struct PartitionState {
+    /// Partitions found next to the main database, ordered from oldest to newest.
+    partitions: Vec<Partition>,
+    current: Option<String>,
+}
+
+/// Per-period event files attached to every connection of the pool.
+///
+/// Each connection gets a temporary `events` view spanning `main.events` and the most
+/// recent partitions, so queries keep using `events`. On the writer, `INSTEAD OF` triggers
+/// route inserts to the current partition and updates and deletes to all attached ones.
+/// Partitions cannot reference `main.sessions`, so the insert trigger checks the session
+/// the way the foreign key of `main.events` does.
+pub struct Partitions {
+    period: PartitionPeriod,
+    dir: PathBuf,
+    prefix: String,
+    archive_dir: PathBuf,
+    attach_limit: usize,
+    synchronous: String,
+    state: Mutex<PartitionState>,
+    generation: AtomicU64,
+}
+
+fn parse_key(key: &str) -> bool {
+    NaiveDate::parse_from_str(&format!("{}-01", key), "%Y-%m-%d").is_ok() && key.len() == 7
+        || NaiveDate::parse_from_str(key, "%Y-%m-%d").is_ok() && key.len() == 10
+}
+
+/// Schema and table name of `main.events` and every partition attached to `conn`.
+pub fn attached_event_tables(conn: &Connection) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_database_list WHERE name LIKE 'p\\_%' ESCAPE '\\'")?;
+    let aliases = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut tables = vec![("main".to_string(), "events".to_string())];
+    tables.extend(aliases.into_iter().map(|alias| {
+        let table = format!("events_{}", &alias[2..]);
+        (alias, table)
+    }));
+
+    Ok(tables)
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    // Fall back to copying if the archive lives on another file system
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+impl Partitions {
+    pub fn new(config: &Config) -> io::Result<Option<Partitions>> {
+        let period = match config.db_partitioning {
+            Some(period) => period,
+            None => return Ok(None)
+        };
+
+        let db_path = Path::new(&config.db_path);
+        let dir = match db_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from(".")
+        };
+        let stem = db_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("analytics");
+
+        let archive_dir = config.db_partition_archive_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| dir.join("archive"));
+
+        let mut partitions = Partitions {
+            period,
+            dir,
+            prefix: format!("{}.events.", stem),
+            archive_dir,
+            attach_limit: config.db_partitions_attached.clamp(1, SQLITE_MAX_ATTACHED),
+            synchronous: config.db_synchronous.clone(),
+            state: Mutex::new(PartitionState { partitions: Vec::new(), current: None }),
+            generation: AtomicU64::new(1),
+        };
+
+        partitions.state.get_mut().partitions = partitions.discover()?;
+
+        Ok(Some(partitions))
+    }
+
+    fn discover(&self) -> io::Result<Vec<Partition>> {
+        let mut partitions = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let key = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(&self.prefix))
+                .and_then(|name| name.strip_suffix(".db"))
+                .filter(|key| parse_key(key))
+                .map(str::to_string);
+
+            if let Some(key) = key {
+                partitions.push(Partition { key, path });
+            }
+        }
+
+        partitions.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(partitions)
+    }
+
+    fn current_key(&self) -> String {
+        match self.period {
+            PartitionPeriod::Month => Utc::now().format("%Y-%m").to_string(),
+            PartitionPeriod::Day => Utc::now().format("%Y-%m-%d").to_string(),
+        }
+    }
+
+    /// Changes whenever the set of attached partitions changes.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn attached(state: &PartitionState, attach_limit: usize) -> &[Partition] {
+        let skip = state.partitions.len().saturating_sub(attach_limit);
+        &state.partitions[skip..]
+    }
+
+    /// Creates the partition of the current period when a new period has begun.
+    pub fn ensure_current(&self) -> rusqlite::Result<()> {
+        let key = self.current_key();
+        let mut state = self.state.lock();
+
+        if state.current.as_deref() == Some(key.as_str()) {
+            return Ok(());
+        }
+
+        let partition = match state.partitions.iter().find(|partition| partition.key == key) {
+            Some(partition) => partition.clone(),
+            None => Partition { path: self.dir.join(format!("{}{}.db", self.prefix, key)), key: key.clone() }
+        };
+
+        let conn = Connection::open(&partition.path)?;
+        let table = partition.table();
+
+        conn.execute_batch(&format!(
+            "
+            PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS {table} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                session_id TEXT NOT NULL,
+                event_name TEXT NOT NULL,
+                timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                ip_address TEXT NOT NULL,
+                params TEXT,
+                seq INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS session_idx ON {table} (session_id);
+            CREATE INDEX IF NOT EXISTS session_event_name_idx ON {table} (session_id, event_name);
+            CREATE INDEX IF NOT EXISTS session_seq_idx ON {table} (session_id, seq);
+            CREATE INDEX IF NOT EXISTS event_timestamp_idx ON {table} (timestamp);
+            ",
+            table = table
+        ))?;
+
+        conn.execute(
+            "INSERT INTO sqlite_sequence (name, seq) SELECT ?1, ?2
+            WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = ?1)",
+            params![table, partition.id_base()],
+        )?;
+
+        if !state.partitions.iter().any(|existing| existing.key == key) {
+            log::info!("Created event partition {}", partition.path.display());
+            state.partitions.push(partition);
+            state.partitions.sort_by(|a, b| a.key.cmp(&b.key));
+        }
+
+        state.current = Some(key);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+
+        Ok(())
+    }
+
+    /// Attaches the current set of partitions to `conn` and recreates the `events` view.
+    /// Returns the generation the connection is in sync with.
+    pub fn sync(&self, conn: &Connection, writer: bool) -> rusqlite::Result<u64> {
+        let state = self.state.lock();
+        let generation = self.generation();
+        let attached = Partitions::attached(&state, self.attach_limit);
+
+        let previous = {
+            let mut stmt = conn.prepare("SELECT name FROM pragma_database_list WHERE name LIKE 'p\\_%' ESCAPE '\\'")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+
+        if !writer {
+            conn.execute_batch("PRAGMA query_only = OFF;")?;
+        }
+
+        // Dropping the view drops its triggers as well
+        conn.execute_batch("DROP VIEW IF EXISTS temp.events;")?;
+
+        for alias in previous {
+            conn.execute_batch(&format!("DETACH DATABASE {};", alias))?;
+        }
+
+        let mut selects = vec![format!("SELECT {} FROM main.events", EVENT_COLUMNS)];
+
+        for partition in attached {
+            conn.execute("ATTACH DATABASE ?1 AS ?2", params![partition.path.to_string_lossy(), partition.alias()])?;
+            if writer {
+                conn.execute_batch(&format!("PRAGMA {}.synchronous = {};", partition.alias(), self.synchronous))?;
+            }
+            selects.push(format!("SELECT {} FROM {}.{}", EVENT_COLUMNS, partition.alias(), partition.table()));
+        }
+
+        conn.execute_batch(&format!("CREATE TEMP VIEW events AS {};", selects.join(" UNION ALL ")))?;
+
+        if writer {
+            let current = attached.iter().find(|partition| state.current.as_deref() == Some(partition.key.as_str()));
+
+            if let Some(current) = current {
+                conn.execute_batch(&format!(
+                    "CREATE TEMP TRIGGER events_insert INSTEAD OF INSERT ON events BEGIN
+                        SELECT RAISE(ABORT, 'FOREIGN KEY constraint failed')
+                        WHERE NOT EXISTS (SELECT 1 FROM main.sessions WHERE session_id = NEW.session_id);
+                        INSERT INTO {table} ({columns}) VALUES (
+                            NEW.id, NEW.session_id, NEW.event_name, NEW.timestamp, NEW.ip_address, NEW.params, NEW.seq
+                        );
+                    END;",
+                    table = current.table(),
+                    columns = EVENT_COLUMNS
+                ))?;
+            }
+
+            let updates = attached.iter().map(|partition| format!(
+                "UPDATE {} SET session_id = NEW.session_id, event_name = NEW.event_name, timestamp = NEW.timestamp,
+                    ip_address = NEW.ip_address, params = NEW.params, seq = NEW.seq WHERE id = OLD.id;",
+                partition.table()
+            )).collect::<String>();
+
+            let deletes = attached.iter().map(|partition| format!(
+                "DELETE FROM {} WHERE id = OLD.id;",
+                partition.table()
+            )).collect::<String>();
+
+            conn.execute_batch(&format!(
+                "CREATE TEMP TRIGGER events_update INSTEAD OF UPDATE ON events BEGIN {} END;
+                CREATE TEMP TRIGGER events_delete INSTEAD OF DELETE ON events BEGIN {} END;",
+                updates, deletes
+            ))?;
+        } else {
+            conn.execute_batch("PRAGMA query_only = ON;")?;
+        }
+
+        Ok(generation)
+    }
+
+    /// Checkpoints an attached partition so its WAL is merged before the file is moved.
+    pub fn checkpoint(&self, conn: &Connection, key: &str) -> rusqlite::Result<()> {
+        let alias = format!("p_{}", key.replace('-', "_"));
+        let attached: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_database_list WHERE name = ?1",
+            params![alias],
+            |row| row.get(0)
+        )?;
+
+        if attached {
+            conn.query_row(&format!("PRAGMA {}.wal_checkpoint(TRUNCATE)", alias), [], |_| Ok(()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes a partition from the attached set. The current partition cannot be archived.
+    pub fn detach(&self, key: &str) -> Option<Partition> {
+        let mut state = self.state.lock();
+
+        if state.current.as_deref() == Some(key) {
+            return None;
+        }
+
+        let index = state.partitions.iter().position(|partition| partition.key == key)?;
+        let partition = state.partitions.remove(index);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+
+        Some(partition)
+    }
+
+    /// Moves a detached partition into the archive directory.
+    pub fn archive(&self, partition: &Partition) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.archive_dir)?;
+
+        let file_name = partition.path.file_name().map(PathBuf::from).unwrap_or_default();
+        let target = self.archive_dir.join(&file_name);
+        move_file(&partition.path, &target)?;
+
+        // Leftovers of connections that did not shut down cleanly
+        for suffix in ["-wal", "-shm"] {
+            let mut side_file = partition.path.clone().into_os_string();
+            side_file.push(suffix);
+            let side_file = PathBuf::from(side_file);
+
+            if side_file.exists() {
+                let mut target_side_file = target.clone().into_os_string();
+                target_side_file.push(suffix);
+                move_file(&side_file, Path::new(&target_side_file))?;
+            }
+        }
+
+        log::info!("Archived event partition {} to {}", partition.key, target.display());
+        Ok(target)
+    }
+
+    pub fn archive_dir(&self) -> &Path {
+        &self.archive_dir
+    }
+
+    /// Files of all partitions that have not been archived.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.state.lock().partitions.iter().map(|partition| partition.path.clone()).collect()
+    }
+
+    /// File and table name of all partitions that have not been archived.
+    pub fn tables(&self) -> Vec<(PathBuf, String)> {
+        self.state.lock().partitions.iter().map(|partition| (partition.path.clone(), partition.table())).collect()
+    }
+
+    /// File, table name and id range of all partitions that have not been archived.
+    pub fn id_ranges(&self) -> Vec<(PathBuf, String, (i64, i64))> {
+        self.state.lock().partitions.iter().map(|partition| (partition.path.clone(), partition.table(), partition.id_range())).collect()
+    }
+
+    /// File and table name of the partitions too old to be attached, which no `events`
+    /// view covers.
+    pub fn unattached_tables(&self) -> Vec<(PathBuf, String)> {
+        let state = self.state.lock();
+        let skip = state.partitions.len().saturating_sub(self.attach_limit);
+        state.partitions[..skip].iter().map(|partition| (partition.path.clone(), partition.table())).collect()
+    }
+
+    /// Names of the partitions too old to be attached, oldest first.
+    pub fn unattached_keys(&self) -> Vec<String> {
+        let state = self.state.lock();
+        let skip = state.partitions.len().saturating_sub(self.attach_limit);
+        state.partitions[..skip].iter().map(|partition| partition.key.clone()).collect()
+    }
+
+    pub fn attached_count(&self) -> usize {
+        let state = self.state.lock();
+        Partitions::attached(&state, self.attach_limit).len()
+    }
+
+    pub fn list(&self) -> Vec<PartitionInfo> {
+        let state = self.state.lock();
+        let attached = Partitions::attached(&state, self.attach_limit);
+
+        state.partitions.iter().map(|partition| PartitionInfo {
+            name: partition.key.clone(),
+            file: partition.path.display().to_string(),
+            size: fs::metadata(&partition.path).map(|meta| meta.len()).unwrap_or(0),
+            attached: attached.iter().any(|other| other.key == partition.key),
+            current: state.current.as_deref() == Some(partition.key.as_str()),
+        }).collect()
+    }
+}
+
+fn partitioning_disabled() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse {
+        success: false,
+        message: "Partitioning is disabled".to_string()
+    })
+}
+
+/// Middleware for endpoints reading events through the `events` view. Names the partitions
+/// the response could not see in `X-RLA-Unattached-Partitions`, e.g. `2026-01,2026-02`.
+pub async fn mark_partial_reads(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let unattached = req.app_data::<web::Data<AppState>>()
+        .and_then(|data| data.db.as_ref().and_then(|db| db.partitions().map(Partitions::unattached_keys)))
+        .unwrap_or_default();
+
+    let mut res = next.call(req).await?;
+
+    if !unattached.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&unattached.join(",")) {
+            res.headers_mut().insert(HeaderName::from_static("x-rla-unattached-partitions"), value);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Lists the event partitions and whether they are attached.
+pub async fn get_partitions(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    match db.partitions() {
+        Some(partitions) => Ok(respond(&req, &partitions.list(), data.config.compress_min_response_size)),
+        None => Ok(partitioning_disabled())
+    }
+}
+
+/// Detaches a partition from all connections and moves its file into the archive directory.
+pub async fn archive_partition(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let key = path.into_inner();
+
+    let partition = match db.partitions() {
+        Some(partitions) => partitions.list().into_iter().find(|partition| partition.name == key),
+        None => return Ok(partitioning_disabled())
+    };
+
+    match partition {
+        None => return Ok(HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: format!("Unknown partition {}", key)
+        })),
+        Some(partition) if partition.current => return Ok(HttpResponse::Conflict().json(ApiResponse {
+            success: false,
+            message: "The current partition cannot be archived".to_string()
+        })),
+        Some(_) => {}
+    }
+
+    match db.archive_partition(key).await {
+        Ok(target) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: format!("Partition archived to {}", target.display())
+        })),
+        Err(e) => Ok(database_error("Partition not archived", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::app_state::{AppState, TestDir};
+    use crate::consent::Consent;
+    use crate::route_handlers::IngestEventRequest;
+    use crate::storage::NewSession;
+
+    use super::*;
+
+    const OLD_ID: i64 = 20200101_0000000001;
+
+    fn event(session_id: &str, event_name: &str) -> IngestEventRequest {
+        serde_json::from_value(serde_json::json!({ "session_id": session_id, "event_name": event_name })).unwrap()
+    }
+
+    /// A partition of an earlier day holding one event of `s1`.
+    fn old_partition(dir: &TestDir) {
+        let conn = Connection::open(dir.file("analytics.events.2020-01-01.db")).unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE events_2020_01_01 (
+                id INTEGER PRIMARY KEY AUTOINCREMENT, session_id TEXT NOT NULL, event_name TEXT NOT NULL,
+                timestamp TIMESTAMP NOT NULL, ip_address TEXT NOT NULL, params TEXT, seq INTEGER
+            );
+            INSERT INTO events_2020_01_01 VALUES ({}, 's1', 'old', 0, '', NULL, NULL);",
+            OLD_ID
+        )).unwrap();
+    }
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn routes_events_through_the_view() {
+        let dir = TestDir::new("partitions");
+        old_partition(&dir);
+
+        let data = AppState::on_sqlite(&dir, &[("DB_PARTITIONING", "day")]);
+        let db = data.db.clone().unwrap();
+
+        data.storage.create_session(NewSession {
+            session_id: "s1".to_string(),
+            user_id: "player".to_string(),
+            ip_address: String::new(),
+            device_model: None,
+            operating_system: None,
+            screen_width: None,
+            screen_height: None,
+            user_agent: None,
+            consent: Consent::all(true),
+        }).await.unwrap();
+
+        data.storage.insert_event(event("s1", "new"), String::new()).await.unwrap();
+
+        // Partitions have no foreign key, the insert trigger rejects unknown sessions
+        assert!(data.storage.insert_event(event("unknown", "new"), String::new()).await.is_err());
+        let results = data.storage.insert_events(vec![event("unknown", "a"), event("s1", "b")], String::new()).await.unwrap();
+        assert!(results[0].is_err() && results[1].is_ok());
+
+        let (tables, in_view) = db.write(|conn| {
+            let tables = attached_event_tables(conn)?;
+            let in_view: i64 = conn.query_row("SELECT COUNT(*) FROM events WHERE session_id = 's1'", [], |row| row.get(0))?;
+
+            conn.execute("UPDATE events SET event_name = 'renamed' WHERE id = ?1", [OLD_ID])?;
+            Ok((tables, in_view))
+        }).await.unwrap();
+
+        let names: Vec<&str> = tables.iter().map(|(_, table)| table.as_str()).collect();
+        let current = format!("events_{}", Utc::now().format("%Y_%m_%d"));
+        assert_eq!(names, ["events", "events_2020_01_01", current.as_str()]);
+        assert_eq!(in_view, 3);
+
+        db.write(move |conn| {
+            // New events land in the current partition, updates reach the old one
+            assert_eq!(count(conn, "main.events"), 0);
+            assert_eq!(count(conn, &current), 2);
+            let renamed: String = conn.query_row("SELECT event_name FROM events_2020_01_01 WHERE id = ?1", [OLD_ID], |row| row.get(0))?;
+            assert_eq!(renamed, "renamed");
+
+            conn.execute("DELETE FROM events WHERE session_id = 's1'", [])?;
+            assert_eq!(count(conn, "events_2020_01_01"), 0);
+            assert_eq!(count(conn, &current), 0);
+            Ok(())
+        }).await.unwrap();
+    }
+}
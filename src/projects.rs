@@ -0,0 +1,124 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use actix_web::http::Uri;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::app_state::{AppState};
+use crate::auth::{credential};
+use crate::route_handlers::{compare_key};
+
+/// The additional projects, each with its own state and storage, served under
+/// `/projects/<name>/`.
+pub struct ProjectRegistry {
+    projects: Vec<web::Data<AppState>>,
+}
+
+impl ProjectRegistry {
+    pub fn new(projects: Vec<web::Data<AppState>>) -> ProjectRegistry {
+        ProjectRegistry { projects }
+    }
+
+    pub fn projects(&self) -> &[web::Data<AppState>] {
+        &self.projects
+    }
+
+    /// Name of the project whose ingestion or secret key the request carries.
+    fn find_by_key(&self, headers: &HeaderMap) -> Option<&str> {
+        self.projects
+            .iter()
+            .find(|project| {
+                compare_key(headers.get("X-RLA-INGEST-KEY").map(HeaderValue::as_bytes), project.config.ingest_key.as_deref())
+                    || compare_key(credential(headers), project.config.secret_key.as_deref())
+            })
+            .and_then(|project| project.config.project.as_deref())
+    }
+}
+
+/// Routes requests without a project prefix to the project whose key they carry, by
+/// rewriting the path before routing. Requests matching no project stay with the
+/// default project.
+pub async fn select_project(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let project = req.app_data::<web::Data<ProjectRegistry>>()
+        .filter(|_| !req.path().starts_with("/projects/"))
+        .and_then(|registry| registry.find_by_key(req.headers()))
+        .map(str::to_string);
+
+    if let Some(project) = project {
+        let path_and_query = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(
+            format!("/projects/{}{}", project, path_and_query)
+                .parse()
+                .map_err(actix_web::error::ErrorBadRequest)?
+        );
+
+        let uri = Uri::from_parts(parts).map_err(actix_web::error::ErrorBadRequest)?;
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+    }
+
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::middleware::from_fn;
+    use actix_web::{test as web_test, App, HttpRequest, HttpResponse};
+
+    use crate::config::{Config};
+    use crate::memory_storage::{MemoryStorage};
+
+    use super::*;
+
+    fn project(name: &str, vars: &[(&str, &str)]) -> web::Data<AppState> {
+        let mut config = Config::from_pairs(vars);
+        config.project = Some(name.to_string());
+        web::Data::new(AppState::init(config, Arc::new(MemoryStorage::default()), None))
+    }
+
+    async fn echo(req: HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().body(req.uri().to_string())
+    }
+
+    #[actix_web::test]
+    async fn routes_requests_by_the_key_they_carry() {
+        let registry = web::Data::new(ProjectRegistry::new(vec![
+            project("alpha", &[("INGEST_KEY", "alpha-ingest"), ("SECRET_KEY", "alpha-secret")]),
+            project("beta", &[("SECRET_KEY", "beta-secret")]),
+        ]));
+
+        let app = web_test::init_service(App::new()
+            .wrap(from_fn(select_project))
+            .app_data(registry)
+            .default_service(web::to(echo))).await;
+
+        let cases = [
+            ("/create_session", None, "/create_session"),
+            ("/create_session?x=1", Some(("X-RLA-INGEST-KEY", "alpha-ingest")), "/projects/alpha/create_session?x=1"),
+            ("/export/events", Some(("X-RLA-KEY", "alpha-secret")), "/projects/alpha/export/events"),
+            ("/export/events", Some(("Authorization", "Bearer beta-secret")), "/projects/beta/export/events"),
+            // Keys of other projects or of the wrong kind route nowhere
+            ("/create_session", Some(("X-RLA-INGEST-KEY", "beta-secret")), "/create_session"),
+            ("/export/events", Some(("X-RLA-KEY", "alpha-ingest")), "/export/events"),
+            // An explicit prefix is kept, the project authenticates the key itself
+            ("/projects/beta/export/events", Some(("X-RLA-KEY", "alpha-secret")), "/projects/beta/export/events"),
+        ];
+
+        for (uri, header, expected) in cases {
+            let mut request = web_test::TestRequest::get().uri(uri);
+            if let Some(header) = header {
+                request = request.insert_header(header);
+            }
+
+            let body = web_test::call_and_read_body(&app, request.to_request()).await;
+            assert_eq!(body, expected.as_bytes(), "{} {:?}", uri, header);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashSet;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use rusqlite::{params, types::Value, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond, Body};
+use crate::partitions::{attached_event_tables};
+use crate::route_handlers::{database_error, now, sqlite_required, ApiResponse};
+
+#[derive(Serialize)]
+pub struct PromotedParam {
+    pub id: i64,
+    pub event_name: String,
+    pub path: String,
+    /// Name of the expression index in every events table
+    pub index: String,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct PromoteParamRequest {
+    pub event_name: String,
+    /// Dot-separated path into the event params, e.g. `floor` or `run.score`
+    pub path: String,
+}
+
+#[derive(Clone, Copy)]
+pub enum ParamOp {
+    Eq,
+    Min,
+    Max,
+}
+
+/// A `param.<path>`, `param_min.<path>` or `param_max.<path>` query filter.
+pub struct ParamFilter {
+    pub path: String,
+    pub op: ParamOp,
+    pub value: Value,
+}
+
+impl ParamFilter {
+    /// Condition comparing the param with the next bound parameter.
+    pub fn condition(&self) -> String {
+        let op = match self.op {
+            ParamOp::Eq => "=",
+            ParamOp::Min => ">=",
+            ParamOp::Max => "<=",
+        };
+        format!("{} {} ?", param_expression(&self.path), op)
+    }
+}
+
+/// Turns `floor` or `run.score` into the JSON path `$.floor` or `$.run.score`.
+/// Only ASCII letters, digits and underscores are allowed in a segment.
+pub fn json_path(path: &str) -> Option<String> {
+    let valid = path.split('.').all(|segment| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    });
+
+    valid.then(|| format!("$.{}", path))
+}
+
+/// Quotes `value` as an SQL string literal.
+pub fn sql_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// The expression the promoted indexes are built on. Queries must spell it the same
+/// way, with the path inlined, for SQLite to pick the index.
+pub fn param_expression(path: &str) -> String {
+    format!("json_extract(params, '$.{}')", path)
+}
+
+fn index_name(id: i64) -> String {
+    format!("promoted_param_{}", id)
+}
+
+fn filter_value(value: &str) -> Value {
+    if let Ok(integer) = value.parse::<i64>() {
+        return Value::Integer(integer);
+    }
+    if let Ok(real) = value.parse::<f64>() {
+        return Value::Real(real);
+    }
+
+    // `json_extract` returns JSON booleans as 1 and 0
+    match value {
+        "true" => Value::Integer(1),
+        "false" => Value::Integer(0),
+        _ => Value::Text(value.to_string())
+    }
+}
+
+/// Collects the param filters of a query string, e.g. `param.class=mage&param_min.floor=3`.
+pub fn parse_filters(query_string: &str) -> Result<Vec<ParamFilter>, String> {
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(query_string)
+        .map_err(|e| e.to_string())?
+        .into_inner();
+
+    let mut filters = Vec::new();
+
+    for (key, value) in pairs {
+        let Some((prefix, path)) = key.split_once('.') else {
+            continue;
+        };
+
+        let op = match prefix {
+            "param" => ParamOp::Eq,
+            "param_min" => ParamOp::Min,
+            "param_max" => ParamOp::Max,
+            _ => continue
+        };
+
+        if json_path(path).is_none() {
+            return Err(format!("Invalid param path {}", path));
+        }
+
+        filters.push(ParamFilter { path: path.to_string(), op, value: filter_value(&value) });
+    }
+
+    Ok(filters)
+}
+
+fn list(conn: &Connection) -> rusqlite::Result<Vec<PromotedParam>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, event_name, path, created_at FROM main.promoted_params ORDER BY id"
+    )?;
+
+    let rows = stmt.query_map([], |row| {
+        let id = row.get(0)?;
+        Ok(PromotedParam {
+            id,
+            event_name: row.get(1)?,
+            path: row.get(2)?,
+            index: index_name(id),
+            created_at: row.get(3)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+/// Creates the missing indexes of `promoted` in one events table and drops those of
+/// params that are no longer promoted.
+fn sync_table_indexes(conn: &Connection, schema: &str, table: &str, promoted: &[PromotedParam]) -> rusqlite::Result<()> {
+    let wanted: HashSet<&str> = promoted.iter().map(|param| param.index.as_str()).collect();
+
+    let existing = {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT name FROM {}.sqlite_master WHERE type = 'index' AND name LIKE 'promoted\\_param\\_%' ESCAPE '\\'",
+            schema
+        ))?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<rusqlite::Result<Vec<String>>>()?
+    };
+
+    for name in existing.iter().filter(|name| !wanted.contains(name.as_str())) {
+        conn.execute_batch(&format!("DROP INDEX IF EXISTS {}.{};", schema, name))?;
+    }
+
+    // Partial indexes only hold the events they are declared for
+    for param in promoted.iter() {
+        conn.execute_batch(&format!(
+            "CREATE INDEX IF NOT EXISTS {}.{} ON {} ({}) WHERE event_name = {};",
+            schema,
+            param.index,
+            table,
+            param_expression(&param.path),
+            sql_literal(&param.event_name)
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Creates the missing indexes of promoted params in `main.events` and every attached
+/// partition, and drops those of params that are no longer promoted.
+pub fn sync_promoted_indexes(conn: &Connection) -> rusqlite::Result<()> {
+    let promoted = list(conn)?;
+
+    for (schema, table) in attached_event_tables(conn)? {
+        sync_table_indexes(conn, &schema, &table, &promoted)?;
+    }
+
+    Ok(())
+}
+
+/// Lists the promoted params.
+pub async fn get_promoted_params(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    match db.read(list).await {
+        Ok(promoted) => Ok(respond(&req, &promoted, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Failed to list promoted params", e))
+    }
+}
+
+/// Promotes a params path of one event name and indexes it right away.
+pub async fn promote_param(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+    payload: Body<PromoteParamRequest>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let request = payload.into_inner();
+
+    if request.event_name.is_empty() || json_path(&request.path).is_none() {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: "Expected an event name and a dot-separated path of letters, digits and underscores".to_string()
+        }));
+    }
+
+    let result = db.write(move |conn| {
+        let tx = conn.transaction()?;
+
+        let exists = tx.query_row(
+            "SELECT 1 FROM main.promoted_params WHERE event_name = ?1 AND path = ?2",
+            params![request.event_name, request.path],
+            |_| Ok(())
+        ).optional()?;
+
+        if exists.is_some() {
+            return Ok(None);
+        }
+
+        tx.execute(
+            "INSERT INTO main.promoted_params (event_name, path, created_at) VALUES (?1, ?2, ?3)",
+            params![request.event_name, request.path, now()],
+        )?;
+        let id = tx.last_insert_rowid();
+
+        tx.commit()?;
+        Ok(Some(id))
+    }).await;
+
+    let id = match result {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return Ok(HttpResponse::Conflict().json(ApiResponse {
+                success: false,
+                message: "Param is already promoted".to_string()
+            }));
+        },
+        Err(e) => return Ok(database_error("Param not promoted", e))
+    };
+
+    let tables = match db.write(|conn| attached_event_tables(conn)).await {
+        Ok(tables) => tables,
+        Err(e) => return Ok(database_error("Param promoted, its indexes are built on the next start", e))
+    };
+
+    // Building an index reads every event of the table, which may take longer than
+    // `DB_TASK_TIMEOUT`. Each table gets its own write so ingestion goes on in between.
+    for (schema, table) in tables {
+        let built = db.write_unbounded(move |conn| {
+            let promoted = list(conn)?;
+            sync_table_indexes(conn, &schema, &table, &promoted)
+        }).await;
+
+        if let Err(e) = built {
+            return Ok(database_error("Param promoted, its remaining indexes are built on the next start", e));
+        }
+    }
+
+    Ok(HttpResponse::Created().json(ApiResponse {
+        success: true,
+        message: format!("Param promoted with index {}", index_name(id))
+    }))
+}
+
+/// Demotes a param and drops its indexes.
+pub async fn demote_param(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let id = path.into_inner();
+
+    let result = db.write(move |conn| {
+        let tx = conn.transaction()?;
+
+        let deleted = tx.execute("DELETE FROM main.promoted_params WHERE id = ?1", params![id])?;
+        if deleted > 0 {
+            sync_promoted_indexes(&tx)?;
+        }
+
+        tx.commit()?;
+        Ok(deleted > 0)
+    }).await;
+
+    match result {
+        Ok(true) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: format!("Param demoted, index {} dropped", index_name(id))
+        })),
+        Ok(false) => Ok(HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: format!("Unknown promoted param {}", id)
+        })),
+        Err(e) => Ok(database_error("Param not demoted", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_plain_json_paths() {
+        assert_eq!(json_path("floor").as_deref(), Some("$.floor"));
+        assert_eq!(json_path("run.score_2").as_deref(), Some("$.run.score_2"));
+
+        for path in ["", ".floor", "floor.", "run..score", "run-score", "floor'", "floor)--", "a b", "stufe_ü", "$.floor"] {
+            assert_eq!(json_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn quotes_sql_literals() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        for value in ["mage", "it's", "'", "''", "'); DROP TABLE events; --", ""] {
+            let literal = sql_literal(value);
+            let read: String = conn.query_row(&format!("SELECT {}", literal), [], |row| row.get(0)).unwrap();
+            assert_eq!(read, value);
+        }
+
+        assert_eq!(sql_literal("it's"), "'it''s'");
+    }
+
+    #[test]
+    fn parses_param_filters() {
+        let filters = parse_filters("param.class=mage&param_min.floor=3&param_max.run.score=1.5&param.won=true&event_name=run_end").unwrap();
+
+        let parsed: Vec<(String, String)> = filters.iter().map(|filter| (filter.path.clone(), filter.condition())).collect();
+        assert_eq!(parsed, [
+            ("class".to_string(), "json_extract(params, '$.class') = ?".to_string()),
+            ("floor".to_string(), "json_extract(params, '$.floor') >= ?".to_string()),
+            ("run.score".to_string(), "json_extract(params, '$.run.score') <= ?".to_string()),
+            ("won".to_string(), "json_extract(params, '$.won') = ?".to_string()),
+        ]);
+
+        let values: Vec<&Value> = filters.iter().map(|filter| &filter.value).collect();
+        assert_eq!(values, [&Value::Text("mage".to_string()), &Value::Integer(3), &Value::Real(1.5), &Value::Integer(1)]);
+
+        assert!(parse_filters("param.floor')%20OR%20('1=1").is_err());
+    }
+
+    #[test]
+    fn filter_conditions_use_the_promoted_index() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE events (id INTEGER PRIMARY KEY, event_name TEXT NOT NULL, params TEXT);").unwrap();
+
+        let promoted = [PromotedParam { id: 1, event_name: "run_start".to_string(), path: "floor".to_string(), index: index_name(1), created_at: 0 }];
+        sync_table_indexes(&conn, "main", "events", &promoted).unwrap();
+
+        let filter = &parse_filters("param_min.floor=3").unwrap()[0];
+        let plan: String = conn.query_row(
+            &format!("EXPLAIN QUERY PLAN SELECT id FROM events WHERE event_name = {} AND {}", sql_literal("run_start"), filter.condition()),
+            [3],
+            |row| row.get(3),
+        ).unwrap();
+        assert!(plan.contains("promoted_param_1"), "{}", plan);
+
+        // Demoted params lose their index
+        sync_table_indexes(&conn, "main", "events", &[]).unwrap();
+        let indexes: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index'", [], |row| row.get(0)).unwrap();
+        assert_eq!(indexes, 0);
+    }
+}
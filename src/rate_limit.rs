@@ -13,6 +13,39 @@ pub struct RateLimitInfo {
     pub last_access: Instant,
 }
 
+impl RateLimitInfo {
+    pub fn new(max_tokens: u64, now: Instant) -> RateLimitInfo {
+        RateLimitInfo {
+            tokens: max_tokens,
+            last_refill: now,
+            last_access: now,
+        }
+    }
+
+    /// Refills the bucket at `rate_limit` tokens per second and consumes `cost` tokens
+    /// if enough are available.
+    pub fn try_consume(&mut self, now: Instant, rate_limit: u64, max_tokens: u64, cost: u64) -> bool {
+        // Refill tokens
+        let elapsed_secs = now.duration_since(self.last_refill).as_secs();
+        if elapsed_secs >= 1 {
+            let refill_tokens = elapsed_secs * rate_limit;
+            self.tokens = (self.tokens + refill_tokens).min(max_tokens);
+            self.last_refill = now;
+        }
+
+        self.last_access = now;
+
+        if self.tokens >= cost {
+            // Consume tokens and allow the request
+            self.tokens -= cost;
+            true
+        } else {
+            // Rate limit exceeded
+            false
+        }
+    }
+}
+
 pub fn check_rate_limit(state: &AppState, ip: &str, cost: u64) -> bool {
     let mut rate_limiter = state.rate_limiter.lock();
 
@@ -21,30 +54,61 @@ pub fn check_rate_limit(state: &AppState, ip: &str, cost: u64) -> bool {
     let token_bucket_size = state.config.token_bucket_size;
     let max_tokens = rate_limit * token_bucket_size;
 
-    let entry = rate_limiter.entry(ip.to_string()).or_insert_with(|| RateLimitInfo {
-        tokens: max_tokens,
-        last_refill: now,
-        last_access: now,
-    });
-
-    // Refill tokens
-    let elapsed_secs = now.duration_since(entry.last_refill).as_secs();
-    if elapsed_secs >= 1 {
-        let refill_tokens = elapsed_secs * rate_limit;
-        entry.tokens = (entry.tokens + refill_tokens).min(max_tokens);
-        entry.last_refill = now;
+    rate_limiter.entry(ip.to_string())
+        .or_insert_with(|| RateLimitInfo::new(max_tokens, now))
+        .try_consume(now, rate_limit, max_tokens, cost)
+}
+
+/// Failed authentication attempts of one IP address within `AUTH_FAILURE_WINDOW`.
+pub struct AuthFailureInfo {
+    pub failures: u64,
+    pub window_start: Instant,
+}
+
+/// Seconds until `ip` may authenticate again, `None` if it has not exceeded
+/// `AUTH_FAILURE_LIMIT`.
+pub fn auth_lockout(state: &AppState, ip: &str) -> Option<u64> {
+    let limit = state.config.auth_failure_limit;
+    if limit == 0 {
+        return None;
     }
 
-    entry.last_access = now;
+    let window = Duration::from_secs(state.config.auth_failure_window);
+    let auth_failures = state.auth_failures.lock();
+
+    auth_failures.get(ip)
+        .filter(|entry| entry.failures >= limit)
+        .and_then(|entry| window.checked_sub(entry.window_start.elapsed()))
+        .map(|remaining| remaining.as_secs().max(1))
+}
 
-    if entry.tokens >= cost {
-        // Consume tokens and allow the request
-        entry.tokens -= cost;
-        true
-    } else {
-        // Rate limit exceeded
-        false
+pub fn record_auth_failure(state: &AppState, ip: &str) {
+    if state.config.auth_failure_limit == 0 {
+        return;
     }
+
+    let now = Instant::now();
+    let window = Duration::from_secs(state.config.auth_failure_window);
+    let mut auth_failures = state.auth_failures.lock();
+
+    let entry = auth_failures.entry(ip.to_string())
+        .or_insert(AuthFailureInfo { failures: 0, window_start: now });
+
+    // Fixed windows, a locked out address starts over once its window has passed
+    if now.duration_since(entry.window_start) > window {
+        entry.failures = 0;
+        entry.window_start = now;
+    }
+
+    entry.failures += 1;
+}
+
+pub fn cleanup_auth_failures(
+    auth_failures: &Arc<Mutex<HashMap<String, AuthFailureInfo>>>,
+    config: &Arc<Config>,
+) {
+    let window = Duration::from_secs(config.auth_failure_window);
+    auth_failures.lock().retain(|_, entry| entry.window_start.elapsed() <= window);
 }
 
 pub fn cleanup_rate_limiter(
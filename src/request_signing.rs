@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use chrono::Utc;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use sha2::Sha256;
+
+use crate::app_state::{AppState};
+use crate::config::{Config};
+use crate::route_handlers::{get_request_id, ApiResponse};
+
+/// Nonces of accepted requests and the unix time in seconds they may be forgotten at.
+pub type NonceCache = Arc<Mutex<HashMap<String, i64>>>;
+
+const MAX_NONCE_LENGTH: usize = 128;
+
+pub fn signing_enabled(config: &Config) -> bool {
+    !config.ingest_signing_keys.is_empty()
+}
+
+fn decode_hex(value: &[u8]) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+
+    value
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+fn rejected(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized().json(ApiResponse {
+        success: false,
+        message: message.to_string()
+    })
+}
+
+/// Checks the signature headers of a request against its body:
+///
+/// - `X-RLA-BUILD`: id of the build whose key signed the request
+/// - `X-RLA-TIMESTAMP`: unix time in seconds, at most `INGEST_SIGNATURE_WINDOW` off
+/// - `X-RLA-NONCE`: unique per request, up to 128 characters
+/// - `X-RLA-SIGNATURE`: hex HMAC-SHA256 of `<timestamp>\n<nonce>\n<body>`
+///
+/// The nonce is only remembered once everything else checked out.
+fn verify(req: &ServiceRequest, data: &AppState, body: &[u8]) -> Result<(), &'static str> {
+    let header = |name: &str| req.headers().get(name).map(|value| value.as_bytes());
+
+    let (Some(build), Some(timestamp), Some(nonce), Some(signature)) = (
+        header("X-RLA-BUILD"),
+        header("X-RLA-TIMESTAMP"),
+        header("X-RLA-NONCE"),
+        header("X-RLA-SIGNATURE"),
+    ) else {
+        return Err("Missing request signature");
+    };
+
+    let Some((_, key)) = data.config.ingest_signing_keys.iter().find(|(id, _)| id.as_bytes() == build) else {
+        return Err("Unknown build");
+    };
+
+    let now = Utc::now().timestamp();
+    let window = data.config.ingest_signature_window;
+    let timestamp = std::str::from_utf8(timestamp).ok()
+        .and_then(|timestamp| timestamp.parse::<i64>().ok())
+        // Timestamps this far off do not fit the difference, they are outside of any window
+        .filter(|timestamp| now.checked_sub(*timestamp).map(i64::unsigned_abs).is_some_and(|offset| offset <= window))
+        .ok_or("Request timestamp outside of the allowed window")?;
+
+    let nonce = std::str::from_utf8(nonce).ok()
+        .filter(|nonce| !nonce.is_empty() && nonce.len() <= MAX_NONCE_LENGTH)
+        .ok_or("Invalid request nonce")?;
+
+    let signature = decode_hex(signature).ok_or("Invalid request signature")?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}\n", timestamp, nonce).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature).map_err(|_| "Invalid request signature")?;
+
+    // A replay has to reuse the timestamp, so the nonce only needs to be kept until
+    // the timestamp falls out of the window
+    let mut nonces = data.nonces.lock();
+    if nonces.contains_key(nonce) {
+        return Err("Request was already received");
+    }
+    nonces.insert(nonce.to_string(), timestamp.saturating_add_unsigned(window));
+
+    Ok(())
+}
+
+/// Buffers up to `limit` bytes of the body to verify the signature over it, then
+/// hands the body on. `None` leaves the payload alone and verifies an empty body.
+async fn verify_signature(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+    limit: Option<fn(&Config) -> usize>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(data) = req.app_data::<web::Data<AppState>>().cloned().filter(|data| signing_enabled(&data.config)) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    let mut body = web::BytesMut::new();
+
+    if let Some(limit) = limit {
+        let limit = limit(&data.config);
+        let mut payload = req.take_payload();
+
+        while let Some(chunk) = payload.next().await {
+            body.extend_from_slice(&chunk?);
+
+            if body.len() > limit {
+                let response = HttpResponse::PayloadTooLarge().json(ApiResponse {
+                    success: false,
+                    message: format!("Payload too large. Maximum size allowed is {} bytes", limit)
+                });
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        }
+    }
+
+    if let Err(message) = verify(&req, &data, &body) {
+        let ip = get_request_id(req.request(), &data).unwrap_or("unknown".to_string());
+        log::warn!("Rejected unsigned ingestion from {} for {}: {}", ip, req.path(), message);
+        return Ok(req.into_response(rejected(message)).map_into_right_body());
+    }
+
+    if limit.is_some() {
+        req.set_payload(body.freeze().into());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Middleware requiring signed `/create_session`, `/ingest_event` and `/update_consent` requests if the
+/// project has `INGEST_SIGNING_KEYS`.
+pub async fn require_signed_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    verify_signature(req, next, Some(|config| config.max_json_payload.max(config.max_compressed_payload))).await
+}
+
+/// Like `require_signed_request` for `/ingest_events`, whose body is buffered in full.
+pub async fn require_signed_stream(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    verify_signature(req, next, Some(|config| config.max_stream_payload)).await
+}
+
+/// Like `require_signed_request` for WebSocket upgrades, signed over an empty body.
+/// Frames on the established connection are not signed.
+pub async fn require_signed_upgrade(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    verify_signature(req, next, None).await
+}
+
+/// Forgets the nonces whose timestamps have left the window.
+pub fn cleanup_nonces(nonces: &NonceCache) {
+    let now = Utc::now().timestamp();
+    nonces.lock().retain(|_, expires_at| *expires_at >= now);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const KEY: &str = "k3y";
+
+    fn request(timestamp: &str, nonce: &str, body: &[u8], key: &str) -> ServiceRequest {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(format!("{}\n{}\n", timestamp, nonce).as_bytes());
+        mac.update(body);
+        let signature = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+
+        TestRequest::post()
+            .insert_header(("X-RLA-BUILD", "1.4.0"))
+            .insert_header(("X-RLA-TIMESTAMP", timestamp))
+            .insert_header(("X-RLA-NONCE", nonce))
+            .insert_header(("X-RLA-SIGNATURE", signature))
+            .to_srv_request()
+    }
+
+    fn state() -> web::Data<AppState> {
+        AppState::in_memory(&[("STORAGE_BACKEND", "memory"), ("INGEST_SIGNING_KEYS", "1.4.0=k3y"), ("INGEST_SIGNATURE_WINDOW", "300")])
+    }
+
+    #[actix_web::test]
+    async fn accepts_signed_requests_once() {
+        let data = state();
+        let now = Utc::now().timestamp().to_string();
+
+        assert_eq!(verify(&request(&now, "n1", b"{}", KEY), &data, b"{}"), Ok(()));
+        assert_eq!(verify(&request(&now, "n1", b"{}", KEY), &data, b"{}"), Err("Request was already received"));
+        assert_eq!(verify(&request(&now, "n2", b"{}", KEY), &data, b"{}"), Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn rejects_bad_signatures() {
+        let data = state();
+        let now = Utc::now().timestamp().to_string();
+
+        assert_eq!(verify(&request(&now, "n1", b"{}", "other"), &data, b"{}"), Err("Invalid request signature"));
+        assert_eq!(verify(&request(&now, "n2", b"{}", KEY), &data, b"{\"x\":1}"), Err("Invalid request signature"));
+        assert_eq!(verify(&TestRequest::post().to_srv_request(), &data, b""), Err("Missing request signature"));
+
+        // The rejected nonces were not remembered
+        assert_eq!(verify(&request(&now, "n1", b"{}", KEY), &data, b"{}"), Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn rejects_timestamps_outside_of_the_window() {
+        let data = state();
+        let now = Utc::now().timestamp();
+        let outside = "Request timestamp outside of the allowed window";
+
+        for timestamp in [now - 301, now + 301, i64::MIN, i64::MAX] {
+            let timestamp = timestamp.to_string();
+            assert_eq!(verify(&request(&timestamp, "n1", b"", KEY), &data, b""), Err(outside));
+        }
+        assert_eq!(verify(&request("soon", "n1", b"", KEY), &data, b""), Err(outside));
+    }
+}
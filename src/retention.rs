@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use serde::Serialize;
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond};
+use crate::config::{Config};
+use crate::db_pool::{DbError, DbPool};
+use crate::route_handlers::{now, sqlite_required};
+
+const DAY_MILLIS: i64 = 86_400_000;
+
+/// Pause between two delete batches, so ingestion gets the writer in between.
+const BATCH_PAUSE: Duration = Duration::from_millis(10);
+
+/// Number of free pages released per `incremental_vacuum` step.
+const VACUUM_STEP_PAGES: i64 = 1000;
+
+#[derive(Serialize, Clone)]
+pub struct PurgedRule {
+    /// `None` for the default event retention, which skips overridden event names.
+    event_name: Option<String>,
+    retention_days: u64,
+    cutoff: i64,
+    deleted: u64,
+}
+
+/// Outcome of a single retention run. Counts stay valid if the run failed halfway.
+#[derive(Serialize, Clone, Default)]
+pub struct RetentionReport {
+    started_at: i64,
+    finished_at: i64,
+    rules: Vec<PurgedRule>,
+    events_deleted: u64,
+    sessions_deleted: u64,
+    incremental_vacuum: bool,
+    pages_vacuumed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RetentionStatus<'a> {
+    enabled: bool,
+    events_days: Option<u64>,
+    sessions_days: Option<u64>,
+    event_overrides: BTreeMap<&'a str, u64>,
+    interval: u64,
+    last_run: Option<RetentionReport>,
+}
+
+pub fn retention_enabled(config: &Config) -> bool {
+    config.retention_events_days.is_some()
+        || config.retention_sessions_days.is_some()
+        || !config.retention_event_overrides.is_empty()
+}
+
+fn cutoff(days: u64) -> i64 {
+    now() - days as i64 * DAY_MILLIS
+}
+
+/// Runs `sql` until it deletes less than a full batch. The last parameter is the batch size.
+pub async fn delete_in_batches(db: &Arc<DbPool>, sql: String, mut params: Vec<Value>, batch_size: usize) -> Result<u64, DbError> {
+    params.push(Value::Integer(batch_size as i64));
+
+    let sql = Arc::new(sql);
+    let params = Arc::new(params);
+    let mut deleted = 0;
+
+    loop {
+        let (sql, params) = (Arc::clone(&sql), Arc::clone(&params));
+        let count = db.write(move |conn| {
+            // Deletes routed through the partition view only show up in the total
+            let before = conn.total_changes();
+            conn.execute(&sql, params_from_iter(params.iter()))?;
+            Ok(conn.total_changes() - before)
+        }).await?;
+
+        deleted += count;
+        if count < batch_size as u64 {
+            return Ok(deleted);
+        }
+
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+}
+
+/// Deletes from every events table, see `DbPool::event_tables`.
+pub async fn delete_events_in_batches(db: &Arc<DbPool>, sql: &str, params: Vec<Value>, batch_size: usize) -> Result<u64, DbError> {
+    let mut deleted = 0;
+
+    for table in db.event_tables() {
+        deleted += delete_in_batches(db, sql.replace("{events}", table), params.clone(), batch_size).await?;
+    }
+
+    Ok(deleted)
+}
+
+async fn purge_events(db: &Arc<DbPool>, config: &Config, report: &mut RetentionReport) -> Result<(), DbError> {
+    let batch_size = config.retention_batch_size.max(1);
+
+    for (event_name, days) in config.retention_event_overrides.iter() {
+        let cutoff = cutoff(*days);
+        let deleted = delete_events_in_batches(
+            db,
+            "DELETE FROM {events} WHERE id IN (
+                SELECT id FROM {events} WHERE event_name = ?1 AND timestamp < ?2 LIMIT ?3
+            )",
+            vec![Value::Text(event_name.clone()), Value::Integer(cutoff)],
+            batch_size,
+        ).await?;
+
+        report.events_deleted += deleted;
+        report.rules.push(PurgedRule { event_name: Some(event_name.clone()), retention_days: *days, cutoff, deleted });
+    }
+
+    if let Some(days) = config.retention_events_days {
+        let cutoff = cutoff(days);
+        let overridden = config.retention_event_overrides.len();
+
+        // ?1 is the cutoff, ?2..=?n+1 the overridden event names and ?n+2 the batch size
+        let excluded = (0..overridden).map(|i| format!("?{}", i + 2)).collect::<Vec<_>>().join(", ");
+        let sql = format!(
+            "DELETE FROM {{events}} WHERE id IN (
+                SELECT id FROM {{events}} WHERE timestamp < ?1 AND event_name NOT IN ({}) LIMIT ?{}
+            )",
+            excluded,
+            overridden + 2
+        );
+
+        let mut params = vec![Value::Integer(cutoff)];
+        params.extend(config.retention_event_overrides.iter().map(|(name, _)| Value::Text(name.clone())));
+
+        let deleted = delete_events_in_batches(db, &sql, params, batch_size).await?;
+
+        report.events_deleted += deleted;
+        report.rules.push(PurgedRule { event_name: None, retention_days: days, cutoff, deleted });
+    }
+
+    Ok(())
+}
+
+/// Deletes expired sessions. Sessions that still have events are kept until those expire.
+async fn purge_sessions(db: &Arc<DbPool>, config: &Config, report: &mut RetentionReport) -> Result<(), DbError> {
+    if let Some(days) = config.retention_sessions_days {
+        report.sessions_deleted += delete_in_batches(
+            db,
+            "DELETE FROM sessions WHERE session_id IN (
+                SELECT session_id FROM sessions
+                WHERE start_date < ?1
+                AND NOT EXISTS (SELECT 1 FROM events WHERE events.session_id = sessions.session_id)
+                LIMIT ?2
+            )".to_string(),
+            vec![Value::Integer(cutoff(days))],
+            config.retention_batch_size.max(1),
+        ).await?;
+    }
+
+    Ok(())
+}
+
+fn pragma_value(conn: &Connection, pragma: &str) -> rusqlite::Result<i64> {
+    conn.query_row(&format!("PRAGMA {}", pragma), [], |row| row.get(0))
+}
+
+/// Releases free pages in steps. Returns `None` if the database does not use incremental auto vacuum.
+pub async fn incremental_vacuum(db: &Arc<DbPool>) -> Result<Option<u64>, DbError> {
+    let mut vacuumed = 0;
+
+    loop {
+        let released = db.write(|conn| {
+            if pragma_value(conn, "auto_vacuum")? != 2 {
+                return Ok(None);
+            }
+
+            let before = pragma_value(conn, "freelist_count")?;
+            if before == 0 {
+                return Ok(Some(0));
+            }
+
+            let mut stmt = conn.prepare(&format!("PRAGMA incremental_vacuum({})", VACUUM_STEP_PAGES))?;
+            let mut rows = stmt.query([])?;
+            while rows.next()?.is_some() {}
+
+            Ok(Some((before - pragma_value(conn, "freelist_count")?).max(0) as u64))
+        }).await?;
+
+        match released {
+            None => return Ok(None),
+            Some(0) => return Ok(Some(vacuumed)),
+            Some(pages) => vacuumed += pages,
+        }
+
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+}
+
+/// Applies the configured retention policy once.
+pub async fn purge(db: &Arc<DbPool>, config: &Config) -> RetentionReport {
+    let mut report = RetentionReport { started_at: now(), ..Default::default() };
+
+    let result = async {
+        // Expired rows of unattached partitions would be missed and sessions whose events
+        // are only stored there deleted
+        db.require_all_partitions_attached("Retention")?;
+
+        purge_events(db, config, &mut report).await?;
+        purge_sessions(db, config, &mut report).await?;
+
+        if report.events_deleted + report.sessions_deleted > 0 {
+            match incremental_vacuum(db).await? {
+                Some(pages) => {
+                    report.incremental_vacuum = true;
+                    report.pages_vacuumed = pages;
+                },
+                None => log::info!("Database does not use incremental auto vacuum, run `PRAGMA auto_vacuum = INCREMENTAL; VACUUM;` once to release purged pages")
+            }
+        }
+
+        Ok::<(), DbError>(())
+    }.await;
+
+    report.finished_at = now();
+
+    match result {
+        Ok(_) => log::info!(
+            "Retention purged {} events and {} sessions, vacuumed {} pages",
+            report.events_deleted, report.sessions_deleted, report.pages_vacuumed
+        ),
+        Err(e) => {
+            log::error!("Retention purge failed: {}", e);
+            report.error = Some(e.to_string());
+        }
+    }
+
+    report
+}
+
+/// Shows the retention policy and the report of the last purge.
+pub async fn get_retention(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+) -> Result<HttpResponse, Error> {
+    if data.db.is_none() {
+        return Ok(sqlite_required());
+    }
+
+    let status = RetentionStatus {
+        enabled: retention_enabled(&data.config),
+        events_days: data.config.retention_events_days,
+        sessions_days: data.config.retention_sessions_days,
+        event_overrides: data.config.retention_event_overrides.iter().map(|(name, days)| (name.as_str(), *days)).collect(),
+        interval: data.config.retention_interval,
+        last_run: data.retention_report.lock().clone(),
+    };
+
+    Ok(respond(&req, &status, data.config.compress_min_response_size))
+}
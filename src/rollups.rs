@@ -0,0 +1,584 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond};
+use crate::config::{Config};
+use crate::db_pool::{DbError, DbPool};
+use crate::hyperloglog::{HyperLogLog};
+use crate::route_handlers::{database_error, sqlite_required, ApiResponse};
+
+/// Pause between two compaction batches, so ingestion gets the writer in between.
+const BATCH_PAUSE: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Granularity {
+    Minute,
+    Hour,
+    Day,
+}
+
+/// Granularities maintained in the rollup tables.
+const ROLLED_UP: [Granularity; 2] = [Granularity::Hour, Granularity::Day];
+
+impl Granularity {
+    fn parse(value: Option<&str>) -> Option<Granularity> {
+        match value.unwrap_or("hour") {
+            "minute" => Some(Granularity::Minute),
+            "hour" => Some(Granularity::Hour),
+            "day" => Some(Granularity::Day),
+            _ => None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    fn millis(&self) -> i64 {
+        match self {
+            Granularity::Minute => 60_000,
+            Granularity::Hour => 3_600_000,
+            Granularity::Day => 86_400_000,
+        }
+    }
+
+    /// Start of the UTC bucket containing `timestamp`.
+    fn bucket(&self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.millis())
+    }
+
+    /// SQL computing `bucket` of `column`, also for timestamps before the epoch.
+    fn bucket_sql(&self, column: &str) -> String {
+        format!("{column} - (({column} % {ms}) + {ms}) % {ms}", column = column, ms = self.millis())
+    }
+}
+
+pub fn rollups_enabled(config: &Config) -> bool {
+    config.rollup_interval > 0
+}
+
+/// Id of the last event, or start date and rowid of the last session, folded into the
+/// rollups.
+fn stored_watermark(conn: &Connection, source: &str) -> rusqlite::Result<Option<i64>> {
+    conn.query_row(
+        "SELECT last_id FROM rollup_watermarks WHERE source = ?1",
+        params![source],
+        |row| row.get(0)
+    )
+    .optional()
+}
+
+fn watermark(conn: &Connection, source: &str) -> rusqlite::Result<i64> {
+    stored_watermark(conn, source).map(|last_id| last_id.unwrap_or(0))
+}
+
+fn set_watermark(conn: &Connection, source: &str, last_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO rollup_watermarks (source, last_id) VALUES (?1, ?2)
+        ON CONFLICT (source) DO UPDATE SET last_id = excluded.last_id",
+        params![source, last_id],
+    )?;
+    Ok(())
+}
+
+/// Folds the next batch of events into the rollups. Event ids only grow, also across
+/// partitions, so everything above the watermark is new.
+fn compact_events(conn: &mut Connection, batch_size: usize) -> rusqlite::Result<usize> {
+    let tx = conn.transaction()?;
+    let last_id = watermark(&tx, "events")?;
+
+    let rows = {
+        let mut stmt = tx.prepare_cached(
+            "SELECT id, event_name, timestamp FROM events WHERE id > ?1 ORDER BY id LIMIT ?2"
+        )?;
+        let rows = stmt.query_map(params![last_id, batch_size as i64], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?))
+        })?;
+        rows.collect::<rusqlite::Result<Vec<(i64, String, i64)>>>()?
+    };
+
+    let Some(max_id) = rows.last().map(|row| row.0) else {
+        return Ok(0);
+    };
+
+    let mut counts: HashMap<(Granularity, i64, &str), i64> = HashMap::new();
+    for (_, event_name, timestamp) in rows.iter() {
+        for granularity in ROLLED_UP {
+            *counts.entry((granularity, granularity.bucket(*timestamp), event_name.as_str())).or_default() += 1;
+        }
+    }
+
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO rollup_events (granularity, bucket, event_name, events) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (granularity, bucket, event_name) DO UPDATE SET events = events + excluded.events"
+        )?;
+        for ((granularity, bucket, event_name), events) in counts {
+            stmt.execute(params![granularity.name(), bucket, event_name, events])?;
+        }
+    }
+
+    set_watermark(&tx, "events", max_id)?;
+    tx.commit()?;
+
+    Ok(rows.len())
+}
+
+/// Start date and rowid of the last session folded into the rollups. Rowids of deleted
+/// sessions are reused, start dates only grow.
+fn session_watermark(conn: &Connection) -> rusqlite::Result<(i64, i64)> {
+    let start_date = stored_watermark(conn, "session_start_date")?.unwrap_or(i64::MIN);
+    Ok((start_date, watermark(conn, "sessions")?))
+}
+
+/// Folds the next batch of sessions into the rollups, merging their users into the
+/// stored sketches.
+fn compact_sessions(conn: &mut Connection, batch_size: usize) -> rusqlite::Result<usize> {
+    let tx = conn.transaction()?;
+    let (last_start_date, last_rowid) = session_watermark(&tx)?;
+
+    let rows = {
+        let mut stmt = tx.prepare_cached(
+            "SELECT rowid, user_id, start_date FROM sessions WHERE (start_date, rowid) > (?1, ?2)
+            ORDER BY start_date, rowid LIMIT ?3"
+        )?;
+        let rows = stmt.query_map(params![last_start_date, last_rowid, batch_size as i64], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, i64>(2)?))
+        })?;
+        rows.collect::<rusqlite::Result<Vec<(i64, Option<String>, i64)>>>()?
+    };
+
+    let Some((max_rowid, _, max_start_date)) = rows.last().cloned() else {
+        return Ok(0);
+    };
+
+    let mut groups: HashMap<(Granularity, i64), (i64, HyperLogLog)> = HashMap::new();
+    for (_, user_id, start_date) in rows.iter() {
+        for granularity in ROLLED_UP {
+            let (sessions, users) = groups.entry((granularity, granularity.bucket(*start_date))).or_default();
+            *sessions += 1;
+            if let Some(user_id) = user_id {
+                users.add(user_id);
+            }
+        }
+    }
+
+    for ((granularity, bucket), (sessions, mut users)) in groups {
+        let stored: Option<Vec<u8>> = tx.query_row(
+            "SELECT users FROM rollup_sessions WHERE granularity = ?1 AND bucket = ?2",
+            params![granularity.name(), bucket],
+            |row| row.get(0)
+        ).optional()?;
+
+        if let Some(stored) = stored.as_deref().and_then(HyperLogLog::from_bytes) {
+            users.merge(&stored);
+        }
+
+        tx.execute(
+            "INSERT INTO rollup_sessions (granularity, bucket, sessions, users) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (granularity, bucket) DO UPDATE SET sessions = sessions + excluded.sessions, users = excluded.users",
+            params![granularity.name(), bucket, sessions, users.to_bytes()],
+        )?;
+    }
+
+    set_watermark(&tx, "session_start_date", max_start_date)?;
+    set_watermark(&tx, "sessions", max_rowid)?;
+    tx.commit()?;
+
+    Ok(rows.len())
+}
+
+/// Folds all events and sessions stored since the last run into the rollups, one
+/// batch per write. Returns the number of events and sessions folded.
+pub async fn compact(db: &Arc<DbPool>, config: &Config) -> Result<(u64, u64), DbError> {
+    let batch_size = config.rollup_batch_size.max(1);
+    let mut events = 0;
+    let mut sessions = 0;
+
+    loop {
+        let folded = db.write(move |conn| compact_events(conn, batch_size)).await?;
+        events += folded as u64;
+        if folded < batch_size {
+            break;
+        }
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+
+    loop {
+        let folded = db.write(move |conn| compact_sessions(conn, batch_size)).await?;
+        sessions += folded as u64;
+        if folded < batch_size {
+            break;
+        }
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+
+    Ok((events, sessions))
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    /// `minute`, `hour` or `day`
+    granularity: Option<String>,
+    /// Inclusive lower bound in milliseconds since the epoch
+    from: Option<i64>,
+    /// Exclusive upper bound in milliseconds since the epoch
+    to: Option<i64>,
+    /// Comma-separated event names
+    event_name: Option<String>,
+}
+
+impl StatsQuery {
+    fn event_names(&self) -> Vec<String> {
+        self.event_name
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    /// Conditions limiting `column` to the requested time range.
+    fn range(&self, column: &str, conditions: &mut Vec<String>, params: &mut Vec<Value>) {
+        if let Some(from) = self.from {
+            conditions.push(format!("{} >= ?", column));
+            params.push(Value::Integer(from));
+        }
+        if let Some(to) = self.to {
+            conditions.push(format!("{} < ?", column));
+            params.push(Value::Integer(to));
+        }
+    }
+
+    fn event_name_filter(&self, conditions: &mut Vec<String>, params: &mut Vec<Value>) {
+        let event_names = self.event_names();
+        if !event_names.is_empty() {
+            conditions.push(format!("event_name IN ({})", vec!["?"; event_names.len()].join(", ")));
+            params.extend(event_names.into_iter().map(Value::Text));
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Stats<T> {
+    granularity: &'static str,
+    /// `rollups` or `events`/`sessions` if the raw tables were scanned
+    source: &'static str,
+    buckets: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct EventBucket {
+    bucket: i64,
+    event_name: String,
+    events: i64,
+}
+
+#[derive(Serialize)]
+struct SessionBucket {
+    bucket: i64,
+    sessions: i64,
+    /// Distinct users, estimated if the stats come from rollups
+    users: u64,
+}
+
+/// Rollups answer queries whose granularity they store and whose range starts and
+/// ends on bucket boundaries; everything else is computed from the raw tables.
+fn use_rollups(config: &Config, granularity: Granularity, query: &StatsQuery) -> bool {
+    rollups_enabled(config)
+        && ROLLED_UP.contains(&granularity)
+        && [query.from, query.to].into_iter().flatten().all(|bound| bound % granularity.millis() == 0)
+}
+
+/// Counts raw events per bucket and name, optionally only those after `after_id`.
+fn count_events(
+    conn: &Connection,
+    granularity: Granularity,
+    query: &StatsQuery,
+    after_id: Option<i64>,
+    counts: &mut BTreeMap<(i64, String), i64>,
+) -> rusqlite::Result<()> {
+    let mut conditions = vec!["1".to_string()];
+    let mut params = Vec::new();
+
+    if let Some(after_id) = after_id {
+        conditions.push("id > ?".to_string());
+        params.push(Value::Integer(after_id));
+    }
+    query.range("timestamp", &mut conditions, &mut params);
+    query.event_name_filter(&mut conditions, &mut params);
+
+    let sql = format!(
+        "SELECT {} AS bucket, event_name, COUNT(*) FROM events WHERE {} GROUP BY bucket, event_name",
+        granularity.bucket_sql("timestamp"),
+        conditions.join(" AND ")
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+
+    while let Some(row) = rows.next()? {
+        *counts.entry((row.get(0)?, row.get(1)?)).or_default() += row.get::<_, i64>(2)?;
+    }
+
+    Ok(())
+}
+
+fn event_stats(conn: &Connection, granularity: Granularity, query: &StatsQuery, rollups: bool) -> rusqlite::Result<Vec<EventBucket>> {
+    let mut counts = BTreeMap::new();
+
+    if rollups {
+        // One snapshot, so a compaction committing in between is not counted twice
+        let tx = conn.unchecked_transaction()?;
+        let last_id = watermark(&tx, "events")?;
+
+        let mut conditions = vec!["granularity = ?".to_string()];
+        let mut params = vec![Value::Text(granularity.name().to_string())];
+        query.range("bucket", &mut conditions, &mut params);
+        query.event_name_filter(&mut conditions, &mut params);
+
+        {
+            let sql = format!("SELECT bucket, event_name, events FROM rollup_events WHERE {}", conditions.join(" AND "));
+            let mut stmt = tx.prepare(&sql)?;
+            let mut rows = stmt.query(params_from_iter(params))?;
+
+            while let Some(row) = rows.next()? {
+                *counts.entry((row.get(0)?, row.get(1)?)).or_default() += row.get::<_, i64>(2)?;
+            }
+        }
+
+        // Events stored since the last compaction
+        count_events(&tx, granularity, query, Some(last_id), &mut counts)?;
+    } else {
+        count_events(conn, granularity, query, None, &mut counts)?;
+    }
+
+    Ok(counts.into_iter().map(|((bucket, event_name), events)| EventBucket { bucket, event_name, events }).collect())
+}
+
+fn session_stats(conn: &Connection, granularity: Granularity, query: &StatsQuery, rollups: bool) -> rusqlite::Result<Vec<SessionBucket>> {
+    if !rollups {
+        let mut conditions = vec!["1".to_string()];
+        let mut params = Vec::new();
+        query.range("start_date", &mut conditions, &mut params);
+
+        let sql = format!(
+            "SELECT {} AS bucket, COUNT(*), COUNT(DISTINCT user_id) FROM sessions WHERE {} GROUP BY bucket ORDER BY bucket",
+            granularity.bucket_sql("start_date"),
+            conditions.join(" AND ")
+        );
+
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(params), |row| {
+            Ok(SessionBucket { bucket: row.get(0)?, sessions: row.get(1)?, users: row.get(2)? })
+        })?;
+
+        return rows.collect();
+    }
+
+    // One snapshot, so a compaction committing in between is not counted twice
+    let tx = conn.unchecked_transaction()?;
+    let (last_start_date, last_rowid) = session_watermark(&tx)?;
+    let mut buckets: BTreeMap<i64, (i64, HyperLogLog)> = BTreeMap::new();
+
+    {
+        let mut conditions = vec!["granularity = ?".to_string()];
+        let mut params = vec![Value::Text(granularity.name().to_string())];
+        query.range("bucket", &mut conditions, &mut params);
+
+        let sql = format!("SELECT bucket, sessions, users FROM rollup_sessions WHERE {}", conditions.join(" AND "));
+        let mut stmt = tx.prepare(&sql)?;
+        let mut rows = stmt.query(params_from_iter(params))?;
+
+        while let Some(row) = rows.next()? {
+            let (sessions, users) = buckets.entry(row.get(0)?).or_default();
+            *sessions += row.get::<_, i64>(1)?;
+            if let Some(stored) = HyperLogLog::from_bytes(&row.get::<_, Vec<u8>>(2)?) {
+                users.merge(&stored);
+            }
+        }
+    }
+
+    // Sessions started since the last compaction
+    {
+        let mut conditions = vec!["(start_date, rowid) > (?, ?)".to_string()];
+        let mut params = vec![Value::Integer(last_start_date), Value::Integer(last_rowid)];
+        query.range("start_date", &mut conditions, &mut params);
+
+        let sql = format!(
+            "SELECT {}, user_id FROM sessions WHERE {}",
+            granularity.bucket_sql("start_date"),
+            conditions.join(" AND ")
+        );
+        let mut stmt = tx.prepare(&sql)?;
+        let mut rows = stmt.query(params_from_iter(params))?;
+
+        while let Some(row) = rows.next()? {
+            let (sessions, users) = buckets.entry(row.get(0)?).or_default();
+            *sessions += 1;
+            if let Some(user_id) = row.get::<_, Option<String>>(1)? {
+                users.add(&user_id);
+            }
+        }
+    }
+
+    Ok(buckets.into_iter().map(|(bucket, (sessions, users))| SessionBucket { bucket, sessions, users: users.estimate() }).collect())
+}
+
+fn invalid_granularity() -> HttpResponse {
+    HttpResponse::BadRequest().json(ApiResponse {
+        success: false,
+        message: "Unsupported granularity, use minute, hour or day".to_string()
+    })
+}
+
+/// Event counts per bucket and event name, e.g. `GET /stats/events?granularity=day&event_name=run_start`.
+pub async fn get_event_stats(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::ReadEvents>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let Some(granularity) = Granularity::parse(query.granularity.as_deref()) else {
+        return Ok(invalid_granularity());
+    };
+
+    let query = query.into_inner();
+    let rollups = use_rollups(&data.config, granularity, &query);
+
+    match db.read(move |conn| event_stats(conn, granularity, &query, rollups)).await {
+        Ok(buckets) => Ok(respond(&req, &Stats {
+            granularity: granularity.name(),
+            source: if rollups { "rollups" } else { "events" },
+            buckets,
+        }, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Event stats not loaded", e))
+    }
+}
+
+/// Sessions started and distinct users per bucket.
+pub async fn get_session_stats(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::ReadSessions>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let Some(granularity) = Granularity::parse(query.granularity.as_deref()) else {
+        return Ok(invalid_granularity());
+    };
+
+    let query = query.into_inner();
+    let rollups = use_rollups(&data.config, granularity, &query);
+
+    match db.read(move |conn| session_stats(conn, granularity, &query, rollups)).await {
+        Ok(buckets) => Ok(respond(&req, &Stats {
+            granularity: granularity.name(),
+            source: if rollups { "rollups" } else { "sessions" },
+            buckets,
+        }, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Session stats not loaded", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app_state::{AppState, TestDir};
+
+    use super::*;
+
+    const HOUR: i64 = 3_600_000;
+
+    fn query() -> StatsQuery {
+        StatsQuery { granularity: None, from: None, to: None, event_name: None }
+    }
+
+    fn event_rows(conn: &Connection, rollups: bool) -> Vec<(i64, String, i64)> {
+        event_stats(conn, Granularity::Hour, &query(), rollups).unwrap()
+            .into_iter()
+            .map(|bucket| (bucket.bucket, bucket.event_name, bucket.events))
+            .collect()
+    }
+
+    fn session_rows(conn: &Connection, rollups: bool) -> Vec<(i64, i64, u64)> {
+        session_stats(conn, Granularity::Hour, &query(), rollups).unwrap()
+            .into_iter()
+            .map(|bucket| (bucket.bucket, bucket.sessions, bucket.users))
+            .collect()
+    }
+
+    fn insert_session(conn: &Connection, session_id: &str, user_id: &str, start_date: i64) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO sessions (session_id, user_id, start_date, ip_address) VALUES (?1, ?2, ?3, '')",
+            params![session_id, user_id, start_date],
+        )?;
+        conn.execute(
+            "INSERT INTO events (session_id, event_name, timestamp, ip_address) VALUES (?1, 'start', ?2, '')",
+            params![session_id, start_date],
+        )?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn rollup_stats_equal_raw_stats() {
+        let dir = TestDir::new("rollups");
+        let data = AppState::on_sqlite(&dir, &[("ROLLUP_INTERVAL", "60"), ("ROLLUP_BATCH_SIZE", "2")]);
+        let db = data.db.clone().unwrap();
+
+        db.write(|conn| {
+            // Before the epoch the remainder is negative, buckets still start at the hour
+            insert_session(conn, "s1", "u1", -HOUR / 2)?;
+            insert_session(conn, "s2", "u1", HOUR / 2)?;
+            insert_session(conn, "s3", "u2", HOUR + 1)?;
+            insert_session(conn, "s4", "u3", 3 * HOUR)
+        }).await.unwrap();
+
+        compact(&db, &data.config).await.unwrap();
+
+        // The newest session is erased and a new one reuses its rowid
+        db.write(|conn| {
+            conn.execute("DELETE FROM events WHERE session_id = 's4'", [])?;
+            conn.execute("DELETE FROM sessions WHERE session_id = 's4'", [])?;
+            insert_session(conn, "s5", "u4", 4 * HOUR)
+        }).await.unwrap();
+
+        let expected_sessions = vec![(-HOUR, 1, 1), (0, 1, 1), (HOUR, 1, 1), (3 * HOUR, 1, 1), (4 * HOUR, 1, 1)];
+
+        for compacted in [false, true] {
+            if compacted {
+                compact(&db, &data.config).await.unwrap();
+            }
+
+            let (raw_events, rollup_events, raw_sessions, rollup_sessions) = db.read(|conn| {
+                Ok((event_rows(conn, false), event_rows(conn, true), session_rows(conn, false), session_rows(conn, true)))
+            }).await.unwrap();
+
+            // Rollups keep the erased session, the raw tables do not
+            assert_eq!(raw_sessions, [&expected_sessions[..3], &expected_sessions[4..]].concat());
+            assert_eq!(rollup_sessions, expected_sessions);
+            assert_eq!(raw_events.len(), 4);
+            assert_eq!(rollup_events.len(), 5);
+            assert!(raw_events.iter().all(|row| rollup_events.contains(row)));
+        }
+    }
+}
@@ -1,21 +1,27 @@
-use actix_web::{web, Error, HttpRequest, HttpResponse, Responder, http::header::HeaderValue};
+use actix_web::{web, Error, HttpRequest, HttpResponse, Responder, http::header::{self, HeaderValue}};
 use serde::{Deserialize, Serialize};
 use deadpool_redis::{redis::{cmd as redis_cmd}};
-use rusqlite::params;
 use serde_json::Value;
 use uuid::Uuid;
 use chrono::{Utc};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 
-use crate::db_pool;
+use crate::body_format::{Body, respond};
 use crate::config::{Config};
+use crate::consent::{dropped_message, missing_consent, Consent, ConsentRequest};
+use crate::db_pool::{DbError};
 use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::storage::{NewSession};
 use crate::rate_limit::{check_rate_limit};
 
 #[derive(Deserialize, Debug)]
 pub struct IngestEventRequest {
-    session_id: String,
-    event_name: String,
-    data: Option<Value>
+    pub session_id: String,
+    pub event_name: String,
+    pub data: Option<Value>,
+    pub seq: Option<i64>
 }
 
 #[derive(Deserialize)]
@@ -24,44 +30,28 @@ pub struct CreateSessionRequest {
     device_model: Option<String>,
     operating_system: Option<String>,
     screen_width: Option<u64>,
-    screen_height: Option<u64>
+    screen_height: Option<u64>,
+    consent: Option<ConsentRequest>
 }
 
 #[derive(Serialize)]
 struct CreateSessionResponse {
     session_id: String,
     user_id: String,
-}
-
-#[derive(Serialize)]
-struct Event {
-    id: i64,
-    event_name: String,
-    time: i64,
-    data: Option<Value>,
-}
-
-#[derive(Serialize)]
-struct SessionInfo {
-    session_id: String,
-    start_date: i64,
+    consent: Consent,
 }
 
 #[derive(Serialize)]
 pub struct ApiResponse {
-    success: bool,
-    message: String,
+    pub success: bool,
+    pub message: String,
 }
 
 pub fn now() -> i64 {
-    let now = Utc::now();
-
-    let millis_since_epoch = now.timestamp_millis();
-
-    millis_since_epoch
+    Utc::now().timestamp_millis()
 }
 
-fn get_request_id(req: &HttpRequest, data: &web::Data<AppState>) -> Option<String> {
+pub fn get_request_id(req: &HttpRequest, data: &web::Data<AppState>) -> Option<String> {
     if data.config.trust_proxy != 0 {
         req.headers().get("cf-connecting-ip")
             .or_else(|| req.headers().get("x-forwarded-for"))
@@ -75,11 +65,64 @@ fn get_request_id(req: &HttpRequest, data: &web::Data<AppState>) -> Option<Strin
     }
 }
 
-fn get_user_agent<'a>(req: &'a HttpRequest) -> Option<&'a str> {
+/// Publishes `<channel> <session_id>` on the Redis instance, if one is configured.
+/// Additional projects publish on `<project>:<channel>`.
+pub async fn publish_session_event(data: &AppState, channel: &str, session_id: &str) {
+    let channel = match data.config.project.as_deref() {
+        Some(project) => format!("{}:{}", project, channel),
+        None => channel.to_string()
+    };
+
+    if let Some(redis_pool) = data.redis_pool.as_ref() {
+        let redis_instance = redis_pool.get().await;
+
+        if let Ok(mut connection) = redis_instance {
+            if let Err(e) = redis_cmd("PUBLISH")
+                .arg(&[channel.as_str(), session_id])
+                .query_async::<()>(&mut connection)
+                .await {
+                eprintln!("Cannot publish to redis: {}", e);
+            }
+        } else {
+            eprintln!("Cannot connect to redis: {}", redis_instance.err().unwrap());
+        }
+    }
+}
+
+fn get_user_agent(req: &HttpRequest) -> Option<&str> {
     req.headers().get("user-agent")?.to_str().ok()
 }
 
-pub async fn create_session(req: HttpRequest, data: web::Data<AppState>, payload: web::Json<CreateSessionRequest>) -> impl Responder {
+/// Responds with 503 and `Retry-After` if the database is overloaded, 500 otherwise.
+pub fn database_error(context: &str, error: DbError) -> HttpResponse {
+    let mut response = if error.is_unavailable() {
+        let mut response = HttpResponse::ServiceUnavailable();
+        response.insert_header((header::RETRY_AFTER, "1"));
+        response
+    } else {
+        HttpResponse::InternalServerError()
+    };
+
+    response.json(ApiResponse {
+        success: false,
+        message: format!("{}: {}", context, error)
+    })
+}
+
+/// Responds to endpoints that work on the SQLite database directly.
+pub fn sqlite_required() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse {
+        success: false,
+        message: "Not available with the in-memory storage backend".to_string()
+    })
+}
+
+pub async fn create_session(req: HttpRequest, data: web::Data<AppState>, payload: Body<CreateSessionRequest>) -> impl Responder {
+    // Check the ingestion key of the project
+    if !check_ingest_key(&req, &data.config) {
+        return ingest_key_rejected();
+    }
+
     // Rate limiting per IP address
     let ip = get_request_id(&req, &data).unwrap_or("unknown".to_string());
 
@@ -90,54 +133,56 @@ pub async fn create_session(req: HttpRequest, data: web::Data<AppState>, payload
         });
     }
 
-    let user_agent = get_user_agent(&req);
+    let user_agent = get_user_agent(&req).map(str::to_string);
 
     let session_id = Uuid::new_v4().to_string();
 
     let user_id = payload.user_id.clone()
         .unwrap_or_else(|| Uuid::new_v4().to_string());
 
-    let execution = db_pool::with_connection(|conn| {
-        conn.execute(
-            "INSERT INTO sessions (session_id, user_id, start_date, ip_address, device_model, operating_system, screen_width, screen_height, user_agent) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-            params![session_id, user_id, now(), ip, payload.device_model, payload.operating_system, payload.screen_width, payload.screen_height, user_agent],
-        )
-    });
+    let payload = payload.into_inner();
+    let consent = payload.consent.unwrap_or_default().apply(Consent::all(data.config.consent_default));
+
+    let execution = data.storage.create_session(NewSession {
+        session_id: session_id.clone(),
+        user_id: user_id.clone(),
+        ip_address: data.ip_anonymizer.anonymize(&ip),
+        device_model: payload.device_model,
+        operating_system: payload.operating_system,
+        screen_width: payload.screen_width,
+        screen_height: payload.screen_height,
+        user_agent,
+        consent,
+    }).await;
 
     match execution {
         Ok(_) => {
+            data.consent.remember(&session_id, consent);
+
             // Notify REDIS channel that sessionId was created
-            if let Some(redis_pool) = data.redis_pool.as_ref() {
-                let redis_instance = redis_pool.get().await;
-
-                if let Ok(mut connection) = redis_instance {
-                    redis_cmd("PUBLISH")
-                        .arg(&["evt_session_created", &session_id])
-                        .query_async::<()>(&mut connection)
-                        .await.unwrap();   
-                } else {
-                    eprintln!("Cannot connect to redis: {}", redis_instance.err().unwrap());
-                }
-            }
+            publish_session_event(&data, "evt_session_created", &session_id).await;
 
             HttpResponse::Ok().json(CreateSessionResponse {
                 session_id,
                 user_id,
+                consent,
             })
         },
 
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse {
-            success: false,
-            message: format!("Session not created: {}", e)
-        })
+        Err(e) => database_error("Session not created", e)
     }
 }
 
 pub async fn ingest_event(
     req: HttpRequest,
     data: web::Data<AppState>,
-    payload: web::Json<IngestEventRequest>,
+    payload: Body<IngestEventRequest>,
 ) -> impl Responder {
+    // Check the ingestion key of the project
+    if !check_ingest_key(&req, &data.config) {
+        return ingest_key_rejected();
+    }
+
     // Rate limiting per IP address
     let ip = get_request_id(&req, &data).unwrap_or("unknown".to_string());
 
@@ -148,37 +193,29 @@ pub async fn ingest_event(
         })        
     }
 
+    // Events the player did not consent to are dropped, not rejected, so clients do not retry them
+    match missing_consent(&data, &payload.session_id, &payload.event_name).await {
+        Ok(None) => {},
+        Ok(Some(category)) => {
+            return HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                message: dropped_message(category)
+            });
+        },
+        Err(e) => return database_error("Event not ingested", e)
+    }
+
     // ToDo: use mpsc::channel and thread to collect events first (BULK_MODE, BULK_INTERVAL=100ms)
     // or allow to use default (instant write).
     // *** tokio::sync::mpsc ***
-    let execution = db_pool::with_connection(|conn| {
-        conn.execute(
-            "INSERT INTO events (session_id, timestamp, event_name, ip_address, params) VALUES (?1, ?2, ?3, ?4, json(?5))",
-            params![
-                payload.session_id,
-                now(),
-                payload.event_name,
-                ip,
-                payload.data.as_ref().map(|x| x.to_string())
-            ],
-        )
-    });
+    let payload = payload.into_inner();
+    let session_id = payload.session_id.clone();
+    let execution = data.storage.insert_event(payload, data.ip_anonymizer.anonymize(&ip)).await;
 
     match execution {
         Ok(_) => {
-            // Notify REDIS channel that sessionId was created
-            if let Some(redis_pool) = data.redis_pool.as_ref() {
-                let redis_instance = redis_pool.get().await;
-
-                if let Ok(mut connection) = redis_instance {
-                    redis_cmd("PUBLISH")
-                        .arg(&["evt_session_updated", &payload.session_id])
-                        .query_async::<()>(&mut connection)
-                        .await.unwrap();   
-                } else {
-                    eprintln!("Cannot connect to redis: {}", redis_instance.err().unwrap());
-                }
-            }
+            // Notify REDIS channel that sessionId was updated
+            publish_session_event(&data, "evt_session_updated", &session_id).await;
 
             HttpResponse::Ok().json(ApiResponse {
                 // ToDo: On SYNC_MODE, notify REDIS channel that sessionId was updated (REDIS_CLIENT, pub/sub)
@@ -188,99 +225,159 @@ pub async fn ingest_event(
             })
         },
 
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse {
-            success: false,
-            message: format!("Event not ingested: {}", e)
-        })
+        Err(e) => database_error("Event not ingested", e)
     }
 }
 
-pub fn compare_secrets(secret_header: Option<&HeaderValue>, config: &Config) -> bool {
-    if secret_header.is_none() || config.secret_key.is_none() {
-        return false;
+/// Whether `value` is `key`, compared in constant time. Always false if no key is configured.
+///
+/// Both sides are hashed first, so the comparison takes the same time wherever the
+/// values differ and whatever their lengths.
+pub fn compare_key(value: Option<&[u8]>, key: Option<&str>) -> bool {
+    match (value, key) {
+        (Some(value), Some(key)) => Sha256::digest(value).ct_eq(&Sha256::digest(key.as_bytes())).into(),
+        _ => false
     }
-    secret_header.unwrap().to_str().unwrap() == config.secret_key.as_ref().unwrap()
+}
+
+/// Ingestion is open unless the project has an `INGEST_KEY`, which clients then
+/// send in the `X-RLA-INGEST-KEY` header.
+pub fn check_ingest_key(req: &HttpRequest, config: &Config) -> bool {
+    let header = req.headers().get("X-RLA-INGEST-KEY").map(HeaderValue::as_bytes);
+    config.ingest_key.is_none() || compare_key(header, config.ingest_key.as_deref())
+}
+
+pub fn ingest_key_rejected() -> HttpResponse {
+    HttpResponse::Unauthorized().json(ApiResponse {
+        success: false,
+        message: "Invalid ingestion key".to_string()
+    })
 }
 
 pub async fn get_events(
     req: HttpRequest,
     data: web::Data<AppState>,
+    _auth: Authorized<scope::ReadEvents>,
     path: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
-    // Check for shared secret
-    let secret = req.headers().get("X-RLA-KEY");
-    if !compare_secrets(secret, &data.config) {
-        return Ok(HttpResponse::Unauthorized().json(ApiResponse {
-            success: false,
-            message: "Insufficient permissions".to_string()
-        }))             
-    }
-
     let session_id = path.into_inner();
 
-    let events = db_pool::with_connection(|conn| {
-        let mut stmt = conn
-            .prepare_cached(
-                "SELECT
-                    id,
-                    event_name,
-                    timestamp,
-                    params
-                FROM events
-                WHERE session_id = ?1",
-            )
-            .unwrap();
-
-        let events_iter = stmt
-            .query_map(params![session_id], |row| {
-                let params_str: String = row.get(3)?;
-                Ok(Event {
-                    id: row.get(0)?,
-                    event_name: row.get(1)?,
-                    time: row.get(2)?,
-                    data: serde_json::from_str(&params_str).unwrap_or(None),
-                })
-            })
-            .unwrap();
+    let events = data.storage.get_events(session_id).await;
 
-        events_iter.map(|event| event.unwrap()).collect::<Vec<Event>>()
-    });
+    match events {
+        Ok(events) => Ok(respond(&req, &events, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Events not loaded", e))
+    }
+}
 
-    Ok(HttpResponse::Ok().json(events))
+pub async fn get_sessions(req: HttpRequest, data: web::Data<AppState>, _auth: Authorized<scope::ReadSessions>) -> Result<HttpResponse, Error> {
+    let sessions = data.storage.get_sessions().await;
+
+    match sessions {
+        Ok(sessions) => Ok(respond(&req, &sessions, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Sessions not loaded", e))
+    }
 }
 
-pub async fn get_sessions(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    // Check for shared secret
-    let secret = req.headers().get("X-RLA-KEY");
-    if !compare_secrets(secret, &data.config) {
-        return Ok(HttpResponse::Unauthorized().json(ApiResponse {
-            success: false,
-            message: "Insufficient permissions".to_string()
-        }));
+pub async fn health_check(data: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    match data.storage.health_check().await {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(e) => {
+            log::error!("Health check failed: {}", e);
+            Ok(HttpResponse::ServiceUnavailable().finish())
+        }
     }
+}
 
-    let sessions = db_pool::with_connection(|conn| {
-        let mut stmt = conn
-            .prepare_cached("SELECT session_id, start_date FROM sessions")
-            .unwrap();
-
-        let sessions_iter = stmt
-            .query_map([], |row| {
-                Ok(SessionInfo {
-                    session_id: row.get(0)?,
-                    start_date: row.get(1)?,
-                })
-            })
-            .unwrap();
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    use super::*;
+
+    const VARS: [(&str, &str); 3] = [("STORAGE_BACKEND", "memory"), ("SECRET_KEY", "secret"), ("MAX_EVENTS_PER_SECOND", "1000")];
+
+    macro_rules! app {
+        ($data:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($data.clone())
+                    .route("/create_session", web::post().to(create_session))
+                    .route("/ingest_event", web::post().to(ingest_event))
+                    .route("/get_events/{session_id}", web::get().to(get_events))
+            ).await
+        };
+    }
 
-        sessions_iter
-            .map(|session| session.unwrap())
-            .collect::<Vec<SessionInfo>>()
-    });
+    #[actix_web::test]
+    async fn returns_events_ordered_by_seq() {
+        let data = AppState::in_memory(&VARS);
+        let app = app!(data);
+
+        let req = test::TestRequest::post().uri("/create_session").set_json(json!({})).to_request();
+        let session: Value = test::call_and_read_body_json(&app, req).await;
+        let session_id = session["session_id"].as_str().unwrap();
+
+        for (event_name, seq) in [("b", json!(2)), ("none", Value::Null), ("c", json!(3)), ("a", json!(1))] {
+            let req = test::TestRequest::post()
+                .uri("/ingest_event")
+                .set_json(json!({ "session_id": session_id, "event_name": event_name, "seq": seq }))
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/get_events/{}", session_id))
+            .insert_header(("X-RLA-KEY", "secret"))
+            .to_request();
+        let events: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+        let names: Vec<&str> = events.iter().map(|event| event["event_name"].as_str().unwrap()).collect();
+
+        assert_eq!(names, ["none", "a", "b", "c"]);
+    }
 
-    Ok(HttpResponse::Ok().json(sessions))
-}
+    #[actix_web::test]
+    async fn drops_events_without_consent() {
+        let data = AppState::in_memory(&[VARS[0], VARS[1], VARS[2], ("CONSENT_CATEGORIES", "crash=crash_reporting")]);
+        let app = app!(data);
+
+        let req = test::TestRequest::post()
+            .uri("/create_session")
+            .set_json(json!({ "consent": { "crash_reporting": false } }))
+            .to_request();
+        let session: Value = test::call_and_read_body_json(&app, req).await;
+        let session_id = session["session_id"].as_str().unwrap();
+        assert_eq!(session["consent"], json!({ "analytics": true, "crash_reporting": false, "personalized": true }));
+
+        let mut messages = Vec::new();
+        for event_name in ["crash", "level_up"] {
+            let req = test::TestRequest::post()
+                .uri("/ingest_event")
+                .set_json(json!({ "session_id": session_id, "event_name": event_name }))
+                .to_request();
+            let response: Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(response["success"], true);
+            messages.push(response["message"].as_str().unwrap().to_string());
+        }
+
+        assert_eq!(messages, ["Event dropped, no consent to crash_reporting", "Event ingested"]);
+        assert_eq!(data.consent.dropped(crate::config::ConsentCategory::CrashReporting), 1);
+
+        let events = data.storage.get_events(session_id.to_string()).await.unwrap();
+        assert_eq!(events.iter().map(|event| event.event_name.as_str()).collect::<Vec<_>>(), ["level_up"]);
+    }
 
-pub async fn health_check() -> Result<HttpResponse, Error> {
-    Ok(HttpResponse::Ok().finish())
+    #[actix_web::test]
+    async fn rejects_events_of_unknown_sessions() {
+        let data = AppState::in_memory(&VARS);
+        let app = app!(data);
+
+        let req = test::TestRequest::post()
+            .uri("/ingest_event")
+            .set_json(json!({ "session_id": "unknown", "event_name": "a" }))
+            .to_request();
+
+        assert!(test::call_service(&app, req).await.status().is_server_error());
+    }
 }
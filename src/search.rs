@@ -0,0 +1,379 @@
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond};
+use crate::config::{Config};
+use crate::partitions::{attached_event_tables, Partitions};
+use crate::promoted_params::{sql_literal};
+use crate::route_handlers::{database_error, sqlite_required, ApiResponse};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/// Tokens of context around the matches in a highlight.
+const SNIPPET_TOKENS: i64 = 24;
+
+pub fn search_enabled(config: &Config) -> bool {
+    !config.search_event_names.is_empty()
+}
+
+/// The `event_search` columns of the event `row`. Only string values are indexed, keys
+/// and numbers are left out.
+fn indexed_columns(row: &str) -> String {
+    format!(
+        "{row}.id, (SELECT group_concat(value, ' ') FROM json_tree({row}.params) WHERE type = 'text'), \
+        {row}.session_id, {row}.event_name, {row}.timestamp",
+        row = row
+    )
+}
+
+/// Whether the event `row` is indexed, `*` indexes every event with params.
+fn indexed_filter(event_names: &[String], row: &str) -> String {
+    if event_names.iter().any(|name| name == "*") {
+        return format!("{}.params IS NOT NULL", row);
+    }
+
+    let names: Vec<String> = event_names.iter().map(|name| sql_literal(name)).collect();
+    format!("{row}.params IS NOT NULL AND {row}.event_name IN ({names})", row = row, names = names.join(", "))
+}
+
+/// Recreates the triggers indexing the params of events into `event_search`.
+///
+/// The triggers are temporary and live on the writer only, so every insert is indexed
+/// in its own transaction. They are recreated after partitions were re-attached, as
+/// triggers on a detached table stop firing.
+pub fn sync_search_triggers(conn: &Connection, event_names: &[String]) -> rusqlite::Result<()> {
+    let existing = {
+        let mut stmt = conn.prepare(
+            "SELECT name FROM sqlite_temp_master WHERE type = 'trigger' AND name LIKE 'event\\_search\\_%' ESCAPE '\\'"
+        )?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<rusqlite::Result<Vec<String>>>()?
+    };
+
+    for name in existing {
+        conn.execute_batch(&format!("DROP TRIGGER IF EXISTS temp.{};", name))?;
+    }
+
+    if event_names.is_empty() {
+        return Ok(());
+    }
+
+    let insert = format!(
+        "INSERT INTO event_search (rowid, content, session_id, event_name, timestamp)
+        SELECT {} WHERE {};",
+        indexed_columns("NEW"),
+        indexed_filter(event_names, "NEW")
+    );
+
+    for (schema, table) in attached_event_tables(conn)? {
+        conn.execute_batch(&format!(
+            "CREATE TEMP TRIGGER event_search_insert_{schema} AFTER INSERT ON {schema}.{table} BEGIN
+                {insert}
+            END;
+            CREATE TEMP TRIGGER event_search_update_{schema} AFTER UPDATE OF event_name, params ON {schema}.{table} BEGIN
+                DELETE FROM event_search WHERE rowid = OLD.id;
+                {insert}
+            END;
+            CREATE TEMP TRIGGER event_search_delete_{schema} AFTER DELETE ON {schema}.{table} BEGIN
+                DELETE FROM event_search WHERE rowid = OLD.id;
+            END;",
+            schema = schema,
+            table = table,
+            insert = insert
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Turns the words of a search into an FTS5 query matching events containing all of
+/// them. Syntax characters are quoted away, a trailing `*` keeps its prefix meaning.
+fn match_expression(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .filter_map(|word| {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(word) => (word, "*"),
+                None => (word, "")
+            };
+            (!word.is_empty()).then(|| format!("\"{}\"{}", word.replace('"', "\"\""), prefix))
+        })
+        .collect();
+
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    /// Comma-separated event names
+    event_name: Option<String>,
+    session_id: Option<String>,
+    limit: Option<usize>,
+    /// Only events with a lower id, the `next_before` of the previous page
+    before: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    id: i64,
+    session_id: String,
+    event_name: String,
+    timestamp: i64,
+    /// Matched text with `<mark>` around the matches
+    highlight: String,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    hits: Vec<SearchHit>,
+    /// Cursor of the next page, `None` on the last page
+    next_before: Option<i64>,
+}
+
+fn search(conn: &Connection, expression: String, query: &SearchQuery, limit: usize) -> rusqlite::Result<SearchResults> {
+    let mut conditions = vec!["event_search MATCH ?".to_string()];
+    let mut params = vec![Value::Text(expression)];
+
+    if let Some(before) = query.before {
+        conditions.push("rowid < ?".to_string());
+        params.push(Value::Integer(before));
+    }
+
+    let event_names: Vec<&str> = query.event_name
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if !event_names.is_empty() {
+        conditions.push(format!("event_name IN ({})", vec!["?"; event_names.len()].join(", ")));
+        params.extend(event_names.into_iter().map(|name| Value::Text(name.to_string())));
+    }
+    if let Some(session_id) = query.session_id.as_ref() {
+        conditions.push("session_id = ?".to_string());
+        params.push(Value::Text(session_id.clone()));
+    }
+
+    // One extra row tells whether there is another page
+    params.push(Value::Integer(limit as i64 + 1));
+
+    let sql = format!(
+        "SELECT rowid, session_id, event_name, timestamp, snippet(event_search, 0, '<mark>', '</mark>', '...', {})
+        FROM event_search
+        WHERE {}
+        ORDER BY rowid DESC
+        LIMIT ?",
+        SNIPPET_TOKENS,
+        conditions.join(" AND ")
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(params), |row| {
+        Ok(SearchHit {
+            id: row.get(0)?,
+            session_id: row.get(1)?,
+            event_name: row.get(2)?,
+            timestamp: row.get(3)?,
+            highlight: row.get(4)?,
+        })
+    })?;
+
+    let mut hits = rows.collect::<rusqlite::Result<Vec<SearchHit>>>()?;
+    let next_before = (hits.len() > limit).then(|| {
+        hits.truncate(limit);
+        hits.last().map(|hit| hit.id)
+    }).flatten();
+
+    Ok(SearchResults { hits, next_before })
+}
+
+/// Searches the text in the params of indexed events, newest first,
+/// e.g. `GET /search?q=NullReferenceException&event_name=crash`.
+pub async fn search_events(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::ReadEvents>,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    if !search_enabled(&data.config) {
+        return Ok(HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: "Search is disabled".to_string()
+        }));
+    }
+
+    let Some(expression) = match_expression(&query.q) else {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: "Search query is empty".to_string()
+        }));
+    };
+
+    let query = query.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    match db.read(move |conn| search(conn, expression, &query, limit)).await {
+        Ok(results) => Ok(respond(&req, &results, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Search failed", e))
+    }
+}
+
+/// Indexes the events of `table` again in batches of `batch_size` ids starting after
+/// `after`. Each batch replaces the entries of its id range in one transaction, which
+/// also drops entries of events that are gone or no longer indexed.
+fn reindex_table(conn: &mut Connection, table: &str, event_names: &[String], after: i64, batch_size: usize) -> rusqlite::Result<usize> {
+    let insert = format!(
+        "INSERT INTO main.event_search (rowid, content, session_id, event_name, timestamp)
+        SELECT {} FROM {} AS e WHERE e.id > ?1 AND e.id <= ?2 AND {}",
+        indexed_columns("e"),
+        table,
+        indexed_filter(event_names, "e")
+    );
+    let next = format!("SELECT MAX(id) FROM (SELECT id FROM {} WHERE id > ?1 ORDER BY id LIMIT ?2)", table);
+
+    let mut cursor = after;
+    let mut indexed = 0;
+
+    while let Some(last) = conn.query_row(&next, params![cursor, batch_size as i64], |row| row.get::<_, Option<i64>>(0))? {
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM main.event_search WHERE rowid > ?1 AND rowid <= ?2", params![cursor, last])?;
+        indexed += tx.execute(&insert, params![cursor, last])?;
+        tx.commit()?;
+
+        cursor = last;
+    }
+
+    Ok(indexed)
+}
+
+/// `reindex-search [--project <name>]`: Indexes the events of `main.events` and of every
+/// partition that has not been archived again, e.g. after `SEARCH_EVENT_NAMES` changed,
+/// and drops the entries of archived partitions. Runs in batches of `RETENTION_BATCH_SIZE`
+/// events, so the server can keep running.
+pub fn run_cli(conn: &mut Connection, config: &Config, args: &[String]) -> std::io::Result<()> {
+    if !args.is_empty() {
+        return Err(std::io::Error::other("Usage: reindex-search [--project <name>]"));
+    }
+
+    if !search_enabled(config) {
+        println!("SEARCH_EVENT_NAMES is not set, nothing to index");
+        return Ok(());
+    }
+
+    let event_names = &config.search_event_names;
+    let batch_size = config.retention_batch_size.max(1);
+
+    let indexed = reindex_table(conn, "main.events", event_names, i64::MIN, batch_size).map_err(std::io::Error::other)?;
+    println!("  main.events: {} events indexed", indexed);
+
+    let Some(partitions) = Partitions::new(config)? else {
+        return Ok(());
+    };
+    let ranges = partitions.id_ranges();
+
+    for (path, table, (start, _)) in ranges.iter() {
+        conn.execute("ATTACH DATABASE ?1 AS reindexed", params![path.display().to_string()]).map_err(std::io::Error::other)?;
+        let result = reindex_table(conn, &format!("reindexed.{}", table), event_names, start - 1, batch_size);
+        conn.execute_batch("DETACH DATABASE reindexed").map_err(std::io::Error::other)?;
+
+        println!("  {}: {} events indexed", path.display(), result.map_err(std::io::Error::other)?);
+    }
+
+    // Ids tell the partition an entry belongs to. Entries between the legacy ids of
+    // `main.events` and the newest partition that no partition claims were archived.
+    if let (Some((_, _, (first, _))), Some((_, _, (newest, _)))) = (ranges.first(), ranges.last()) {
+        let live: Vec<String> = ranges.iter()
+            .map(|(_, _, (start, end))| format!("(rowid >= {} AND rowid < {})", start, end))
+            .collect();
+
+        let removed = conn.execute(
+            &format!("DELETE FROM main.event_search WHERE rowid >= ?1 AND rowid < ?2 AND NOT ({})", live.join(" OR ")),
+            params![first, newest],
+        ).map_err(std::io::Error::other)?;
+        println!("  {} entries of archived partitions removed", removed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app_state::{AppState, TestDir};
+
+    use super::*;
+
+    fn query(q: &str) -> SearchQuery {
+        SearchQuery { q: q.to_string(), event_name: None, session_id: None, limit: None, before: None }
+    }
+
+    /// Ids of the events matching `q`, newest first.
+    fn hits(conn: &Connection, q: &str) -> Vec<i64> {
+        let expression = match_expression(q).unwrap();
+        search(conn, expression, &query(q), MAX_LIMIT).unwrap().hits.into_iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn quotes_search_terms() {
+        assert_eq!(match_expression("goblin  king").as_deref(), Some("\"goblin\" \"king\""));
+        assert_eq!(match_expression("gob*").as_deref(), Some("\"gob\"*"));
+        assert_eq!(match_expression("a*b").as_deref(), Some("\"a*b\""));
+        assert_eq!(match_expression("say \"hi\"").as_deref(), Some("\"say\" \"\"\"hi\"\"\""));
+        assert_eq!(match_expression("NOT col:x").as_deref(), Some("\"NOT\" \"col:x\""));
+        assert_eq!(match_expression("  * "), None);
+        assert_eq!(match_expression(""), None);
+    }
+
+    #[actix_web::test]
+    async fn indexes_listed_events_and_matches_syntax_as_text() {
+        let dir = TestDir::new("search");
+        let data = AppState::on_sqlite(&dir, &[("SEARCH_EVENT_NAMES", "crash")]);
+        let db = data.db.clone().unwrap();
+
+        db.write(|conn| {
+            conn.execute_batch(
+                r#"INSERT INTO sessions (session_id, start_date, ip_address) VALUES ('s1', 0, '');
+                INSERT INTO events (id, session_id, event_name, timestamp, ip_address, params) VALUES
+                    (1, 's1', 'crash', 0, '', '{"error": "NullReferenceException in Goblin.Attack", "floor": 3}'),
+                    (2, 's1', 'crash', 0, '', '{"error": "NOT col:x (unbalanced"}'),
+                    (3, 's1', 'level_up', 0, '', '{"error": "NullReferenceException"}'),
+                    (4, 's1', 'crash', 0, '', '{"error": "say \"hi\" Goblins"}');"#
+            )
+        }).await.unwrap();
+
+        db.write(|conn| {
+            // Only listed events are indexed, numbers are left out
+            assert_eq!(hits(conn, "NullReferenceException"), [1]);
+            assert_eq!(hits(conn, "3"), Vec::<i64>::new());
+            assert_eq!(hits(conn, "gob*"), [4, 1]);
+
+            // Pages continue below the last id of the previous one
+            let first = search(conn, match_expression("gob*").unwrap(), &query("gob*"), 1)?;
+            assert_eq!((first.hits[0].id, first.next_before), (4, Some(4)));
+            let next = SearchQuery { before: first.next_before, ..query("gob*") };
+            let second = search(conn, match_expression("gob*").unwrap(), &next, 1)?;
+            assert_eq!((second.hits[0].id, second.next_before), (1, None));
+
+            // Query syntax is matched as text instead of failing the query
+            assert_eq!(hits(conn, "NOT col:x (unbalanced"), [2]);
+            assert_eq!(hits(conn, "\"hi\""), [4]);
+
+            // Updates and deletes keep the index in step
+            conn.execute("UPDATE events SET params = '{\"error\": \"StackOverflow\"}' WHERE id = 1", [])?;
+            conn.execute("DELETE FROM events WHERE id = 4", [])?;
+            assert_eq!(hits(conn, "gob*"), Vec::<i64>::new());
+            assert_eq!(hits(conn, "StackOverflow"), [1]);
+
+            Ok(())
+        }).await.unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond};
+use crate::route_handlers::{database_error};
+
+#[derive(Serialize)]
+struct SequenceGap {
+    from: i64,
+    to: i64,
+}
+
+/// Event loss and reordering of a session, based on the client supplied `seq`.
+///
+/// `missing` only counts holes between the lowest and highest received `seq`;
+/// `out_of_order` counts events that arrived after an event with a higher `seq`.
+/// Clients may send any `i64`, so distances are computed in `i128`.
+#[derive(Serialize)]
+struct SequenceReport {
+    session_id: String,
+    events: i64,
+    first_seq: Option<i64>,
+    last_seq: Option<i64>,
+    missing: u64,
+    duplicates: i64,
+    out_of_order: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gaps: Option<Vec<SequenceGap>>,
+}
+
+/// Number of values in `first..=last` that were not received, never below zero.
+fn missing_between(first: i64, last: i64, received: i64) -> u64 {
+    let span = last as i128 - first as i128 + 1;
+    (span - received as i128).clamp(0, u64::MAX as i128) as u64
+}
+
+#[derive(Deserialize)]
+pub struct SequenceReportQuery {
+    incomplete: Option<bool>,
+}
+
+fn build_report(session_id: String, seqs_by_arrival: &[i64]) -> SequenceReport {
+    let mut out_of_order = 0;
+    let mut highest: Option<i64> = None;
+
+    for seq in seqs_by_arrival {
+        match highest {
+            Some(max) if *seq < max => out_of_order += 1,
+            _ => highest = Some(*seq)
+        }
+    }
+
+    let mut sorted = seqs_by_arrival.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut gaps = Vec::new();
+    for pair in sorted.windows(2) {
+        if pair[1] as i128 - pair[0] as i128 > 1 {
+            gaps.push(SequenceGap { from: pair[0] + 1, to: pair[1] - 1 });
+        }
+    }
+
+    SequenceReport {
+        session_id,
+        events: seqs_by_arrival.len() as i64,
+        first_seq: sorted.first().copied(),
+        last_seq: sorted.last().copied(),
+        missing: gaps.iter().fold(0u64, |missing, gap| missing.saturating_add(missing_between(gap.from, gap.to, 0))),
+        duplicates: (seqs_by_arrival.len() - sorted.len()) as i64,
+        out_of_order,
+        gaps: Some(gaps),
+    }
+}
+
+/// Reports gaps, duplicates and out-of-order arrivals for a single session.
+pub async fn get_event_gaps(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::ReadEvents>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+
+    let seqs = data.storage.get_event_seqs(session_id.clone()).await;
+
+    match seqs {
+        Ok(seqs) => Ok(respond(&req, &build_report(session_id, &seqs), data.config.compress_min_response_size)),
+
+        Err(e) => Ok(database_error("Report not created", e))
+    }
+}
+
+/// Summarizes sequence numbers of all sessions, e.g. to compare event loss across client builds.
+pub async fn get_all_event_gaps(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::ReadEvents>,
+    query: web::Query<SequenceReportQuery>,
+) -> Result<HttpResponse, Error> {
+    let reports = data.storage.get_sequence_summaries().await.map(|summaries| {
+        summaries.into_iter().map(|summary| SequenceReport {
+            session_id: summary.session_id,
+            events: summary.events,
+            first_seq: Some(summary.first_seq),
+            last_seq: Some(summary.last_seq),
+            missing: missing_between(summary.first_seq, summary.last_seq, summary.distinct),
+            duplicates: summary.events - summary.distinct,
+            out_of_order: summary.out_of_order,
+            gaps: None,
+        }).collect::<Vec<SequenceReport>>()
+    });
+
+    match reports {
+        Ok(mut reports) => {
+            if query.incomplete.unwrap_or(false) {
+                reports.retain(|report| report.missing > 0 || report.out_of_order > 0);
+            }
+            Ok(respond(&req, &reports, data.config.compress_min_response_size))
+        },
+
+        Err(e) => Ok(database_error("Report not created", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_gaps_and_reordering() {
+        let report = build_report("s".to_string(), &[1, 2, 5, 4, 4, 8]);
+
+        assert_eq!(report.missing, 3);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.out_of_order, 2);
+        let gaps: Vec<(i64, i64)> = report.gaps.unwrap().iter().map(|gap| (gap.from, gap.to)).collect();
+        assert_eq!(gaps, vec![(3, 3), (6, 7)]);
+    }
+
+    #[test]
+    fn handles_extreme_seqs() {
+        let report = build_report("s".to_string(), &[i64::MAX, i64::MIN, 0]);
+
+        assert_eq!(report.first_seq, Some(i64::MIN));
+        assert_eq!(report.last_seq, Some(i64::MAX));
+        assert_eq!(report.missing, u64::MAX - 2);
+        assert_eq!(missing_between(i64::MIN, i64::MAX, 2), u64::MAX - 1);
+        assert_eq!(missing_between(5, 5, 3), 0);
+    }
+}
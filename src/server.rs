@@ -1,15 +1,37 @@
+use std::sync::Arc;
 use std::time::{Duration};
 use std::collections::HashSet;
 
 use dotenv::dotenv;
-use actix_web::{middleware, web, App, HttpServer, error, HttpResponse, http};
+use actix_web::{middleware, web, App, HttpRequest, HttpServer, error, HttpResponse, http};
+use actix_web::middleware::from_fn;
 use actix_cors::Cors;
 
 use serde::{Serialize};
 
+use crate::api_keys::{delete_key, get_keys, post_key, post_key_rotation};
 use crate::app_state::{AppState};
+use crate::backup::{backup_enabled, create_backup, get_backup, remove_partial_backups, run_backup, try_begin};
 use crate::config::{Config};
-use crate::rate_limit::{cleanup_rate_limiter};
+use crate::compression::{limit_compressed_payload};
+use crate::consent::{update_consent};
+use crate::export::{export_events, export_sessions};
+use crate::metrics::{get_metrics};
+use crate::partitions::{archive_partition, get_partitions, mark_partial_reads};
+use crate::projects::{select_project, ProjectRegistry};
+use crate::promoted_params::{demote_param, get_promoted_params, promote_param};
+use crate::rate_limit::{cleanup_auth_failures, cleanup_rate_limiter};
+use crate::parquet_archive::{create_parquet_archive, get_parquet_archive, parquet_archive_enabled, run_archive, try_begin as try_begin_archive};
+use crate::request_signing::{cleanup_nonces, require_signed_request, require_signed_stream, require_signed_upgrade};
+use crate::retention::{get_retention, purge, retention_enabled};
+use crate::rollups::{compact, get_event_stats, get_session_stats, rollups_enabled};
+use crate::search::{search_events};
+use crate::storage::{open_storage};
+use crate::tls::{self, redirect_to_https, spawn_reload, tls_enabled, ReloadingCertificate};
+use crate::sequence_report::{get_event_gaps, get_all_event_gaps};
+use crate::stream_ingest::{ingest_events};
+use crate::user_data::{erase_user, export_user, get_erasures};
+use crate::ws_ingest::{ingest_ws};
 use crate::route_handlers::{
     create_session,
     ingest_event,
@@ -19,7 +41,7 @@ use crate::route_handlers::{
 };
 
 #[derive(Serialize)]
-struct PublicJsonError {
+pub struct PublicJsonError {
     pub message: String
 }
 
@@ -45,7 +67,15 @@ fn cors_middleware(value: &Option<String>) -> Cors {
 
     Cors::default()
         .allowed_methods(vec!["GET", "POST", "OPTIONS"])
-        .allowed_headers(vec![http::header::CONTENT_TYPE])
+        .allowed_headers(vec![
+            http::header::CONTENT_TYPE,
+            http::header::CONTENT_ENCODING,
+            http::header::HeaderName::from_static("x-rla-ingest-key"),
+            http::header::HeaderName::from_static("x-rla-build"),
+            http::header::HeaderName::from_static("x-rla-timestamp"),
+            http::header::HeaderName::from_static("x-rla-nonce"),
+            http::header::HeaderName::from_static("x-rla-signature"),
+        ])
         .allowed_origin_fn(move |origin, _req_head| {
             match origin.to_str() {
                 Ok(value) => (value.starts_with("http://") || value.starts_with("https://")) && allowed.contains(value),
@@ -55,86 +85,311 @@ fn cors_middleware(value: &Option<String>) -> Cors {
         .max_age(3600)
 }
 
-#[actix_web::main]
-pub async fn main() -> std::io::Result<()> {
-    dotenv().ok();
-
-    // Initialize logger
-    env_logger::init();
-
-    // Read the config from env vars
-    let config = Config::from_env();
+/// Opens the storage of a project, applying pending schema migrations.
+async fn open_project(config: Config) -> std::io::Result<web::Data<AppState>> {
+    let (storage, db) = open_storage(&config)?;
 
-    // Create appState
-    let app_state = AppState::init(config);
+    let app_state = AppState::init(config, storage, db);
 
     // Test connection
     app_state.test_connection().await?;
 
-    // Create new app state
-    let data = web::Data::new(app_state);
+    Ok(web::Data::new(app_state))
+}
 
-    // Spawn cleanup worker
+/// Starts the background workers of a project.
+fn spawn_workers(data: &web::Data<AppState>) {
     let rate_limiter_clone = data.rate_limiter.clone();
+    let auth_failures_clone = data.auth_failures.clone();
+    let nonces_clone = data.nonces.clone();
+    let consent_clone = data.consent.clone();
     let config_clone = data.config.clone();
 
-    // Create a worker that cleans the ratelimit
+    // Create a worker that cleans the ratelimit, expired failed authentication attempts, nonces and cached consent
     actix_web::rt::spawn(async move {
         let mut interval =
             tokio::time::interval(Duration::from_secs(config_clone.ratelimiter_cleanup_interval));
         loop {
             interval.tick().await;
             cleanup_rate_limiter(&rate_limiter_clone, &config_clone);
+            cleanup_auth_failures(&auth_failures_clone, &config_clone);
+            cleanup_nonces(&nonces_clone);
+            consent_clone.cleanup();
         }
     });
 
+    // Create a worker that purges expired events and sessions
+    if let Some(db_clone) = data.db.clone().filter(|_| retention_enabled(&data.config)) {
+        let config_clone = data.config.clone();
+        let report_clone = data.retention_report.clone();
+
+        actix_web::rt::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(config_clone.retention_interval));
+            loop {
+                interval.tick().await;
+                let report = purge(&db_clone, &config_clone).await;
+                *report_clone.lock() = Some(report);
+            }
+        });
+    }
+
+    // Create a worker that folds new events and sessions into the rollup tables
+    if let Some(db_clone) = data.db.clone().filter(|_| rollups_enabled(&data.config)) {
+        let config_clone = data.config.clone();
+
+        actix_web::rt::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(config_clone.rollup_interval));
+            loop {
+                interval.tick().await;
+                if let Err(e) = compact(&db_clone, &config_clone).await {
+                    log::error!("Rollup compaction failed: {}", e);
+                }
+            }
+        });
+    }
+
+    // Create a worker that backs up the database
+    remove_partial_backups(&data.config);
+
+    if let Some(db_clone) = data.db.clone().filter(|_| backup_enabled(&data.config) && data.config.backup_interval > 0) {
+        let config_clone = data.config.clone();
+        let status_clone = data.backup_status.clone();
+
+        actix_web::rt::spawn(async move {
+            let period = Duration::from_secs(config_clone.backup_interval);
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if try_begin(&status_clone) {
+                    run_backup(db_clone.clone(), config_clone.clone(), status_clone.clone()).await;
+                }
+            }
+        });
+    }
+
+    // Create a worker that moves old events and sessions into Parquet files
+    if let Some(db_clone) = data.db.clone().filter(|_| parquet_archive_enabled(&data.config) && data.config.parquet_archive_interval > 0) {
+        let config_clone = data.config.clone();
+        let status_clone = data.parquet_archive_status.clone();
+
+        actix_web::rt::spawn(async move {
+            let period = Duration::from_secs(config_clone.parquet_archive_interval);
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if try_begin_archive(&status_clone) {
+                    run_archive(db_clone.clone(), config_clone.clone(), status_clone.clone()).await;
+                }
+            }
+        });
+    }
+}
+
+/// Limits and error responses of JSON bodies, per project.
+fn json_config(config: &Config) -> web::JsonConfig {
+    let max_compressed_payload = config.max_compressed_payload;
+
+    web::JsonConfig::default()
+        .limit(config.max_json_payload)
+        .error_handler(move |err, _req| {
+            match err {
+                error::JsonPayloadError::OverflowKnownLength { limit, .. } |
+                error::JsonPayloadError::Overflow { limit } => {
+                    // Handle payload too large error
+                    let response = HttpResponse::PayloadTooLarge().json(
+                        PublicJsonError { message: format!("Payload too large. Maximum size allowed is {} bytes", limit) }
+                    );
+                    error::InternalError::from_response(err, response).into()
+                },
+                error::JsonPayloadError::Payload(error::PayloadError::Overflow) => {
+                    // Handle compressed payload exceeding the compressed size cap
+                    let response = HttpResponse::PayloadTooLarge().json(
+                        PublicJsonError { message: format!("Compressed payload too large. Maximum size allowed is {} bytes", max_compressed_payload) }
+                    );
+                    error::InternalError::from_response(err, response).into()
+                },
+                _ => {
+                    // Handle other JSON parsing errors
+                    let response = HttpResponse::BadRequest().json(
+                        PublicJsonError { message: format!("Invalid JSON: {}", err) }
+                    );
+                    error::InternalError::from_response(err, response).into()
+                }
+            }
+        })
+}
+
+/// The endpoints of a project.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/create_session")
+            .wrap(from_fn(limit_compressed_payload))
+            .wrap(from_fn(require_signed_request))
+            .route(web::post().to(create_session)),
+    )
+    .service(
+        web::resource("/ingest_event")
+            .wrap(from_fn(limit_compressed_payload))
+            .wrap(from_fn(require_signed_request))
+            .route(web::post().to(ingest_event)),
+    )
+    .service(
+        web::resource("/update_consent")
+            .wrap(from_fn(limit_compressed_payload))
+            .wrap(from_fn(require_signed_request))
+            .route(web::post().to(update_consent)),
+    )
+    .service(
+        web::resource("/ingest_events")
+            .wrap(from_fn(require_signed_stream))
+            .route(web::post().to(ingest_events)),
+    )
+    .service(
+        web::resource("/ingest_ws/{session_id}")
+            .wrap(from_fn(require_signed_upgrade))
+            .route(web::get().to(ingest_ws)),
+    )
+    .service(
+        web::resource("/get_events/{session_id}")
+            .wrap(middleware::Compress::default())
+            .wrap(from_fn(mark_partial_reads))
+            .route(web::get().to(get_events)),
+    )
+    .service(web::resource("/get_event_gaps").wrap(from_fn(mark_partial_reads)).route(web::get().to(get_all_event_gaps)))
+    .service(web::resource("/get_event_gaps/{session_id}").wrap(from_fn(mark_partial_reads)).route(web::get().to(get_event_gaps)))
+    .service(
+        web::resource("/get_sessions")
+            .wrap(middleware::Compress::default())
+            .route(web::get().to(get_sessions)),
+    )
+    .service(web::resource("/search").wrap(from_fn(mark_partial_reads)).route(web::get().to(search_events)))
+    .service(web::resource("/stats/events").wrap(from_fn(mark_partial_reads)).route(web::get().to(get_event_stats)))
+    .service(web::resource("/stats/sessions").wrap(from_fn(mark_partial_reads)).route(web::get().to(get_session_stats)))
+    .service(web::resource("/export/events").wrap(from_fn(mark_partial_reads)).route(web::get().to(export_events)))
+    .service(web::resource("/export/sessions").route(web::get().to(export_sessions)))
+    .service(web::resource("/users/{user_id}").route(web::delete().to(erase_user)))
+    .service(web::resource("/users/{user_id}/export").route(web::get().to(export_user)))
+    .service(
+        web::resource("/admin/backup")
+            .route(web::get().to(get_backup))
+            .route(web::post().to(create_backup))
+    )
+    .service(web::resource("/admin/erasures").route(web::get().to(get_erasures)))
+    .service(
+        web::resource("/admin/keys")
+            .route(web::get().to(get_keys))
+            .route(web::post().to(post_key))
+    )
+    .service(web::resource("/admin/keys/{id}").route(web::delete().to(delete_key)))
+    .service(web::resource("/admin/keys/{id}/rotate").route(web::post().to(post_key_rotation)))
+    .service(
+        web::resource("/admin/parquet")
+            .route(web::get().to(get_parquet_archive))
+            .route(web::post().to(create_parquet_archive))
+    )
+    .service(web::resource("/admin/partitions").route(web::get().to(get_partitions)))
+    .service(web::resource("/admin/partitions/{name}/archive").route(web::post().to(archive_partition)))
+    .service(
+        web::resource("/admin/promoted_params")
+            .route(web::get().to(get_promoted_params))
+            .route(web::post().to(promote_param))
+    )
+    .service(web::resource("/admin/promoted_params/{id}").route(web::delete().to(demote_param)))
+    .service(web::resource("/admin/retention").route(web::get().to(get_retention)))
+    .service(web::resource("/metrics").route(web::get().to(get_metrics)))
+    .service(web::resource("/health_check").route(web::get().to(health_check)));
+}
+
+#[actix_web::main]
+pub async fn main() -> std::io::Result<()> {
+    dotenv().ok();
+
+    // Initialize logger
+    env_logger::init();
+
+    // Read the config from env vars
+    let config = Config::from_env();
+    let project_configs = config.project_configs();
+
+    // The default project is served without a URL prefix
+    let data = open_project(config).await?;
+    spawn_workers(&data);
+
+    let mut projects = Vec::with_capacity(project_configs.len());
+    for project_config in project_configs {
+        let project = open_project(project_config).await?;
+        spawn_workers(&project);
+        projects.push(project);
+    }
+
+    let registry = web::Data::new(ProjectRegistry::new(projects));
+
     // Create a clone for the binding
     let config_task = data.config.clone();
 
-    let max_json_payload = config_task.max_json_payload;
-    let allowed_origins = config_task.cors_origins.clone();
-
     // Start server
     let server = HttpServer::new(move || {
-        let json_config = web::JsonConfig::default()
-            .limit(max_json_payload)
-            .error_handler(|err, _req| {
-                match err {
-                    error::JsonPayloadError::OverflowKnownLength { limit, .. } |
-                    error::JsonPayloadError::Overflow { limit } => {
-                        // Handle payload too large error
-                        let response = HttpResponse::PayloadTooLarge().json(
-                            PublicJsonError { message: format!("Payload too large. Maximum size allowed is {} bytes", limit) }
-                        );
-                        error::InternalError::from_response(err, response).into()
-                    },
-                    _ => {
-                        // Handle other JSON parsing errors
-                        let response = HttpResponse::BadRequest().json(
-                            PublicJsonError { message: format!("Invalid JSON: {}", err) }
-                        );
-                        error::InternalError::from_response(err, response).into()
-                    }
-                }
-            });
-
-        App::new()
+        let mut app = App::new()
+            .wrap(from_fn(select_project))
             .wrap(middleware::Logger::default())
-            .wrap(cors_middleware(&allowed_origins))
-            .app_data(json_config)
-            .app_data(data.clone())
-            .service(
-                web::resource("/create_session").route(web::post().to(create_session)),
-            )
-            .service(
-                web::resource("/ingest_event").route(web::post().to(ingest_event)),
-            )
-            .service(web::resource("/get_events/{session_id}").route(web::get().to(get_events)))
-            .service(web::resource("/get_sessions").route(web::get().to(get_sessions)))
-            .service(web::resource("/health_check").route(web::get().to(health_check)))
-    });
+            .app_data(registry.clone());
+
+        // Every project gets its own scope, so handlers pick up its state, limits and origins
+        for project in registry.projects() {
+            app = app.service(
+                web::scope(&format!("/projects/{}", project.config.project.as_deref().unwrap_or_default()))
+                    .wrap(cors_middleware(&project.config.cors_origins))
+                    .app_data(json_config(&project.config))
+                    .app_data(project.clone())
+                    .configure(routes)
+            );
+        }
+
+        app.service(
+            web::scope("")
+                .wrap(cors_middleware(&data.config.cors_origins))
+                .app_data(json_config(&data.config))
+                .app_data(data.clone())
+                .configure(routes)
+        )
+    })
+    .on_connect(tls::on_connect);
+
+    let host = config_task.host.clone();
+
+    if !tls_enabled(&config_task) {
+        if config_task.tls_client_ca_path.is_some() || config_task.http_redirect_port.is_some() {
+            return Err(std::io::Error::other("TLS_CLIENT_CA_PATH and HTTP_REDIRECT_PORT require TLS_CERT_PATH"));
+        }
+
+        return server.bind((host.as_str(), config_task.port))?
+            .run()
+            .await;
+    }
+
+    let cert_path = config_task.tls_cert_path.as_deref().unwrap_or_default();
+    let key_path = config_task.tls_key_path.as_deref()
+        .ok_or_else(|| std::io::Error::other("TLS_KEY_PATH is required with TLS_CERT_PATH"))?;
+
+    let certificate = Arc::new(ReloadingCertificate::load(cert_path, key_path)?);
+    spawn_reload(certificate.clone(), &config_task);
+
+    let https = server.bind_rustls_0_23((host.as_str(), config_task.port), tls::server_config(&config_task, certificate)?)?
+        .run();
+
+    let Some(redirect_port) = config_task.http_redirect_port else {
+        return https.await;
+    };
+
+    // Plain HTTP listener sending every request to the HTTPS port
+    let https_port = config_task.port;
+    let redirect = HttpServer::new(move || {
+        App::new().default_service(web::to(move |req: HttpRequest| redirect_to_https(req, https_port)))
+    })
+    .bind((host.as_str(), redirect_port))?
+    .run();
 
-    server.bind((config_task.host.as_str(), config_task.port))?
-    .run()
-    .await
+    futures_util::future::try_join(https, redirect).await.map(|_| ())
 }
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::consent::{Consent};
+use crate::db_pool::{DbError, DbPool};
+use crate::route_handlers::{now, IngestEventRequest};
+use crate::storage::{Event, NewSession, SequenceSummary, SessionInfo, Storage};
+
+/// The default backend, a SQLite database accessed through `DbPool`.
+pub struct SqliteStorage {
+    db: Arc<DbPool>,
+    /// Consent of sessions created before it was recorded, `CONSENT_DEFAULT`
+    consent_default: bool,
+}
+
+impl SqliteStorage {
+    pub fn new(db: Arc<DbPool>, consent_default: bool) -> SqliteStorage {
+        SqliteStorage { db, consent_default }
+    }
+}
+
+fn get_consent(conn: &Connection, session_id: &str, default: bool) -> rusqlite::Result<Option<Consent>> {
+    conn.query_row(
+        "SELECT consent_analytics, consent_crash_reporting, consent_personalized FROM sessions WHERE session_id = ?1",
+        params![session_id],
+        |row| Ok(Consent {
+            analytics: row.get::<_, Option<bool>>(0)?.unwrap_or(default),
+            crash_reporting: row.get::<_, Option<bool>>(1)?.unwrap_or(default),
+            personalized: row.get::<_, Option<bool>>(2)?.unwrap_or(default),
+        })
+    )
+    .optional()
+}
+
+fn insert_event(conn: &Connection, event: &IngestEventRequest, ip: &str) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT INTO events (session_id, timestamp, event_name, ip_address, params, seq) VALUES (?1, ?2, ?3, ?4, json(?5), ?6)",
+        params![
+            event.session_id,
+            now(),
+            event.event_name,
+            ip,
+            event.data.as_ref().map(|x| x.to_string()),
+            event.seq
+        ],
+    )
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn create_session(&self, session: NewSession) -> Result<(), DbError> {
+        self.db.write(move |conn| {
+            conn.execute(
+                "INSERT INTO sessions (session_id, user_id, start_date, ip_address, device_model, operating_system, screen_width, screen_height, user_agent, consent_analytics, consent_crash_reporting, consent_personalized) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    session.session_id,
+                    session.user_id,
+                    now(),
+                    session.ip_address,
+                    session.device_model,
+                    session.operating_system,
+                    session.screen_width,
+                    session.screen_height,
+                    session.user_agent,
+                    session.consent.analytics,
+                    session.consent.crash_reporting,
+                    session.consent.personalized
+                ],
+            )
+        }).await?;
+
+        Ok(())
+    }
+
+    async fn session_exists(&self, session_id: String) -> Result<bool, DbError> {
+        self.db.read(move |conn| {
+            conn.query_row(
+                "SELECT 1 FROM sessions WHERE session_id = ?1",
+                params![session_id],
+                |_| Ok(())
+            )
+            .optional()
+            .map(|row| row.is_some())
+        }).await
+    }
+
+    async fn get_consent(&self, session_id: String) -> Result<Option<Consent>, DbError> {
+        let default = self.consent_default;
+        self.db.read(move |conn| get_consent(conn, &session_id, default)).await
+    }
+
+    async fn set_consent(&self, session_id: String, consent: Consent) -> Result<bool, DbError> {
+        let updated = self.db.write(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET consent_analytics = ?2, consent_crash_reporting = ?3, consent_personalized = ?4, consent_updated_at = ?5
+                WHERE session_id = ?1",
+                params![session_id, consent.analytics, consent.crash_reporting, consent.personalized, now()],
+            )
+        }).await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn insert_event(&self, event: IngestEventRequest, ip: String) -> Result<(), DbError> {
+        self.db.write(move |conn| insert_event(conn, &event, &ip)).await?;
+        Ok(())
+    }
+
+    async fn insert_events(&self, events: Vec<IngestEventRequest>, ip: String) -> Result<Vec<Result<(), String>>, DbError> {
+        self.db.write(move |conn| {
+            let tx = conn.transaction()?;
+            let mut results = Vec::with_capacity(events.len());
+
+            for event in events {
+                results.push(insert_event(&tx, &event, &ip).map(|_| ()).map_err(|e| e.to_string()));
+            }
+
+            tx.commit()?;
+            Ok(results)
+        }).await
+    }
+
+    async fn get_sessions(&self) -> Result<Vec<SessionInfo>, DbError> {
+        self.db.read(|conn| {
+            let mut stmt = conn
+                .prepare_cached("SELECT session_id, start_date FROM sessions")?;
+
+            let sessions_iter = stmt
+                .query_map([], |row| {
+                    Ok(SessionInfo {
+                        session_id: row.get(0)?,
+                        start_date: row.get(1)?,
+                    })
+                })?;
+
+            sessions_iter.collect::<rusqlite::Result<Vec<SessionInfo>>>()
+        }).await
+    }
+
+    async fn get_events(&self, session_id: String) -> Result<Vec<Event>, DbError> {
+        self.db.read(move |conn| {
+            let mut stmt = conn
+                .prepare_cached(
+                    "SELECT
+                        id,
+                        event_name,
+                        timestamp,
+                        params,
+                        seq
+                    FROM events
+                    WHERE session_id = ?1
+                    ORDER BY seq, id",
+                )?;
+
+            let events_iter = stmt
+                .query_map(params![session_id], |row| {
+                    let params_str: Option<String> = row.get(3)?;
+                    Ok(Event {
+                        id: row.get(0)?,
+                        seq: row.get(4)?,
+                        event_name: row.get(1)?,
+                        time: row.get(2)?,
+                        data: params_str.and_then(|params| serde_json::from_str(&params).ok()),
+                    })
+                })?;
+
+            events_iter.collect::<rusqlite::Result<Vec<Event>>>()
+        }).await
+    }
+
+    async fn get_event_seqs(&self, session_id: String) -> Result<Vec<i64>, DbError> {
+        self.db.read(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT seq FROM events WHERE session_id = ?1 AND seq IS NOT NULL ORDER BY id"
+            )?;
+
+            let rows = stmt.query_map(params![session_id], |row| row.get::<_, i64>(0))?;
+            rows.collect::<rusqlite::Result<Vec<i64>>>()
+        }).await
+    }
+
+    async fn get_sequence_summaries(&self) -> Result<Vec<SequenceSummary>, DbError> {
+        self.db.read(|conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT
+                    session_id,
+                    COUNT(*),
+                    MIN(seq),
+                    MAX(seq),
+                    COUNT(DISTINCT seq),
+                    SUM(out_of_order)
+                FROM (
+                    SELECT
+                        session_id,
+                        seq,
+                        CASE WHEN seq < MAX(seq) OVER (
+                            PARTITION BY session_id ORDER BY id
+                            ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+                        ) THEN 1 ELSE 0 END AS out_of_order
+                    FROM events
+                    WHERE seq IS NOT NULL
+                )
+                GROUP BY session_id
+                ORDER BY session_id"
+            )?;
+
+            let rows = stmt.query_map([], |row| {
+                Ok(SequenceSummary {
+                    session_id: row.get(0)?,
+                    events: row.get(1)?,
+                    first_seq: row.get(2)?,
+                    last_seq: row.get(3)?,
+                    distinct: row.get(4)?,
+                    out_of_order: row.get(5)?,
+                })
+            })?;
+
+            rows.collect::<rusqlite::Result<Vec<SequenceSummary>>>()
+        }).await
+    }
+
+    async fn health_check(&self) -> Result<(), DbError> {
+        self.db.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::{MIGRATIONS};
+
+    #[test]
+    fn sessions_from_before_consent_get_the_default() {
+        let conn = Connection::open_in_memory().unwrap();
+        let (before, after) = MIGRATIONS.split_at(MIGRATIONS.iter().position(|migration| migration.name == "session_consent").unwrap());
+
+        for migration in before {
+            conn.execute_batch(migration.sql).unwrap();
+        }
+        conn.execute("INSERT INTO sessions (session_id, ip_address) VALUES ('old', '')", []).unwrap();
+
+        for migration in after {
+            conn.execute_batch(migration.sql).unwrap();
+        }
+        conn.execute(
+            "INSERT INTO sessions (session_id, ip_address, consent_analytics, consent_crash_reporting, consent_personalized) VALUES ('new', '', 1, 0, 1)",
+            []
+        ).unwrap();
+
+        assert_eq!(get_consent(&conn, "old", false).unwrap(), Some(Consent::all(false)));
+        assert_eq!(get_consent(&conn, "old", true).unwrap(), Some(Consent::all(true)));
+
+        let recorded = Consent { analytics: true, crash_reporting: false, personalized: true };
+        assert_eq!(get_consent(&conn, "new", false).unwrap(), Some(recorded));
+        assert_eq!(get_consent(&conn, "unknown", true).unwrap(), None);
+    }
+}
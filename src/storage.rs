@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{Config, StorageBackend};
+use crate::consent::{Consent};
+use crate::db_pool::{DbError, DbPool};
+use crate::memory_storage::{MemoryStorage};
+use crate::route_handlers::{IngestEventRequest};
+use crate::sqlite_storage::{SqliteStorage};
+
+pub struct NewSession {
+    pub session_id: String,
+    pub user_id: String,
+    pub ip_address: String,
+    pub device_model: Option<String>,
+    pub operating_system: Option<String>,
+    pub screen_width: Option<u64>,
+    pub screen_height: Option<u64>,
+    pub user_agent: Option<String>,
+    pub consent: Consent,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Event {
+    pub id: i64,
+    pub seq: Option<i64>,
+    pub event_name: String,
+    pub time: i64,
+    pub data: Option<Value>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub start_date: i64,
+}
+
+/// Sequence number statistics of one session, see `sequence_report`.
+pub struct SequenceSummary {
+    pub session_id: String,
+    pub events: i64,
+    pub first_seq: i64,
+    pub last_seq: i64,
+    pub distinct: i64,
+    /// Events whose `seq` is lower than one that arrived before it
+    pub out_of_order: i64,
+}
+
+/// What session creation, ingestion, consent and the per-session queries need from a
+/// storage backend. Stats, search, exports and the admin endpoints use `DbPool` directly.
+///
+/// Timestamps are assigned by the backend when a session or event is stored.
+/// Events may only be inserted into existing sessions.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn create_session(&self, session: NewSession) -> Result<(), DbError>;
+
+    async fn session_exists(&self, session_id: String) -> Result<bool, DbError>;
+
+    /// `None` if the session does not exist.
+    async fn get_consent(&self, session_id: String) -> Result<Option<Consent>, DbError>;
+
+    /// Returns false if the session does not exist.
+    async fn set_consent(&self, session_id: String, consent: Consent) -> Result<bool, DbError>;
+
+    async fn insert_event(&self, event: IngestEventRequest, ip: String) -> Result<(), DbError>;
+
+    /// Inserts events in one transaction. The outer error fails the whole batch, the
+    /// returned list holds the outcome of every event in order.
+    async fn insert_events(&self, events: Vec<IngestEventRequest>, ip: String) -> Result<Vec<Result<(), String>>, DbError>;
+
+    async fn get_sessions(&self) -> Result<Vec<SessionInfo>, DbError>;
+
+    /// Events of a session ordered by `seq`, then by arrival.
+    async fn get_events(&self, session_id: String) -> Result<Vec<Event>, DbError>;
+
+    /// Non-null `seq` values of a session in the order the events arrived.
+    async fn get_event_seqs(&self, session_id: String) -> Result<Vec<i64>, DbError>;
+
+    /// Summaries of all sessions with at least one `seq`, ordered by session id.
+    async fn get_sequence_summaries(&self) -> Result<Vec<SequenceSummary>, DbError>;
+
+    async fn health_check(&self) -> Result<(), DbError>;
+}
+
+/// The opened backend and, for SQLite, the pool it runs on.
+pub type OpenedStorage = (Arc<dyn Storage>, Option<Arc<DbPool>>);
+
+/// Opens the configured backend. The SQLite pool is also returned for the features
+/// that work on the database directly, like backups, exports and retention.
+pub fn open_storage(config: &Config) -> Result<OpenedStorage, DbError> {
+    match config.storage_backend {
+        StorageBackend::Sqlite => {
+            let db = Arc::new(DbPool::open(config)?);
+            Ok((Arc::new(SqliteStorage::new(db.clone(), config.consent_default)), Some(db)))
+        },
+        StorageBackend::Memory => {
+            log::warn!("Using in-memory storage, all data is lost when the server stops");
+            Ok((Arc::new(MemoryStorage::default()), None))
+        }
+    }
+}
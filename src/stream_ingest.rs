@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+
+use actix_web::{dev::Decompress, web, HttpRequest, HttpResponse, Responder};
+use futures_util::StreamExt;
+use serde::Serialize;
+
+use crate::db_pool::{DbError};
+use crate::app_state::{AppState};
+use crate::consent::{missing_consent};
+use crate::rate_limit::{check_rate_limit};
+use crate::storage::{Storage};
+use crate::route_handlers::{
+    check_ingest_key,
+    get_request_id,
+    ingest_key_rejected,
+    publish_session_event,
+    database_error,
+    ApiResponse,
+    IngestEventRequest
+};
+
+/// Upper bound for the number of line errors echoed back to the client.
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(Serialize)]
+struct LineError {
+    line: usize,
+    message: String,
+}
+
+#[derive(Serialize, Default)]
+struct StreamIngestSummary {
+    accepted: usize,
+    rejected: usize,
+    /// Valid events the player did not consent to
+    dropped: usize,
+    errors: Vec<LineError>,
+    /// The stream was cut off once the rate limit ran out, later lines were not read
+    rate_limited: bool,
+}
+
+impl StreamIngestSummary {
+    fn reject(&mut self, line: usize, message: String) {
+        self.rejected += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(LineError { line, message });
+        }
+    }
+}
+
+/// Splits a byte stream into lines without holding more than one line in memory.
+struct LineSplitter {
+    buffer: Vec<u8>,
+    max_line_length: usize,
+    line_number: usize,
+    discarding: bool,
+}
+
+enum Line {
+    Complete(usize, Vec<u8>),
+    TooLong(usize),
+}
+
+impl LineSplitter {
+    fn new(max_line_length: usize) -> LineSplitter {
+        LineSplitter {
+            buffer: Vec::new(),
+            max_line_length,
+            line_number: 0,
+            discarding: false,
+        }
+    }
+
+    fn push(&mut self, mut chunk: &[u8], lines: &mut Vec<Line>) {
+        while let Some(pos) = chunk.iter().position(|b| *b == b'\n') {
+            self.line_number += 1;
+
+            if self.discarding || self.buffer.len() + pos > self.max_line_length {
+                lines.push(Line::TooLong(self.line_number));
+            } else {
+                self.buffer.extend_from_slice(&chunk[..pos]);
+                lines.push(Line::Complete(self.line_number, std::mem::take(&mut self.buffer)));
+            }
+
+            self.buffer.clear();
+            self.discarding = false;
+            chunk = &chunk[pos + 1..];
+        }
+
+        if self.discarding {
+            return;
+        }
+
+        if self.buffer.len() + chunk.len() > self.max_line_length {
+            // Keep counting the line but stop buffering it
+            self.buffer.clear();
+            self.discarding = true;
+        } else {
+            self.buffer.extend_from_slice(chunk);
+        }
+    }
+
+    fn finish(&mut self, lines: &mut Vec<Line>) {
+        if self.discarding {
+            self.line_number += 1;
+            lines.push(Line::TooLong(self.line_number));
+        } else if !self.buffer.is_empty() {
+            self.line_number += 1;
+            lines.push(Line::Complete(self.line_number, std::mem::take(&mut self.buffer)));
+        }
+    }
+}
+
+async fn insert_batch(
+    storage: &dyn Storage,
+    batch: Vec<(usize, IngestEventRequest)>,
+    ip: &str,
+    summary: &mut StreamIngestSummary,
+    sessions: &mut HashSet<String>,
+) -> Result<(), DbError> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    let lines = batch.iter().map(|(line, event)| (*line, event.session_id.clone())).collect::<Vec<_>>();
+    let events = batch.into_iter().map(|(_, event)| event).collect();
+
+    let results = storage.insert_events(events, ip.to_string()).await?;
+
+    for ((line, session_id), result) in lines.into_iter().zip(results) {
+        match result {
+            Ok(_) => {
+                summary.accepted += 1;
+                sessions.insert(session_id);
+            },
+            Err(e) => summary.reject(line, format!("Event not ingested: {}", e))
+        }
+    }
+
+    Ok(())
+}
+
+/// Ingests a newline-delimited JSON stream of events.
+///
+/// Every line is parsed on its own and limited to `max_json_payload` bytes; valid
+/// events are written in transactions of `ingest_stream_batch_size` rows. Each valid
+/// event costs `ingest_event_cost` tokens like on `/ingest_event`, once the bucket is
+/// empty the stream is stopped and answered with `429 Too Many Requests`.
+pub async fn ingest_events(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    payload: web::Payload,
+) -> impl Responder {
+    // Check the ingestion key of the project
+    if !check_ingest_key(&req, &data.config) {
+        return ingest_key_rejected();
+    }
+
+    // Rate limiting per IP address
+    let ip = get_request_id(&req, &data).unwrap_or("unknown".to_string());
+
+    if !check_rate_limit(&data, &ip, data.config.ingest_stream_cost) {
+        return HttpResponse::TooManyRequests().json(ApiResponse {
+            success: false,
+            message: "Rate limit exceeded".to_string()
+        });
+    }
+
+    let batch_size = data.config.ingest_stream_batch_size.max(1);
+    let max_stream_payload = data.config.max_stream_payload;
+
+    let mut stream = Decompress::from_headers(payload.into_inner(), req.headers());
+    let mut splitter = LineSplitter::new(data.config.max_json_payload);
+    let mut summary = StreamIngestSummary::default();
+    let mut sessions = HashSet::new();
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut lines = Vec::new();
+    let mut received = 0;
+    let mut finished = false;
+
+    while !finished && !summary.rate_limited {
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                received += chunk.len();
+                if received > max_stream_payload {
+                    return HttpResponse::PayloadTooLarge().json(ApiResponse {
+                        success: false,
+                        message: format!(
+                            "Stream too large. Maximum size allowed is {} bytes, {} events were ingested before",
+                            max_stream_payload, summary.accepted
+                        )
+                    });
+                }
+                splitter.push(&chunk, &mut lines);
+            },
+            Some(Err(e)) => {
+                return HttpResponse::BadRequest().json(ApiResponse {
+                    success: false,
+                    message: format!("Unable to read stream: {}, {} events were ingested before", e, summary.accepted)
+                });
+            },
+            None => {
+                splitter.finish(&mut lines);
+                finished = true;
+            }
+        }
+
+        for line in lines.drain(..) {
+            if summary.rate_limited {
+                break;
+            }
+
+            match line {
+                Line::TooLong(number) => summary.reject(
+                    number,
+                    format!("Line too large. Maximum size allowed is {} bytes", data.config.max_json_payload)
+                ),
+                Line::Complete(number, bytes) => {
+                    if bytes.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    match serde_json::from_slice::<IngestEventRequest>(&bytes) {
+                        Ok(_) if !check_rate_limit(&data, &ip, data.config.ingest_event_cost) => summary.rate_limited = true,
+                        Ok(event) => match missing_consent(&data, &event.session_id, &event.event_name).await {
+                            Ok(None) => batch.push((number, event)),
+                            Ok(Some(_)) => summary.dropped += 1,
+                            Err(e) => {
+                                let context = format!("Events not ingested ({} events were ingested before)", summary.accepted);
+                                return database_error(&context, e);
+                            }
+                        },
+                        Err(e) => summary.reject(number, format!("Invalid JSON: {}", e))
+                    }
+                }
+            }
+        }
+
+        if batch.len() >= batch_size || ((finished || summary.rate_limited) && !batch.is_empty()) {
+            let pending = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+            if let Err(e) = insert_batch(data.storage.as_ref(), pending, &data.ip_anonymizer.anonymize(&ip), &mut summary, &mut sessions).await {
+                let context = format!("Events not ingested ({} events were ingested before)", summary.accepted);
+                return database_error(&context, e);
+            }
+        }
+    }
+
+    // Notify REDIS channel once per touched session
+    for session_id in sessions.iter() {
+        publish_session_event(&data, "evt_session_updated", session_id).await;
+    }
+
+    summary.errors.sort_by_key(|error| error.line);
+
+    match summary.rate_limited {
+        true => HttpResponse::TooManyRequests().json(summary),
+        false => HttpResponse::Ok().json(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::consent::{Consent};
+    use crate::storage::{NewSession};
+
+    async fn session(data: &AppState, session_id: &str) {
+        data.storage.create_session(NewSession {
+            session_id: session_id.to_string(),
+            user_id: "player".to_string(),
+            ip_address: String::new(),
+            device_model: None,
+            operating_system: None,
+            screen_width: None,
+            screen_height: None,
+            user_agent: None,
+            consent: Consent::all(true),
+        }).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn reports_the_outcome_of_every_line() {
+        let data = AppState::in_memory(&[("STORAGE_BACKEND", "memory"), ("MAX_EVENTS_PER_SECOND", "1000"), ("INGEST_STREAM_BATCH_SIZE", "2")]);
+        session(&data, "s1").await;
+
+        let app = test::init_service(App::new().app_data(data.clone()).route("/ingest_events", web::post().to(ingest_events))).await;
+
+        let body = [
+            r#"{"session_id": "s1", "event_name": "a", "seq": 1}"#,
+            "",
+            "not json",
+            r#"{"session_id": "unknown", "event_name": "b"}"#,
+            r#"{"session_id": "s1", "event_name": "c", "seq": 2}"#,
+        ].join("\n");
+
+        let req = test::TestRequest::post().uri("/ingest_events").set_payload(body).to_request();
+        let summary: Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(summary["accepted"], 2);
+        assert_eq!(summary["rejected"], 2);
+        assert_eq!(summary["rate_limited"], false);
+        let lines: Vec<i64> = summary["errors"].as_array().unwrap().iter().map(|error| error["line"].as_i64().unwrap()).collect();
+        assert_eq!(lines, [3, 4]);
+
+        let events = data.storage.get_events("s1".to_string()).await.unwrap();
+        assert_eq!(events.iter().map(|event| event.event_name.as_str()).collect::<Vec<_>>(), ["a", "c"]);
+    }
+
+    #[actix_web::test]
+    async fn stops_once_the_rate_limit_is_exhausted() {
+        // The bucket holds 5 tokens, the stream costs 1 and every event 1
+        let data = AppState::in_memory(&[
+            ("STORAGE_BACKEND", "memory"),
+            ("MAX_EVENTS_PER_SECOND", "5"),
+            ("TOKEN_BUCKET_SIZE", "1"),
+            ("INGEST_STREAM_COST", "1"),
+            ("INGEST_EVENT_COST", "1"),
+        ]);
+        session(&data, "s1").await;
+
+        let app = test::init_service(App::new().app_data(data.clone()).route("/ingest_events", web::post().to(ingest_events))).await;
+
+        let body = (0..10).map(|seq| json!({ "session_id": "s1", "event_name": "a", "seq": seq }).to_string()).collect::<Vec<_>>().join("\n");
+        let req = test::TestRequest::post().uri("/ingest_events").set_payload(body).to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        let summary: Value = test::read_body_json(res).await;
+        assert_eq!(summary["accepted"], 4);
+        assert_eq!(summary["rate_limited"], true);
+    }
+}
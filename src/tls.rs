@@ -0,0 +1,221 @@
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::http::header;
+use actix_web::rt::net::TcpStream;
+use actix_web::{HttpRequest, HttpResponse};
+use parking_lot::{Mutex, RwLock};
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+
+use crate::config::{Config};
+
+/// Connection data of TLS connections that presented a client certificate signed by
+/// `TLS_CLIENT_CA_PATH`. Certificates failing verification abort the handshake.
+pub struct ClientCertificate;
+
+pub fn tls_enabled(config: &Config) -> bool {
+    config.tls_cert_path.is_some()
+}
+
+fn read_certificates(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if certificates.is_empty() {
+        return Err(io::Error::other(format!("No certificates found in {}", path)));
+    }
+
+    Ok(certificates)
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<CertifiedKey> {
+    let certificates = read_certificates(cert_path)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| io::Error::other(format!("No private key found in {}", key_path)))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(io::Error::other)?;
+
+    Ok(CertifiedKey::new(certificates, signing_key))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Hands out the certificate last loaded from `TLS_CERT_PATH` and `TLS_KEY_PATH`.
+/// Reloading only affects new connections.
+#[derive(Debug)]
+pub struct ReloadingCertificate {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<Arc<CertifiedKey>>,
+    loaded: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCertificate {
+    pub fn load(cert_path: &str, key_path: &str) -> io::Result<ReloadingCertificate> {
+        let loaded = (modified(cert_path), modified(key_path));
+
+        Ok(ReloadingCertificate {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// Loads the certificate and key again if `force` is set or either file changed.
+    /// On failure, e.g. while only one of both files was replaced yet, the previous
+    /// certificate stays in use and the next check tries again.
+    pub fn reload(&self, force: bool) {
+        let mut loaded = self.loaded.lock();
+        let current = (modified(&self.cert_path), modified(&self.key_path));
+
+        if !force && current == *loaded {
+            return;
+        }
+
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write() = Arc::new(key);
+                *loaded = current;
+                log::info!("Reloaded TLS certificate {}", self.cert_path);
+            },
+            Err(e) => log::error!("Cannot reload TLS certificate {}: {}", self.cert_path, e)
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().clone())
+    }
+}
+
+/// Builds the rustls configuration. With `TLS_CLIENT_CA_PATH`, clients may present a
+/// certificate signed by that CA; connections without one are still accepted.
+pub fn server_config(config: &Config, certificate: Arc<ReloadingCertificate>) -> io::Result<ServerConfig> {
+    let builder = ServerConfig::builder();
+
+    let builder = match config.tls_client_ca_path.as_deref() {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(path)? {
+                roots.add(certificate).map_err(io::Error::other)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .map_err(io::Error::other)?;
+
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+
+    Ok(builder.with_cert_resolver(certificate))
+}
+
+/// Reloads the certificate when its files change, checked every `TLS_RELOAD_INTERVAL`
+/// seconds, and on `SIGHUP`.
+pub fn spawn_reload(certificate: Arc<ReloadingCertificate>, config: &Config) {
+    if config.tls_reload_interval > 0 {
+        let certificate = certificate.clone();
+        let period = Duration::from_secs(config.tls_reload_interval);
+
+        actix_web::rt::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                certificate.reload(false);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    actix_web::rt::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                log::error!("Cannot listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            certificate.reload(true);
+        }
+    });
+}
+
+/// Records on TLS connections whether a verified client certificate was presented.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = stream.get_ref();
+
+        if session.peer_certificates().is_some_and(|certificates| !certificates.is_empty()) {
+            data.insert(ClientCertificate);
+        }
+    }
+}
+
+/// Sends plain HTTP requests to the same host and path on the HTTPS port.
+pub async fn redirect_to_https(req: HttpRequest, https_port: u16) -> HttpResponse {
+    let connection = req.connection_info();
+    let host = connection.host();
+
+    // Drop the port of the plain listener, keeping bracketed IPv6 addresses intact
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.contains(']') => hostname,
+        _ => host
+    };
+
+    let authority = match https_port {
+        443 => hostname.to_string(),
+        port => format!("{}:{}", hostname, port)
+    };
+
+    let path = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, format!("https://{}{}", authority, path)))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn redirects_to_the_https_port_of_the_host() {
+        let cases = [
+            ("example.com:8080", "/get_sessions?limit=5", 443, "https://example.com/get_sessions?limit=5"),
+            ("example.com", "/", 8443, "https://example.com:8443/"),
+            ("10.0.0.2:80", "/health", 443, "https://10.0.0.2/health"),
+            ("[::1]:8080", "/health", 8443, "https://[::1]:8443/health"),
+            ("[::1]", "/health", 443, "https://[::1]/health"),
+        ];
+
+        for (host, uri, https_port, expected) in cases {
+            let req = TestRequest::get().uri(uri).insert_header((header::HOST, host)).to_http_request();
+            let response = redirect_to_https(req, https_port).await;
+
+            assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+            assert_eq!(response.headers().get(header::LOCATION).unwrap(), expected, "{}", host);
+        }
+    }
+}
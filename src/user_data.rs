@@ -0,0 +1,597 @@
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use rusqlite::{params, types::Value, Connection, OpenFlags, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::app_state::{AppState};
+use crate::auth::{scope, Authorized};
+use crate::body_format::{respond};
+use crate::config::{Config};
+use crate::db_pool::{DbError, DbPool};
+use crate::export::{EventJson, SessionJson};
+use crate::parquet_archive::{EventRow, SessionRow, EVENT_COLUMNS, SESSION_COLUMNS};
+use crate::partitions::{Partitions};
+use crate::retention::{delete_events_in_batches, delete_in_batches};
+use crate::route_handlers::{database_error, now, sqlite_required, ApiResponse};
+
+/// The audit record of an erasure request. The user id is only kept as its SHA-256
+/// hash, enough to answer whether a given player's data was erased.
+#[derive(Serialize)]
+pub struct Erasure {
+    id: i64,
+    user_id_hash: String,
+    /// `SECRET_KEY` or the id and name of the API key
+    requested_by: String,
+    reason: Option<String>,
+    requested_at: i64,
+    /// `None` while running or if the erasure failed
+    completed_at: Option<i64>,
+    sessions_deleted: u64,
+    events_deleted: u64,
+    error: Option<String>,
+    /// Stores the erasure does not reach that held data when it ran, see `retained_copies`
+    retained_in: Vec<String>,
+}
+
+impl Erasure {
+    const COLUMNS: &'static str = "id, user_id_hash, requested_by, reason, requested_at, completed_at, \
+        sessions_deleted, events_deleted, error, retained_in";
+
+    fn from_row(row: &Row) -> rusqlite::Result<Erasure> {
+        Ok(Erasure {
+            id: row.get(0)?,
+            user_id_hash: row.get(1)?,
+            requested_by: row.get(2)?,
+            reason: row.get(3)?,
+            requested_at: row.get(4)?,
+            completed_at: row.get(5)?,
+            sessions_deleted: row.get(6)?,
+            events_deleted: row.get(7)?,
+            error: row.get(8)?,
+            retained_in: row.get::<_, Option<String>>(9)?
+                .map(|stores| stores.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UserExportQuery {
+    /// `json` (default) or `zip`
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EraseUserQuery {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ErasuresQuery {
+    /// Only the erasures of this user id
+    user_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UserExport<'a> {
+    user_id: &'a str,
+    exported_at: i64,
+    sessions: Vec<SessionJson<'a>>,
+    events: Vec<EventJson<'a>>,
+    /// Stores that may hold more data of the user but are not searched
+    not_included: Vec<&'static str>,
+}
+
+fn hash_user_id(user_id: &str) -> String {
+    format!("{:x}", Sha256::digest(user_id.as_bytes()))
+}
+
+fn has_entries(dir: &Path) -> bool {
+    fs::read_dir(dir).map(|mut entries| entries.next().is_some()).unwrap_or(false)
+}
+
+/// Stores outside the database that can hold copies of a player's data and are neither
+/// exported nor erased: archived partitions, Parquet archives and backups.
+fn retained_copies(data: &AppState) -> Vec<&'static str> {
+    let partition_archive = data.db.as_ref()
+        .and_then(|db| db.partitions().map(|partitions| has_entries(partitions.archive_dir())))
+        .unwrap_or(false);
+    let configured = |dir: &Option<String>| dir.as_deref().is_some_and(|dir| has_entries(Path::new(dir)));
+
+    [
+        ("partition_archive", partition_archive),
+        ("parquet_archive", configured(&data.config.parquet_archive_dir)),
+        ("backups", configured(&data.config.backup_dir)),
+    ].into_iter().filter(|(_, held)| *held).map(|(store, _)| store).collect()
+}
+
+fn session_ids(conn: &Connection, user_id: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached("SELECT session_id FROM main.sessions WHERE user_id = ?1")?;
+    let rows = stmt.query_map(params![user_id], |row| row.get(0))?;
+    rows.collect()
+}
+
+fn select_events(conn: &Connection, table: &str, session_id: &str) -> rusqlite::Result<Vec<EventRow>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM {} WHERE session_id = ?1", EVENT_COLUMNS, table))?;
+    let rows = stmt.query_map(params![session_id], EventRow::from_row)?;
+    rows.collect()
+}
+
+/// The sessions of a user and their events, from the attached and the unattached partitions.
+fn load_user_data(
+    conn: &Connection,
+    user_id: &str,
+    unattached: &[(PathBuf, String)],
+) -> rusqlite::Result<(Vec<SessionRow>, Vec<EventRow>)> {
+    let sessions = {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM main.sessions WHERE user_id = ?1 ORDER BY start_date, rowid",
+            SESSION_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![user_id], SessionRow::from_row)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+
+    let mut events = Vec::new();
+
+    for session in sessions.iter() {
+        events.extend(select_events(conn, "events", &session.session_id)?);
+    }
+
+    for (path, table) in unattached {
+        let partition = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        for session in sessions.iter() {
+            events.extend(select_events(&partition, table, &session.session_id)?);
+        }
+    }
+
+    events.sort_by_key(|event| (event.timestamp, event.id));
+
+    Ok((sessions, events))
+}
+
+fn zip_bundle(sessions: &[SessionJson], events: &[EventJson]) -> Result<Vec<u8>, DbError> {
+    let zip_error = |e: zip::result::ZipError| DbError::Failed(format!("ZIP: {}", e));
+    let json_error = |e: serde_json::Error| DbError::Failed(format!("JSON: {}", e));
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+
+    zip.start_file("sessions.json", options).map_err(zip_error)?;
+    zip.write_all(&serde_json::to_vec_pretty(sessions).map_err(json_error)?)?;
+
+    zip.start_file("events.json", options).map_err(zip_error)?;
+    zip.write_all(&serde_json::to_vec_pretty(events).map_err(json_error)?)?;
+
+    Ok(zip.finish().map_err(zip_error)?.into_inner())
+}
+
+fn attachment(filename: &str) -> ContentDisposition {
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename.to_string())],
+    }
+}
+
+/// Everything stored about a player, for a data subject access request, e.g.
+/// `GET /users/<user_id>/export?format=zip`. The JSON bundle holds the sessions and
+/// their events; the ZIP bundle holds the same as `sessions.json` and `events.json`.
+pub async fn export_user(
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Export>,
+    path: web::Path<String>,
+    query: web::Query<UserExportQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let zip = match query.format.as_deref().unwrap_or("json") {
+        "json" => false,
+        "zip" => true,
+        _ => return Ok(HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: "Expected format json or zip".to_string()
+        }))
+    };
+
+    let user_id = path.into_inner();
+    let unattached = db.partitions().map(Partitions::unattached_tables).unwrap_or_default();
+    let not_included = retained_copies(&data);
+
+    let id = user_id.clone();
+    let (sessions, events) = match db.read(move |conn| load_user_data(conn, &id, &unattached)).await {
+        Ok(rows) => rows,
+        Err(e) => return Ok(database_error("User data not exported", e))
+    };
+
+    if sessions.is_empty() {
+        let message = match not_included.is_empty() {
+            true => "No data stored for this user".to_string(),
+            false => format!("No data stored for this user in the database, not searched: {}", not_included.join(", "))
+        };
+        return Ok(HttpResponse::NotFound().json(ApiResponse { success: false, message }));
+    }
+
+    let bundle = UserExport {
+        user_id: &user_id,
+        exported_at: now(),
+        sessions: sessions.iter().map(SessionRow::json).collect(),
+        events: events.iter().map(EventRow::json).collect(),
+        not_included,
+    };
+
+    if !zip {
+        return Ok(HttpResponse::Ok()
+            .insert_header(attachment("user-data.json"))
+            .json(bundle));
+    }
+
+    match zip_bundle(&bundle.sessions, &bundle.events) {
+        Ok(body) => Ok(HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header(attachment("user-data.zip"))
+            .body(body)),
+        Err(e) => Ok(database_error("User data not exported", e))
+    }
+}
+
+/// Deletes the events of `session_ids` from the partitions no `events` view covers.
+fn erase_unattached_events(unattached: &[(PathBuf, String)], session_ids: &[String], busy_timeout: Duration) -> rusqlite::Result<u64> {
+    let mut deleted = 0;
+
+    for (path, table) in unattached {
+        let partition = Connection::open(path)?;
+        partition.busy_timeout(busy_timeout)?;
+
+        for session_id in session_ids {
+            deleted += partition.execute(&format!("DELETE FROM {} WHERE session_id = ?1", table), params![session_id])? as u64;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Deletes the sessions of a user and their events in batches, events first, so an
+/// interrupted erasure never leaves events without their session. Counts what it
+/// deleted into `erasure`, also if it fails halfway.
+async fn erase(db: &Arc<DbPool>, config: &Config, user_id: String, erasure: &mut Erasure) -> Result<(), DbError> {
+    let batch_size = config.retention_batch_size.max(1);
+    let session_ids = db.read(move |conn| session_ids(conn, &user_id)).await?;
+
+    for session_id in session_ids.iter() {
+        // The changes counted while deleting include search index rows removed by
+        // triggers, so the events are counted up front
+        let id = session_id.clone();
+        let events = db.read(move |conn| conn.query_row(
+            "SELECT COUNT(*) FROM events WHERE session_id = ?1",
+            params![id],
+            |row| row.get::<_, u64>(0)
+        )).await?;
+
+        delete_events_in_batches(
+            db,
+            "DELETE FROM {events} WHERE id IN (SELECT id FROM {events} WHERE session_id = ?1 LIMIT ?2)",
+            vec![Value::Text(session_id.clone())],
+            batch_size,
+        ).await?;
+        erasure.events_deleted += events;
+    }
+
+    let unattached = db.partitions().map(Partitions::unattached_tables).unwrap_or_default();
+
+    if !unattached.is_empty() {
+        let session_ids = session_ids.clone();
+        let busy_timeout = Duration::from_millis(config.db_busy_timeout);
+        erasure.events_deleted += db.write_unbounded(move |_| erase_unattached_events(&unattached, &session_ids, busy_timeout)).await?;
+    }
+
+    // Triggers only remove the index rows of events deleted from attached partitions,
+    // events of unattached and archived partitions keep theirs
+    for session_id in session_ids.iter() {
+        delete_in_batches(
+            db,
+            "DELETE FROM main.event_search WHERE rowid IN (SELECT rowid FROM main.event_search WHERE session_id = ?1 LIMIT ?2)".to_string(),
+            vec![Value::Text(session_id.clone())],
+            batch_size,
+        ).await?;
+    }
+
+    for session_id in session_ids {
+        erasure.sessions_deleted += delete_in_batches(
+            db,
+            "DELETE FROM main.sessions WHERE rowid IN (SELECT rowid FROM main.sessions WHERE session_id = ?1 LIMIT ?2)".to_string(),
+            vec![Value::Text(session_id)],
+            batch_size,
+        ).await?;
+    }
+
+    Ok(())
+}
+
+/// Erases everything stored about a player, e.g. `DELETE /users/<user_id>?reason=...`.
+/// The request is recorded before anything is deleted and completed with the counts
+/// afterwards, so failed erasures show up in `/admin/erasures` as well. Archives and
+/// backups the erasure cannot reach are named in `retained_in`.
+pub async fn erase_user(
+    data: web::Data<AppState>,
+    auth: Authorized<scope::Delete>,
+    path: web::Path<String>,
+    query: web::Query<EraseUserQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let user_id = path.into_inner();
+    let mut erasure = Erasure {
+        id: 0,
+        user_id_hash: hash_user_id(&user_id),
+        requested_by: auth.principal().to_string(),
+        reason: query.into_inner().reason.filter(|reason| !reason.trim().is_empty()),
+        requested_at: now(),
+        completed_at: None,
+        sessions_deleted: 0,
+        events_deleted: 0,
+        error: None,
+        retained_in: retained_copies(&data).into_iter().map(str::to_string).collect(),
+    };
+
+    let (user_id_hash, requested_by, reason, requested_at) =
+        (erasure.user_id_hash.clone(), erasure.requested_by.clone(), erasure.reason.clone(), erasure.requested_at);
+    let retained_in = Some(erasure.retained_in.join(",")).filter(|stores| !stores.is_empty());
+
+    erasure.id = match db.write(move |conn| {
+        conn.execute(
+            "INSERT INTO main.erasures (user_id_hash, requested_by, reason, requested_at, retained_in) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![user_id_hash, requested_by, reason, requested_at, retained_in],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }).await {
+        Ok(id) => id,
+        Err(e) => return Ok(database_error("Erasure not recorded", e))
+    };
+
+    let result = erase(db, &data.config, user_id, &mut erasure).await;
+
+    match result.as_ref() {
+        Ok(()) => erasure.completed_at = Some(now()),
+        Err(e) => erasure.error = Some(e.to_string())
+    }
+
+    log::info!(
+        "Erasure {} requested by {}: {} sessions and {} events deleted{}{}",
+        erasure.id, erasure.requested_by, erasure.sessions_deleted, erasure.events_deleted,
+        Some(erasure.retained_in.join(", ")).filter(|stores| !stores.is_empty()).map(|stores| format!(", retained in {}", stores)).unwrap_or_default(),
+        erasure.error.as_deref().map(|error| format!(", failed: {}", error)).unwrap_or_default()
+    );
+
+    let (id, completed_at, sessions_deleted, events_deleted, error) =
+        (erasure.id, erasure.completed_at, erasure.sessions_deleted, erasure.events_deleted, erasure.error.clone());
+
+    let recorded = db.write(move |conn| conn.execute(
+        "UPDATE main.erasures SET completed_at = ?2, sessions_deleted = ?3, events_deleted = ?4, error = ?5 WHERE id = ?1",
+        params![id, completed_at, sessions_deleted, events_deleted, error],
+    )).await;
+
+    match (result, recorded) {
+        (Err(e), _) => Ok(database_error("User data not erased completely, retry the request", e)),
+        (Ok(()), Err(e)) => Ok(database_error("Erasure not recorded", e)),
+        (Ok(()), Ok(_)) => Ok(HttpResponse::Ok().json(erasure))
+    }
+}
+
+/// The audit records of erasure requests, newest first, e.g.
+/// `GET /admin/erasures?user_id=<user_id>` to check whether a player's data was erased.
+pub async fn get_erasures(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    _auth: Authorized<scope::Admin>,
+    query: web::Query<ErasuresQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(db) = data.db.as_ref() else {
+        return Ok(sqlite_required());
+    };
+
+    let user_id_hash = query.user_id.as_deref().map(hash_user_id);
+
+    let erasures = db.read(move |conn| {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM main.erasures WHERE ?1 IS NULL OR user_id_hash = ?1 ORDER BY id DESC",
+            Erasure::COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![user_id_hash], Erasure::from_row)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()
+    }).await;
+
+    match erasures {
+        Ok(erasures) => Ok(respond(&req, &erasures, data.config.compress_min_response_size)),
+        Err(e) => Ok(database_error("Failed to list erasures", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test as web_test, App};
+    use serde_json::{json, Value as Json};
+
+    use crate::app_state::{TestDir};
+    use crate::consent::{Consent};
+    use crate::route_handlers::{IngestEventRequest};
+    use crate::storage::{NewSession};
+
+    use super::*;
+
+    const VARS: [(&str, &str); 5] = [
+        ("DB_PARTITIONING", "day"),
+        ("DB_PARTITIONS_ATTACHED", "2"),
+        ("SEARCH_EVENT_NAMES", "crash"),
+        ("SECRET_KEY", "secret"),
+        ("RETENTION_BATCH_SIZE", "1"),
+    ];
+
+    /// Partitions of two earlier days, only the newer one is attached next to the current.
+    const OLD_PARTITIONS: [(&str, i64); 2] = [("2020-01-01", 20200101_0000000000), ("2020-01-02", 20200102_0000000000)];
+
+    fn old_partition(dir: &TestDir, key: &str, first_id: i64) {
+        let conn = Connection::open(dir.file(&format!("analytics.events.{}.db", key))).unwrap();
+        conn.execute_batch(&format!(
+            "CREATE TABLE events_{table} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT, session_id TEXT NOT NULL, event_name TEXT NOT NULL,
+                timestamp TIMESTAMP NOT NULL, ip_address TEXT NOT NULL, params TEXT, seq INTEGER
+            );
+            INSERT INTO events_{table} VALUES ({s1}, 's1', 'crash', 0, '', '{{\"error\": \"old\"}}', NULL);
+            INSERT INTO events_{table} VALUES ({s2}, 's2', 'crash', 0, '', '{{\"error\": \"old\"}}', NULL);",
+            table = key.replace('-', "_"),
+            s1 = first_id + 1,
+            s2 = first_id + 2
+        )).unwrap();
+    }
+
+    /// Sessions `s1` of `u1` and `s2` of `u2`, each with an event in every partition and
+    /// every event in the search index.
+    async fn project(dir: &TestDir) -> web::Data<AppState> {
+        for (key, first_id) in OLD_PARTITIONS {
+            old_partition(dir, key, first_id);
+        }
+
+        let data = AppState::on_sqlite(dir, &VARS);
+
+        for (session_id, user_id) in [("s1", "u1"), ("s2", "u2")] {
+            data.storage.create_session(NewSession {
+                session_id: session_id.to_string(),
+                user_id: user_id.to_string(),
+                ip_address: String::new(),
+                device_model: None,
+                operating_system: None,
+                screen_width: None,
+                screen_height: None,
+                user_agent: None,
+                consent: Consent::all(true),
+            }).await.unwrap();
+
+            let event: IngestEventRequest = serde_json::from_value(json!({
+                "session_id": session_id, "event_name": "crash", "data": { "error": "new" }
+            })).unwrap();
+            data.storage.insert_event(event, String::new()).await.unwrap();
+        }
+
+        // Events of earlier partitions were indexed when they were ingested
+        data.db.as_ref().unwrap().write(|conn| {
+            for (_, first_id) in OLD_PARTITIONS {
+                conn.execute(
+                    "INSERT INTO event_search (rowid, content, session_id, event_name, timestamp)
+                    VALUES (?1, 'old', 's1', 'crash', 0), (?2, 'old', 's2', 'crash', 0)",
+                    params![first_id + 1, first_id + 2],
+                )?;
+            }
+            Ok(())
+        }).await.unwrap();
+
+        data
+    }
+
+    /// Events of `session_id` per partition, oldest first, and its search index rows.
+    async fn stored(data: &AppState, dir: &TestDir, session_id: &'static str) -> (Vec<i64>, i64) {
+        let unattached = Connection::open(dir.file("analytics.events.2020-01-01.db")).unwrap();
+        let mut counts = vec![unattached.query_row(
+            "SELECT COUNT(*) FROM events_2020_01_01 WHERE session_id = ?1", params![session_id], |row| row.get(0)
+        ).unwrap()];
+
+        let (attached, indexed) = data.db.as_ref().unwrap().read(move |conn| {
+            let mut counts = Vec::new();
+            for (schema, table) in crate::partitions::attached_event_tables(conn)?.into_iter().skip(1) {
+                counts.push(conn.query_row(
+                    &format!("SELECT COUNT(*) FROM {}.{} WHERE session_id = ?1", schema, table),
+                    params![session_id],
+                    |row| row.get::<_, i64>(0)
+                )?);
+            }
+            let indexed = conn.query_row("SELECT COUNT(*) FROM event_search WHERE session_id = ?1", params![session_id], |row| row.get(0))?;
+            Ok((counts, indexed))
+        }).await.unwrap();
+
+        counts.extend(attached);
+        (counts, indexed)
+    }
+
+    async fn erasures(data: &AppState) -> Vec<Erasure> {
+        data.db.as_ref().unwrap().read(|conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM erasures ORDER BY id", Erasure::COLUMNS))?;
+            let rows = stmt.query_map([], Erasure::from_row)?;
+            rows.collect()
+        }).await.unwrap()
+    }
+
+    fn authorized(request: web_test::TestRequest) -> web_test::TestRequest {
+        request.insert_header(("X-RLA-KEY", "secret"))
+    }
+
+    #[actix_web::test]
+    async fn erases_events_of_every_partition_and_their_search_entries() {
+        let dir = TestDir::new("erase");
+        let data = project(&dir).await;
+
+        let app = web_test::init_service(App::new().app_data(data.clone())
+            .route("/users/{user_id}", web::delete().to(erase_user))
+            .route("/users/{user_id}/export", web::get().to(export_user))).await;
+
+        let response = web_test::call_service(&app, authorized(web_test::TestRequest::get().uri("/users/u1/export")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let bundle: Json = web_test::read_body_json(response).await;
+        assert_eq!(bundle["events"].as_array().unwrap().len(), 3);
+
+        let response = web_test::call_service(&app, authorized(web_test::TestRequest::delete().uri("/users/u1?reason=ticket")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let erasure: Json = web_test::read_body_json(response).await;
+        assert_eq!((erasure["sessions_deleted"].as_u64(), erasure["events_deleted"].as_u64()), (Some(1), Some(3)));
+
+        assert_eq!(stored(&data, &dir, "s1").await, (vec![0, 0, 0], 0));
+        assert_eq!(stored(&data, &dir, "s2").await, (vec![1, 1, 1], 3));
+
+        let records = erasures(&data).await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].user_id_hash, hash_user_id("u1"));
+        assert_eq!(records[0].reason.as_deref(), Some("ticket"));
+        assert!(records[0].completed_at.is_some() && records[0].error.is_none());
+
+        // Erased and unknown players have nothing left to export
+        for uri in ["/users/u1/export", "/users/unknown/export"] {
+            let response = web_test::call_service(&app, authorized(web_test::TestRequest::get().uri(uri)).to_request()).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_web::test]
+    async fn records_erasures_that_fail_halfway() {
+        let dir = TestDir::new("erase-failure");
+        let data = project(&dir).await;
+
+        Connection::open(dir.file("analytics.events.2020-01-01.db")).unwrap()
+            .execute_batch("ALTER TABLE events_2020_01_01 RENAME TO unreadable").unwrap();
+
+        let app = web_test::init_service(App::new().app_data(data.clone())
+            .route("/users/{user_id}", web::delete().to(erase_user))).await;
+
+        let response = web_test::call_service(&app, authorized(web_test::TestRequest::delete().uri("/users/u1")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // The attached partitions were erased, the session is kept for a retry
+        let records = erasures(&data).await;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].completed_at.is_none() && records[0].error.is_some());
+        assert_eq!((records[0].sessions_deleted, records[0].events_deleted), (0, 2));
+        assert!(data.storage.session_exists("s1".to_string()).await.unwrap());
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::{Duration, Instant};
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Session};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::app_state::{AppState};
+use crate::body_format::{BodyFormat};
+use crate::consent::{dropped_message, missing_consent};
+use crate::rate_limit::{check_rate_limit, RateLimitInfo};
+use crate::route_handlers::{
+    check_ingest_key,
+    database_error,
+    get_request_id,
+    ingest_key_rejected,
+    publish_session_event,
+    ApiResponse,
+    IngestEventRequest
+};
+
+/// A single event frame. The session is fixed when the channel is opened.
+#[derive(Deserialize)]
+struct WebSocketEvent {
+    event_name: String,
+    data: Option<Value>,
+    seq: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct WebSocketAck {
+    seq: u64,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl WebSocketAck {
+    fn ok(seq: u64) -> WebSocketAck {
+        WebSocketAck { seq, success: true, message: None }
+    }
+
+    fn error(seq: u64, message: String) -> WebSocketAck {
+        WebSocketAck { seq, success: false, message: Some(message) }
+    }
+}
+
+/// An open channel counted against `WS_MAX_CONNECTIONS_PER_IP` until it is dropped.
+struct ConnectionSlot {
+    data: web::Data<AppState>,
+    ip: String,
+}
+
+impl ConnectionSlot {
+    fn acquire(data: &web::Data<AppState>, ip: &str) -> Option<ConnectionSlot> {
+        let mut connections = data.ws_connections.lock();
+        let open = connections.entry(ip.to_string()).or_insert(0);
+
+        if *open >= data.config.ws_max_connections_per_ip {
+            return None;
+        }
+
+        *open += 1;
+        Some(ConnectionSlot { data: data.clone(), ip: ip.to_string() })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.data.ws_connections.lock();
+
+        if let Some(open) = connections.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+struct Connection {
+    data: web::Data<AppState>,
+    session_id: String,
+    ip: String,
+    seq: u64,
+    rate_limit: RateLimitInfo,
+    _slot: ConnectionSlot,
+}
+
+impl Connection {
+    /// Charges an event against the connection. The IP address is charged once when the
+    /// channel opens and its number of channels is capped, which bounds its event rate.
+    fn consume_token(&mut self) -> bool {
+        let rate_limit = self.data.config.ws_max_events_per_second;
+        let max_tokens = rate_limit * self.data.config.token_bucket_size;
+        let cost = self.data.config.ingest_event_cost;
+
+        self.rate_limit.try_consume(Instant::now(), rate_limit, max_tokens, cost)
+    }
+
+    async fn handle_frame(&mut self, format: BodyFormat, frame: &[u8]) -> WebSocketAck {
+        self.seq += 1;
+
+        if !self.consume_token() {
+            return WebSocketAck::error(self.seq, "Rate limit exceeded".to_string());
+        }
+
+        let event = match format.deserialize::<WebSocketEvent>(frame) {
+            Ok(event) => IngestEventRequest {
+                session_id: self.session_id.clone(),
+                event_name: event.event_name,
+                data: event.data,
+                seq: event.seq,
+            },
+            Err(e) => return WebSocketAck::error(self.seq, format!("Invalid frame: {}", e))
+        };
+
+        match missing_consent(&self.data, &self.session_id, &event.event_name).await {
+            Ok(None) => {},
+            Ok(Some(category)) => return WebSocketAck { seq: self.seq, success: true, message: Some(dropped_message(category)) },
+            Err(e) => return WebSocketAck::error(self.seq, format!("Event not ingested: {}", e))
+        }
+
+        match self.data.storage.insert_event(event, self.data.ip_anonymizer.anonymize(&self.ip)).await {
+            Ok(_) => {
+                // Notify REDIS channel that sessionId was updated
+                publish_session_event(&self.data, "evt_session_updated", &self.session_id).await;
+                WebSocketAck::ok(self.seq)
+            },
+            Err(e) => WebSocketAck::error(self.seq, format!("Event not ingested: {}", e))
+        }
+    }
+}
+
+async fn send_ack(session: &mut Session, ack: &WebSocketAck) -> bool {
+    match serde_json::to_string(ack) {
+        Ok(text) => session.text(text).await.is_ok(),
+        Err(_) => false
+    }
+}
+
+/// Opens a WebSocket channel for an existing session.
+///
+/// Every text (JSON) or binary (MessagePack) frame carries one event and is answered
+/// with an acknowledgement holding the frame's sequence number on this connection.
+pub async fn ingest_ws(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Payload,
+) -> Result<HttpResponse, Error> {
+    // Check the ingestion key of the project
+    if !check_ingest_key(&req, &data.config) {
+        return Ok(ingest_key_rejected());
+    }
+
+    // Rate limiting per IP address, events are charged against the channel
+    let ip = get_request_id(&req, &data).unwrap_or("unknown".to_string());
+
+    if !check_rate_limit(&data, &ip, data.config.ws_connect_cost) {
+        return Ok(HttpResponse::TooManyRequests().json(ApiResponse {
+            success: false,
+            message: "Rate limit exceeded".to_string()
+        }));
+    }
+
+    let slot = match ConnectionSlot::acquire(&data, &ip) {
+        Some(slot) => slot,
+        None => {
+            return Ok(HttpResponse::TooManyRequests().json(ApiResponse {
+                success: false,
+                message: "Too many open channels".to_string()
+            }));
+        }
+    };
+
+    let session_id = path.into_inner();
+
+    match data.storage.session_exists(session_id.clone()).await {
+        Ok(true) => {},
+        Ok(false) => {
+            return Ok(HttpResponse::Unauthorized().json(ApiResponse {
+                success: false,
+                message: "Unknown session".to_string()
+            }));
+        },
+        Err(e) => return Ok(database_error("Session not verified", e))
+    }
+
+    let (response, mut session, stream) = actix_ws::handle(&req, body)?;
+
+    let mut stream = stream
+        .max_frame_size(data.config.max_json_payload)
+        .aggregate_continuations()
+        .max_continuation_size(data.config.max_json_payload);
+
+    let idle_timeout = Duration::from_secs(data.config.ws_idle_timeout);
+    let rate_limit = RateLimitInfo::new(data.config.ws_max_events_per_second * data.config.token_bucket_size, Instant::now());
+
+    let mut connection = Connection {
+        data,
+        session_id,
+        ip,
+        seq: 0,
+        rate_limit,
+        _slot: slot,
+    };
+
+    actix_web::rt::spawn(async move {
+        let reason = loop {
+            let message = match tokio::time::timeout(idle_timeout, stream.recv()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(e))) => break Some(CloseReason { code: CloseCode::Protocol, description: Some(e.to_string()) }),
+                Ok(None) => break None,
+                Err(_) => break Some(CloseReason { code: CloseCode::Away, description: Some("Idle timeout".to_string()) }),
+            };
+
+            let ack = match message {
+                AggregatedMessage::Text(text) => connection.handle_frame(BodyFormat::Json, text.as_bytes()).await,
+                AggregatedMessage::Binary(bytes) => connection.handle_frame(BodyFormat::MessagePack, &bytes).await,
+                AggregatedMessage::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                    continue;
+                },
+                AggregatedMessage::Pong(_) => continue,
+                AggregatedMessage::Close(reason) => break reason,
+            };
+
+            if !send_ack(&mut session, &ack).await {
+                return;
+            }
+        };
+
+        let _ = session.close(reason).await;
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app_state::AppState;
+
+    use super::*;
+
+    #[test]
+    fn caps_open_channels_per_ip() {
+        let data = AppState::in_memory(&[("WS_MAX_CONNECTIONS_PER_IP", "2")]);
+
+        let first = ConnectionSlot::acquire(&data, "1.2.3.4").unwrap();
+        let _second = ConnectionSlot::acquire(&data, "1.2.3.4").unwrap();
+        assert!(ConnectionSlot::acquire(&data, "1.2.3.4").is_none());
+        assert!(ConnectionSlot::acquire(&data, "5.6.7.8").is_some());
+
+        drop(first);
+        assert!(ConnectionSlot::acquire(&data, "1.2.3.4").is_some());
+    }
+}
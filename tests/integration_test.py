@@ -11,6 +11,9 @@ import requests
 import sqlite3
 import tempfile
 import json
+import gzip
+import hmac
+import hashlib
 
 def generate_secret_key(length=32):
     return ''.join(random.choices(string.ascii_letters + string.digits, k=length))
@@ -241,6 +244,165 @@ def test_ingest_event_with_data(BASE_URL, cursor, session_id):
     except Exception as e:
         print(f'⍜ Test 7 Failed: {e}')
 
+def test_ingest_event_gzip(BASE_URL, cursor, session_id):
+    event_data = {
+        'session_id': session_id,
+        'event_name': 'test-gzip-event',
+        'data': {'floor': 3}
+    }
+    try:
+        body = gzip.compress(json.dumps(event_data).encode('utf-8'))
+        headers = {'Content-Type': 'application/json', 'Content-Encoding': 'gzip'}
+        response = requests.post(f'{BASE_URL}/ingest_event', data=body, headers=headers)
+        if response.status_code == 200:
+            cursor.execute("SELECT * FROM events WHERE session_id = ? AND event_name = ?", (session_id, 'test-gzip-event'))
+            row = cursor.fetchone()
+            if row is None:
+                print('⍜ Test 9 Failed: Event not found in database')
+            else:
+                print('⦿ Test 9 Passed: gzip compressed event stored in database')
+        else:
+            print(f'⍜ Test 9 Failed: Request failed with status code {response.status_code}')
+    except Exception as e:
+        print(f'⍜ Test 9 Failed: {e}')
+
+def test_api_keys(BASE_URL, SECRET_KEY):
+    admin = {'X-RLA-KEY': SECRET_KEY}
+    try:
+        response = requests.post(f'{BASE_URL}/admin/keys', headers=admin, json={'name': 'too-long', 'scopes': ['read_sessions'], 'expires_in_days': 2**63})
+        assert response.status_code == 400, f"Expected 400 for an expiry out of range, got {response.status_code}"
+
+        response = requests.post(f'{BASE_URL}/admin/keys', headers=admin, json={'name': 'dashboard', 'scopes': ['read_sessions'], 'expires_in_days': 30})
+        assert response.status_code == 201, f"Expected 201, got {response.status_code}"
+        issued = response.json()
+
+        response = requests.get(f'{BASE_URL}/get_sessions', headers={'Authorization': f"Bearer {issued['key']}"})
+        assert response.status_code == 200, f"Expected the key to read sessions, got {response.status_code}"
+
+        response = requests.get(f'{BASE_URL}/admin/keys', headers={'X-RLA-KEY': issued['key']})
+        assert response.status_code == 401, f"Expected 401 without the admin scope, got {response.status_code}"
+
+        response = requests.post(f"{BASE_URL}/admin/keys/{issued['id']}/rotate?grace_period={2**63}", headers=admin)
+        assert response.status_code == 400, f"Expected 400 for a grace period out of range, got {response.status_code}"
+
+        response = requests.post(f"{BASE_URL}/admin/keys/{issued['id']}/rotate?grace_period=60", headers=admin)
+        assert response.status_code == 200, f"Expected 200, got {response.status_code}"
+        rotated = response.json()
+
+        for key in (issued['key'], rotated['key']):
+            response = requests.get(f'{BASE_URL}/get_sessions', headers={'X-RLA-KEY': key})
+            assert response.status_code == 200, f"Expected both values to work during the grace period, got {response.status_code}"
+
+        response = requests.delete(f"{BASE_URL}/admin/keys/{issued['id']}", headers=admin)
+        assert response.status_code == 200, f"Expected 200, got {response.status_code}"
+
+        response = requests.get(f'{BASE_URL}/get_sessions', headers={'X-RLA-KEY': rotated['key']})
+        assert response.status_code == 401, f"Expected 401 for a revoked key, got {response.status_code}"
+
+        print('⦿ Test 10 Passed: API key scopes, expiry limits, rotation and revocation')
+    except Exception as e:
+        print(f'⍜ Test 10 Failed: {e}')
+
+def test_auth_lockout(BASE_URL, SECRET_KEY, AUTH_FAILURE_LIMIT):
+    try:
+        statuses = []
+        for attempt in range(int(AUTH_FAILURE_LIMIT) + 1):
+            response = requests.get(f'{BASE_URL}/get_sessions', headers={'Authorization': f'Bearer wrong-{attempt}'})
+            statuses.append(response.status_code)
+            if response.status_code == 429:
+                break
+        assert statuses[-1] == 429, f"Expected 429 after {AUTH_FAILURE_LIMIT} failed attempts, got {statuses}"
+
+        response = requests.get(f'{BASE_URL}/get_sessions', headers={'X-RLA-KEY': SECRET_KEY})
+        assert response.status_code == 429, f"Expected the correct key to be locked out too, got {response.status_code}"
+        assert 'Retry-After' in response.headers, "Expected a Retry-After header"
+
+        print('⦿ Test 13 Passed: Failed authentication lockout')
+    except Exception as e:
+        print(f'⍜ Test 13 Failed: {e}')
+
+def test_consent(BASE_URL, cursor):
+    try:
+        response = requests.post(f'{BASE_URL}/create_session', json={'consent': {'analytics': False}})
+        assert response.status_code == 200, f"Expected 200, got {response.status_code}"
+        session_id = response.json()['session_id']
+
+        cursor.execute("SELECT consent_analytics, consent_crash_reporting FROM sessions WHERE session_id = ?", (session_id,))
+        row = cursor.fetchone()
+        assert (row['consent_analytics'], row['consent_crash_reporting']) == (0, 1), f"Unexpected consent in database: {tuple(row)}"
+
+        response = requests.post(f'{BASE_URL}/ingest_event', json={'session_id': session_id, 'event_name': 'without-consent'})
+        assert response.status_code == 200, f"Expected dropped events to succeed, got {response.status_code}"
+        assert 'no consent to analytics' in response.json()['message'], f"Expected the event to be dropped: {response.text}"
+
+        response = requests.post(f'{BASE_URL}/update_consent', json={'session_id': session_id, 'analytics': True})
+        assert response.status_code == 200, f"Expected 200, got {response.status_code}"
+        assert response.json()['consent'] == {'analytics': True, 'crash_reporting': True, 'personalized': True}, f"Unexpected consent: {response.text}"
+
+        response = requests.post(f'{BASE_URL}/ingest_event', json={'session_id': session_id, 'event_name': 'with-consent'})
+        assert response.status_code == 200, f"Expected 200, got {response.status_code}"
+
+        cursor.execute("SELECT event_name FROM events WHERE session_id = ?", (session_id,))
+        names = [row['event_name'] for row in cursor.fetchall()]
+        assert names == ['with-consent'], f"Expected only the event after the update to be stored, got {names}"
+
+        response = requests.post(f'{BASE_URL}/update_consent', json={'session_id': 'unknown', 'analytics': False})
+        assert response.status_code == 404, f"Expected 404 for an unknown session, got {response.status_code}"
+
+        print('⦿ Test 11 Passed: Consent is recorded, enforced and updated')
+    except Exception as e:
+        print(f'⍜ Test 11 Failed: {e}')
+
+def test_request_signing(server_command, env_vars, HOST):
+    # A second project requiring signed ingestion
+    PORT = '8001'
+    db_fd, DB_PATH = tempfile.mkstemp(suffix='.sqlite3')
+    env_vars = dict(env_vars, PORT=PORT, DB_PATH=DB_PATH, INGEST_SIGNING_KEYS='1.4.0=k3y', INGEST_SIGNATURE_WINDOW='300')
+    server_process = subprocess.Popen([server_command], env=env_vars, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+
+    def signed(body, timestamp=None, nonce=None, key=b'k3y'):
+        timestamp = str(int(time.time()) if timestamp is None else timestamp)
+        nonce = nonce or generate_secret_key(16)
+        signature = hmac.new(key, f"{timestamp}\n{nonce}\n".encode() + body, hashlib.sha256).hexdigest()
+        return {
+            'Content-Type': 'application/json',
+            'X-RLA-BUILD': '1.4.0',
+            'X-RLA-TIMESTAMP': timestamp,
+            'X-RLA-NONCE': nonce,
+            'X-RLA-SIGNATURE': signature,
+        }
+
+    try:
+        wait_for_server(HOST, PORT)
+        url = f'http://{HOST}:{PORT}/create_session'
+        body = b'{}'
+
+        response = requests.post(url, data=body, headers={'Content-Type': 'application/json'})
+        assert response.status_code == 401, f"Expected 401 without a signature, got {response.status_code}"
+
+        headers = signed(body)
+        response = requests.post(url, data=body, headers=headers)
+        assert response.status_code == 200, f"Expected 200 for a signed request, got {response.status_code}"
+
+        response = requests.post(url, data=body, headers=headers)
+        assert response.status_code == 401, f"Expected 401 for a replayed request, got {response.status_code}"
+
+        for timestamp in (int(time.time()) - 301, -2**63, 2**63 - 1):
+            response = requests.post(url, data=body, headers=signed(body, timestamp=timestamp))
+            assert response.status_code == 401, f"Expected 401 for timestamp {timestamp}, got {response.status_code}"
+
+        response = requests.post(url, data=body, headers=signed(body, key=b'wrong'))
+        assert response.status_code == 401, f"Expected 401 for a bad signature, got {response.status_code}"
+
+        print('⦿ Test 12 Passed: Signed, replayed, stale and forged requests')
+    except Exception as e:
+        print(f'⍜ Test 12 Failed: {e}')
+    finally:
+        server_process.terminate()
+        server_process.wait()
+        os.close(db_fd)
+        os.unlink(DB_PATH)
+
 def main():
     server_process = None
 
@@ -278,6 +440,7 @@ def main():
     HOST = '127.0.0.1'
     PORT = '8000'
     MAX_JSON_PAYLOAD = '1024'
+    AUTH_FAILURE_LIMIT = '5'
     db_fd, DB_PATH = tempfile.mkstemp(suffix='.sqlite3')
 
     env_vars = os.environ.copy() # contains the REDIS vars
@@ -286,6 +449,7 @@ def main():
     env_vars['SECRET_KEY'] = SECRET_KEY
     env_vars['MAX_JSON_PAYLOAD'] = MAX_JSON_PAYLOAD
     env_vars['DB_PATH'] = DB_PATH
+    env_vars['AUTH_FAILURE_LIMIT'] = AUTH_FAILURE_LIMIT
 
     # Start the Rust server
     if len(sys.argv) < 2:
@@ -330,9 +494,15 @@ def main():
             test_ingest_event_missing_event_name(BASE_URL, session_id_2)
             test_ingest_event_with_event_name(BASE_URL, cursor, session_id_2)
             test_ingest_event_with_data(BASE_URL, cursor, session_id_2)
+            test_ingest_event_gzip(BASE_URL, cursor, session_id_2)
         else:
             print("Skipping some tests due to failure in session creation.")
         session_id_1b = test_create_session_with_user_id(BASE_URL, cursor)
+        test_api_keys(BASE_URL, SECRET_KEY)
+        test_consent(BASE_URL, cursor)
+        test_request_signing(server_command, env_vars, HOST)
+        # Locks out this client, keep it last
+        test_auth_lockout(BASE_URL, SECRET_KEY, AUTH_FAILURE_LIMIT)
 
     finally:
         # Clean up